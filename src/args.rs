@@ -1,3 +1,4 @@
+use crate::crypto::Uuid;
 use clap::{CommandFactory, ValueHint};
 use clap_complete::{generate, Shell};
 use std::io;
@@ -12,36 +13,329 @@ pub struct Args {
 		long = "config",
 		value_name = "PATH",
 		value_hint = ValueHint::FilePath,
-		default_value = "config.toml",
 		global = true,
-		help = "Config file path"
+		help = "Config file path (looked up in the config directories if omitted)"
     )]
-	pub conf_path: PathBuf,
+	pub conf_path: Option<PathBuf>,
+	#[arg(
+		long,
+		value_name = "ADDRESS",
+		env = "P2P_LISTEN_ADDR",
+		global = true,
+		help = "Address peers connect to, overriding the config"
+	)]
+	pub listen_addr: Option<SocketAddr>,
+	#[arg(
+		long,
+		value_name = "ADDRESS",
+		env = "P2P_CHAT_ADDR",
+		global = true,
+		help = "Address peers send chat messages to, overriding the config"
+	)]
+	pub chat_addr: Option<SocketAddr>,
+	#[arg(
+		long,
+		value_name = "PATH",
+		value_hint = ValueHint::DirPath,
+		env = "P2P_DATA_DIR",
+		global = true,
+		help = "Directory keys, peer info and chat history are kept in, overriding the config"
+	)]
+	pub data_dir: Option<PathBuf>,
+	#[arg(long, global = true, help = "Don't color output, also set by the NO_COLOR variable")]
+	pub no_color: bool,
 	#[command(subcommand)]
 	pub command: Command,
 }
 
-#[derive(clap::Subcommand, Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+#[derive(clap::Subcommand, Clone, Eq, PartialEq, Hash, Debug, Default)]
 pub enum Command {
 	#[default]
 	#[command(about = "Initializes files")]
 	Init,
 	#[command(about = "Listens for connections")]
 	Listen,
-	#[command(about = "Connects to a peer")]
+	#[command(about = "Runs the server, chat listener and keepalive pings until stopped")]
+	Daemon,
+	#[command(about = "Connects to one or more peers")]
 	Connect(ConnectArgs),
+	#[command(about = "Pings a peer and reports round-trip times")]
+	Ping(PingArgs),
+	#[command(about = "Pings all known peers and updates their status")]
+	Refresh(RefreshArgs),
+	#[command(about = "Disconnects from a peer or all known peers")]
+	Disconnect(DisconnectArgs),
+	#[command(alias = "remove", about = "Forgets a known peer or all known peers")]
+	Forget(ForgetArgs),
+	#[command(about = "Forgets peers that haven't been seen for longer than configured")]
+	Prune(PruneArgs),
+	#[command(about = "Exports or imports known peers")]
+	Peers(PeersArgs),
+	#[command(about = "Manages the config file")]
+	Config(ConfigArgs),
 	#[command(alias = "ls", about = "Lists connected peers")]
-	List,
+	List(ListArgs),
+	#[command(about = "Prints the fingerprint of our or a known peer's public key")]
+	Fingerprint(FingerprintArgs),
+	#[command(about = "Sets our nickname, or clears it if none is given")]
+	Nick(NickArgs),
+	#[command(about = "Sets the alias of a known peer, or clears it if none is given")]
+	Rename(RenameArgs),
+	#[command(about = "Refuses pings and messages from a peer or an IP address")]
+	Block(BlockArgs),
+	#[command(about = "Lifts a block set with block")]
+	Unblock(BlockArgs),
+	#[command(alias = "whoami", about = "Shows our identity and whether we are listening")]
+	Status(StatusArgs),
 	#[command(about = "Starts realtime chat with connected peers")]
 	Chat,
+	#[command(about = "Sends a single message to a peer without starting chat")]
+	Send(SendArgs),
 	#[command(about = "Generates shell completions")]
 	Completion(CompletionArgs),
 }
 
-#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[derive(clap::Args, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct ConnectArgs {
-	#[arg(value_name = "ADDRESS", value_hint = ValueHint::Hostname, help = "Peer address")]
-	pub addr: SocketAddr,
+	#[arg(
+		value_name = "ADDRESS",
+		value_hint = ValueHint::Hostname,
+		required = true,
+		help = "Peer addresses as host:port, where the host may be a name"
+	)]
+	pub addrs: Vec<String>,
+	#[arg(long, help = "Replace the pinned public key if the peer's key has changed")]
+	pub trust_new_key: bool,
+	#[arg(long, help = "Keep pinging the peer until interrupted")]
+	pub keepalive: bool,
+	#[arg(long, help = "Mark the peer unreachable instead of failing if it can't be reached")]
+	pub keep_going: bool,
+	#[arg(long, help = "Try IPv6 addresses of host names before IPv4 ones")]
+	pub prefer_ipv6: bool,
+	#[arg(long, value_name = "N", default_value_t = 0, help = "Retry failed connections N times")]
+	pub retry: u32,
+	#[arg(
+		long,
+		value_name = "MS",
+		default_value_t = 1000,
+		help = "Wait before the first retry, doubled after each one"
+	)]
+	pub retry_delay: u64,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct PingArgs {
+	#[arg(
+		value_name = "ID|ADDRESS",
+		value_hint = ValueHint::Hostname,
+		help = "Known peer's ID, a unique prefix of it or its alias, or an address as host:port"
+	)]
+	pub peer: String,
+	#[arg(
+		long,
+		value_name = "N",
+		default_value_t = 1,
+		value_parser = clap::value_parser!(u32).range(1..),
+		help = "Number of pings to send"
+	)]
+	pub count: u32,
+	#[arg(long, value_name = "MS", default_value_t = 1000, help = "Wait between pings")]
+	pub interval: u64,
+	#[arg(
+		long,
+		value_name = "SECS",
+		default_value_t = 5,
+		value_parser = clap::value_parser!(u64).range(1..),
+		help = "Give up on a ping after this long"
+	)]
+	pub timeout: u64,
+	#[arg(long, help = "Try IPv6 addresses of host names before IPv4 ones")]
+	pub prefer_ipv6: bool,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct DisconnectArgs {
+	#[arg(
+		value_name = "ADDRESS|ID",
+		value_parser = parse_peer_target,
+		help = "Peer address or ID (all known peers if omitted)"
+	)]
+	pub peer: Option<PeerTarget>,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ListArgs {
+	#[arg(long, help = "Show the fingerprint of each peer's public key")]
+	pub keys: bool,
+	#[arg(long, help = "Show when peers were last seen as RFC 3339 timestamps")]
+	pub absolute: bool,
+	#[arg(long, value_enum, default_value_t, help = "Output format")]
+	pub format: Format,
+	#[arg(long, conflicts_with = "offline", help = "Only show peers that are online")]
+	pub online: bool,
+	#[arg(long, help = "Only show peers that aren't online")]
+	pub offline: bool,
+	#[arg(long, value_enum, default_value_t, help = "Order to show peers in")]
+	pub sort: Sort,
+	#[arg(long, value_name = "N", help = "Show at most N peers")]
+	pub limit: Option<usize>,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Sort {
+	/// Most recently seen first, never seen last.
+	#[default]
+	LastSeen,
+	/// By ID.
+	Id,
+	/// By address.
+	Addr,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct StatusArgs {
+	#[arg(long, value_enum, default_value_t, help = "Output format")]
+	pub format: Format,
+}
+
+#[derive(clap::ValueEnum, Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Format {
+	/// Table for reading in a terminal.
+	#[default]
+	Table,
+	/// JSON for scripts.
+	Json,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct PruneArgs {
+	#[arg(
+		long,
+		value_name = "N",
+		value_parser = clap::value_parser!(u64).range(1..),
+		help = "Forget peers unseen for more than N days, overriding the config"
+	)]
+	pub days: Option<u64>,
+	#[arg(long, help = "Only print the peers that would be forgotten")]
+	pub dry_run: bool,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ConfigArgs {
+	#[command(subcommand)]
+	pub command: ConfigCommand,
+}
+
+#[derive(clap::Subcommand, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ConfigCommand {
+	#[command(about = "Writes the default config file")]
+	Init(ConfigInitArgs),
+	#[command(about = "Prints the path of the config file in use")]
+	Path,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ConfigInitArgs {
+	#[arg(long, help = "Overwrite an existing config file")]
+	pub force: bool,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct PeersArgs {
+	#[command(subcommand)]
+	pub command: PeersCommand,
+}
+
+#[derive(clap::Subcommand, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PeersCommand {
+	#[command(about = "Writes known peers as JSON")]
+	Export(ExportArgs),
+	#[command(about = "Adds known peers from a file written by export")]
+	Import(ImportArgs),
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ExportArgs {
+	#[arg(
+		short,
+		long,
+		value_name = "PATH",
+		value_hint = ValueHint::FilePath,
+		help = "Output file path (standard output if omitted)"
+	)]
+	pub output: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ImportArgs {
+	#[arg(value_name = "PATH", value_hint = ValueHint::FilePath, help = "Exported file path")]
+	pub path: PathBuf,
+	#[arg(long, conflicts_with = "replace", help = "Merge into known peers (default)")]
+	pub merge: bool,
+	#[arg(long, help = "Replace all known peers")]
+	pub replace: bool,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct NickArgs {
+	#[arg(value_name = "NAME", help = "Nickname shown to peers")]
+	pub name: Option<String>,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct RenameArgs {
+	#[arg(value_name = "ID", help = "Peer ID, a unique prefix of it or its alias")]
+	pub peer: String,
+	#[arg(value_name = "ALIAS", help = "Name shown for the peer")]
+	pub alias: Option<String>,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct SendArgs {
+	#[arg(value_name = "ID", help = "Peer ID, a unique prefix of it or its alias")]
+	pub peer: String,
+	#[arg(value_name = "TEXT", help = "Message to send, read from stdin if it is -")]
+	pub text: String,
+	#[arg(long, help = "Fail unless the peer acknowledges the message")]
+	pub wait: bool,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct BlockArgs {
+	#[arg(
+		value_name = "ID|IP",
+		help = "Peer ID, a unique prefix of it or its alias, or an IP address"
+	)]
+	pub peer: String,
+}
+
+#[derive(clap::Args, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ForgetArgs {
+	#[arg(
+		value_name = "ADDRESS|ID",
+		required_unless_present = "all",
+		help = "Peer address, ID, a unique prefix of it or its alias"
+	)]
+	pub peer: Option<String>,
+	#[arg(long, conflicts_with = "peer", help = "Forget all known peers")]
+	pub all: bool,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct RefreshArgs {
+	#[arg(
+		long,
+		default_value_t = 16,
+		value_parser = clap::value_parser!(u16).range(1..),
+		help = "Number of peers pinged at once"
+	)]
+	pub jobs: u16,
+}
+
+#[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FingerprintArgs {
+	#[arg(value_name = "ID", help = "Peer ID (our own key if omitted)")]
+	pub peer: Option<Uuid>,
 }
 
 #[derive(clap::Args, Copy, Clone, Eq, PartialEq, Hash, Debug)]
@@ -50,6 +344,19 @@ pub struct CompletionArgs {
 	pub shell: Shell,
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PeerTarget {
+	Addr(SocketAddr),
+	Id(Uuid),
+}
+
+fn parse_peer_target(s: &str) -> Result<PeerTarget, String> {
+	if let Ok(addr) = s.parse() {
+		return Ok(PeerTarget::Addr(addr));
+	}
+	s.parse().map(PeerTarget::Id).map_err(|_| "expected a socket address or a peer ID".to_owned())
+}
+
 pub fn gen_completion(shell: Shell) {
 	generate(shell, &mut Args::command(), env!("CARGO_BIN_NAME"), &mut io::stdout());
 }
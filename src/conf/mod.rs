@@ -1,56 +1,396 @@
+use crate::crypto::UuidVersion;
+use crate::peer::{sanitize_nickname, MAX_NICKNAME_LEN};
+use crate::rpc::request::MAX_REQUEST_LEN;
+use crate::rpc::transfer::MAX_CHUNK_SIZE;
+use log::warn;
 use std::cmp::PartialEq;
 use std::fmt::{Debug, Display, Formatter};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{env, fmt, fs};
 use tokio::io;
 
 mod raw;
 
+/// Name of the directories under the XDG base directories.
+const APP_NAME: &str = "p2p";
+
+/// Smallest maximum request size that may be configured, which still fits a ping with an RSA
+/// key.
+const MIN_REQUEST_SIZE: usize = 4 * 1024;
+
+/// Name of the config file looked up when no path is given.
+const FILE_NAME: &str = "config.toml";
+
+/// Config written by `init` when there is none, with every option at its default.
+const DEFAULT_TOML: &str = include_str!("default.toml");
+
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Conf {
 	pub path: path::Conf,
 	pub net: net::Conf,
 	pub crypto: crypto::Conf,
 	pub chat: chat::Conf,
+	pub transfer: transfer::Conf,
+	pub peer: peer::Conf,
+	pub peers: peers::Conf,
 }
 
 impl Conf {
-	/// Loads config from a file.
+	/// Returns the default config file contents, which [`Self::load`] accepts.
+	pub fn default_toml() -> &'static str {
+		DEFAULT_TOML
+	}
+
+	/// Returns the config file read for the given path, or for no path.
+	///
+	/// A relative path that doesn't exist is also looked up in the config directories. Without a
+	/// path, `config.toml` is looked up in `$XDG_CONFIG_HOME/p2p`, `~/.config/p2p` and, on
+	/// Windows, `%APPDATA%\p2p`, then in the current directory. If none exists, the first of
+	/// them is returned so a new config is written where it will be found again.
+	///
+	/// # Errors
+	///
+	/// If the home environment variable is not set, error kind is [`ErrorKind::HomeNotFound`].
+	pub fn resolve_path(path: Option<&Path>) -> Result<PathBuf, Error> {
+		let home = home_dir()?;
+		let dirs = config_dirs(&home);
+		let Some(path) = path else {
+			let mut candidates: Vec<_> = dirs.iter().map(|dir| dir.join(FILE_NAME)).collect();
+			candidates.push(PathBuf::from(FILE_NAME));
+			let found = candidates.iter().position(|path| path.exists()).unwrap_or(0);
+			return Ok(candidates.swap_remove(found));
+		};
+		if path.is_relative() && !path.exists() {
+			if let Some(fallback) = dirs.iter().map(|dir| dir.join(path)).find(|path| path.exists())
+			{
+				return Ok(fallback);
+			}
+		}
+		Ok(path.to_path_buf())
+	}
+
+	/// Loads config from the file [`Self::resolve_path`] finds without a path.
+	///
+	/// # Errors
+	///
+	/// See [`Self::load`].
+	pub fn load_default() -> Result<Self, Error> {
+		Self::load(Self::resolve_path(None)?)
+	}
+
+	/// Loads config from a file, looked up as described in [`Self::resolve_path`].
+	///
+	/// Keys and peer info are kept in the app directory from the config, relative to the home
+	/// directory. On Unix, the default `.p2p` is replaced by `$XDG_DATA_HOME/p2p` (or
+	/// `~/.local/share/p2p`) unless it already exists.
+	///
+	/// Missing sections and keys take their defaults, and the chat address defaults to the port
+	/// after the network address. Unknown keys are logged and ignored.
 	///
 	/// # Errors
 	///
 	/// If the file doesn't exist, error kind is [`ErrorKind::FileNotFound`].
 	/// If there is an error while reading from the file, error kind is [`ErrorKind::ReadError`].
-	/// If the file can't be parsed into config, error kind is [`ErrorKind::InvalidData`].
+	/// If a required key is missing from the file, error kind is [`ErrorKind::MissingField`].
+	/// If the file can't be parsed into config otherwise, error kind is [`ErrorKind::InvalidData`].
+	/// Both keep the parse error, which names the offending key and points at its line.
 	/// If the home environment variable is not set, error kind is [`ErrorKind::HomeNotFound`].
+	/// If the transfer chunk size is zero or exceeds [`MAX_CHUNK_SIZE`], the maximum request size
+	/// is below 4 KiB or exceeds [`MAX_REQUEST_LEN`], the maximum message size is zero, the UUID
+	/// version is neither 4 nor 7, the RSA modulus size is unsupported, a read, connect or offline
+	/// timeout or the heartbeat, save or ping interval is zero, a peer expiry isn't a duration like
+	/// `30d`, the network or chat address can't be resolved, they are the same, the chat address
+	/// is left out with network port 65535, or the nickname is invalid, error kind is
+	/// [`ErrorKind::InvalidConfig`].
 	pub fn load<P>(path: P) -> Result<Self, Error>
 	where
 		P: AsRef<Path>,
 	{
-		let raw_conf: raw::Conf =
-			toml::from_str(&fs::read_to_string(path).map_err(|e| match e.kind() {
-				io::ErrorKind::NotFound => Error::new(ErrorKind::FileNotFound, "file not found"),
-				_ => Error::new(ErrorKind::ReadError, e),
-			})?)
-			.map_err(|_| Error::new(ErrorKind::InvalidData, "file is malformed"))?;
-
-		let home = if cfg!(windows) {
-			env::var("USERPROFILE").map_err(|e| Error::new(ErrorKind::HomeNotFound, e))?
-		} else {
-			env::var("HOME").map_err(|e| Error::new(ErrorKind::HomeNotFound, e))?
-		};
-		let app = PathBuf::from(&home).join(&raw_conf.path.app);
+		let home = home_dir()?;
+		let path = Self::resolve_path(Some(path.as_ref()))?;
+		let data = fs::read_to_string(&path).map_err(|e| match e.kind() {
+			io::ErrorKind::NotFound => {
+				Error::new(ErrorKind::FileNotFound, format!("{} not found", path.display()))
+			}
+			_ => Error::new(ErrorKind::ReadError, e),
+		})?;
+		let mut unknown = Vec::new();
+		let mut ignored = |key: serde_ignored::Path<'_>| unknown.push(key.to_string());
+		let deserializer =
+			serde_ignored::Deserializer::new(toml::Deserializer::new(&data), &mut ignored);
+		let raw_conf: raw::Conf = serde_path_to_error::deserialize(deserializer).map_err(|e| {
+			let key = e.path().to_string();
+			let e = e.into_inner();
+			let kind = if e.message().starts_with("missing field") {
+				ErrorKind::MissingField
+			} else {
+				ErrorKind::InvalidData
+			};
+			match key.as_str() {
+				"." => Error::new(kind, e),
+				key => Error::new(kind, format!("invalid {key}: {e}")),
+			}
+		})?;
+		for key in unknown {
+			warn!("ignoring unknown config key {key} in {}", path.display());
+		}
+
+		let app = data_dir(&home, &raw_conf.path.app);
 		let private_key = app.join(&raw_conf.path.private_key);
 		let public_key = app.join(&raw_conf.path.public_key);
+		let certificate = app.join(&raw_conf.path.certificate);
 		let peers = app.join(&raw_conf.path.peer_info);
+		let history_path = app.join(&raw_conf.chat.history_path);
+		let downloads = home.join(&raw_conf.transfer.downloads);
+
+		let uuid_version = UuidVersion::try_from(raw_conf.crypto.uuid_version)
+			.map_err(|e| Error::new(ErrorKind::InvalidConfig, e))?;
+
+		let nickname = match raw_conf.peer.nickname {
+			Some(nickname) if sanitize_nickname(&nickname).as_ref() != Some(&nickname) => {
+				return Err(Error::new(
+					ErrorKind::InvalidConfig,
+					format!("nickname must be 1 to {MAX_NICKNAME_LEN} characters without control characters"),
+				));
+			}
+			nickname => nickname,
+		};
+
+		if raw_conf.transfer.chunk_size == 0 || raw_conf.transfer.chunk_size > MAX_CHUNK_SIZE {
+			return Err(Error::new(
+				ErrorKind::InvalidConfig,
+				format!("chunk size must be between 1 and {MAX_CHUNK_SIZE} bytes"),
+			));
+		}
+
+		let rsa_bits = raw_conf.crypto.rsa_bits;
+		if rsa_bits != 1024 && (rsa_bits < 2048 || !rsa_bits.is_multiple_of(8)) {
+			return Err(Error::new(
+				ErrorKind::InvalidConfig,
+				format!("RSA key size must be 1024 or at least 2048 bits in multiples of 8, got {rsa_bits}"),
+			));
+		}
+
+		let expire_after =
+			raw_conf.peers.expire_after.as_deref().map(parse_duration).transpose()?;
+		let never_seen_expire_after =
+			raw_conf.peers.never_seen_expire_after.as_deref().map(parse_duration).transpose()?;
+
+		let max_request_size = raw_conf.network.max_request_size;
+		if !(MIN_REQUEST_SIZE..=MAX_REQUEST_LEN).contains(&max_request_size) {
+			return Err(Error::new(
+				ErrorKind::InvalidConfig,
+				format!(
+					"maximum request size must be between {MIN_REQUEST_SIZE} and {MAX_REQUEST_LEN} bytes"
+				),
+			));
+		}
+
+		if raw_conf.chat.max_message_size == 0 {
+			return Err(Error::new(
+				ErrorKind::InvalidConfig,
+				"maximum message size must not be zero",
+			));
+		}
+
+		if raw_conf.network.read_timeout == 0 || raw_conf.chat.read_timeout == 0 {
+			return Err(Error::new(ErrorKind::InvalidConfig, "read timeout must not be zero"));
+		}
+
+		if raw_conf.network.connect_timeout == 0 {
+			return Err(Error::new(ErrorKind::InvalidConfig, "connect timeout must not be zero"));
+		}
+
+		if raw_conf.network.offline_after == 0 {
+			return Err(Error::new(ErrorKind::InvalidConfig, "offline timeout must not be zero"));
+		}
+
+		if raw_conf.network.heartbeat_interval == 0 {
+			return Err(Error::new(
+				ErrorKind::InvalidConfig,
+				"heartbeat interval must not be zero",
+			));
+		}
+
+		if raw_conf.network.save_interval == 0 {
+			return Err(Error::new(ErrorKind::InvalidConfig, "save interval must not be zero"));
+		}
+
+		if raw_conf.network.ping_interval == 0 {
+			return Err(Error::new(ErrorKind::InvalidConfig, "ping interval must not be zero"));
+		}
+
+		let net_addr = resolve_addr("network", &raw_conf.network.address)?;
+		let chat_addr = match &raw_conf.chat.address {
+			Some(addr) => resolve_addr("chat", addr)?,
+			None => {
+				// Both are picked by the OS then.
+				let port = match net_addr.port() {
+					0 => Some(0),
+					port => port.checked_add(1),
+				};
+				let port = port.ok_or_else(|| {
+					Error::new(
+						ErrorKind::InvalidConfig,
+						"chat address must be set when the network port is 65535",
+					)
+				})?;
+				SocketAddr::new(net_addr.ip(), port)
+			}
+		};
+		if net_addr == chat_addr && chat_addr.port() != 0 {
+			return Err(Error::new(
+				ErrorKind::InvalidConfig,
+				format!("network and chat addresses must differ, both are {chat_addr}"),
+			));
+		}
 
 		Ok(Self {
-			path: path::Conf { app, private_key, public_key, peer_info: peers },
-			net: net::Conf { addr: raw_conf.network.address },
-			crypto: crypto::Conf { rsa_bits: raw_conf.crypto.rsa_bits },
-			chat: chat::Conf { addr: raw_conf.chat.address },
+			path: path::Conf { app, private_key, public_key, certificate, peer_info: peers },
+			net: net::Conf {
+				addr: net_addr,
+				codec: raw_conf.network.codec,
+				share_peers: raw_conf.network.share_peers,
+				read_timeout: Duration::from_secs(raw_conf.network.read_timeout),
+				connect_timeout: Duration::from_secs(raw_conf.network.connect_timeout),
+				max_clock_skew: Duration::from_secs(raw_conf.network.max_clock_skew),
+				tls: raw_conf.network.tls,
+				offline_after: Duration::from_secs(raw_conf.network.offline_after),
+				heartbeat_interval: Duration::from_secs(raw_conf.network.heartbeat_interval),
+				pin_addresses: raw_conf.network.pin_addresses,
+				max_request_size,
+				save_interval: Duration::from_secs(raw_conf.network.save_interval),
+				ping_interval: Duration::from_secs(raw_conf.network.ping_interval),
+			},
+			crypto: crypto::Conf {
+				algorithm: raw_conf.crypto.algorithm,
+				rsa_bits,
+				uuid_version,
+				encrypt_peer_info: raw_conf.crypto.encrypt_peer_info,
+			},
+			chat: chat::Conf {
+				addr: chat_addr,
+				show_timestamps: raw_conf.chat.show_timestamps,
+				read_timeout: Duration::from_secs(raw_conf.chat.read_timeout),
+				history_path,
+				history_lines: raw_conf.chat.history_lines,
+				max_message_size: raw_conf.chat.max_message_size,
+			},
+			transfer: transfer::Conf { chunk_size: raw_conf.transfer.chunk_size, downloads },
+			peer: peer::Conf { nickname },
+			peers: peers::Conf {
+				expire_after,
+				never_seen_expire_after,
+				max_failed_attempts: raw_conf.peers.max_failed_attempts,
+			},
 		})
 	}
+
+	/// Moves keys, peer info and chat history kept in the app directory to the directory.
+	///
+	/// Paths set to absolute paths in the config are kept.
+	pub fn set_app_dir<P>(&mut self, dir: P)
+	where
+		P: AsRef<Path>,
+	{
+		let dir = dir.as_ref();
+		for path in [
+			&mut self.path.private_key,
+			&mut self.path.public_key,
+			&mut self.path.certificate,
+			&mut self.path.peer_info,
+			&mut self.chat.history_path,
+		] {
+			if let Ok(relative) = path.strip_prefix(&self.path.app) {
+				*path = dir.join(relative);
+			}
+		}
+		self.path.app = dir.to_path_buf();
+	}
+}
+
+/// Resolves a configured address whose host may be a name, preferring IPv4.
+fn resolve_addr(name: &str, addr: &str) -> Result<SocketAddr, Error> {
+	let mut addrs: Vec<_> = addr
+		.to_socket_addrs()
+		.map_err(|e| {
+			Error::new(
+				ErrorKind::InvalidConfig,
+				format!("failed to resolve {name} address {addr}: {e}"),
+			)
+		})?
+		.collect();
+	addrs.sort_by_key(SocketAddr::is_ipv6);
+	addrs.first().copied().ok_or_else(|| {
+		Error::new(ErrorKind::InvalidConfig, format!("{name} address {addr} resolves to nothing"))
+	})
+}
+
+/// Parses a duration made of a number and a unit of `s`, `m`, `h` or `d`, such as `30d`.
+fn parse_duration(s: &str) -> Result<Duration, Error> {
+	let invalid = || {
+		Error::new(
+			ErrorKind::InvalidConfig,
+			format!("invalid duration {s:?}, expected a number followed by s, m, h or d"),
+		)
+	};
+	let s = s.trim();
+	let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?);
+	let number: u64 = number.parse().map_err(|_| invalid())?;
+	let secs = match unit {
+		"s" => Some(number),
+		"m" => number.checked_mul(60),
+		"h" => number.checked_mul(3600),
+		"d" => number.checked_mul(86400),
+		_ => None,
+	};
+	secs.map(Duration::from_secs).ok_or_else(invalid)
+}
+
+/// Returns the home directory of the user.
+fn home_dir() -> Result<PathBuf, Error> {
+	let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+	env::var_os(var)
+		.filter(|home| !home.is_empty())
+		.map(PathBuf::from)
+		.ok_or_else(|| Error::new(ErrorKind::HomeNotFound, format!("{var} is not set")))
+}
+
+/// Returns the directories config files are looked up in, most preferred first.
+fn config_dirs(home: &Path) -> Vec<PathBuf> {
+	let mut dirs = vec![xdg_dir("XDG_CONFIG_HOME", home, ".config").join(APP_NAME)];
+	let default = home.join(".config").join(APP_NAME);
+	if !dirs.contains(&default) {
+		dirs.push(default);
+	}
+	if cfg!(windows) {
+		dirs.push(xdg_dir("APPDATA", home, "AppData/Roaming").join(APP_NAME));
+	}
+	dirs
+}
+
+/// Returns the directory keys and peer info are kept in.
+///
+/// A custom app directory is used as configured, relative to the home directory. The default
+/// one is replaced by the XDG data directory unless it already exists, so files created before
+/// XDG directories were supported are still found.
+fn data_dir(home: &Path, app: &str) -> PathBuf {
+	let dir = home.join(app);
+	if cfg!(windows) || app != raw::path::Conf::default().app || dir.exists() {
+		return dir;
+	}
+	xdg_dir("XDG_DATA_HOME", home, ".local/share").join(APP_NAME)
+}
+
+/// Returns the directory from an XDG environment variable, or its default in the home
+/// directory if the variable is unset or not an absolute path, as the specification requires.
+fn xdg_dir(var: &str, home: &Path, default: &str) -> PathBuf {
+	env::var_os(var)
+		.map(PathBuf::from)
+		.filter(|dir| dir.is_absolute())
+		.unwrap_or_else(|| home.join(default))
 }
 
 pub mod path {
@@ -61,32 +401,112 @@ pub mod path {
 		pub app: PathBuf,
 		pub private_key: PathBuf,
 		pub public_key: PathBuf,
+		/// Self-signed certificate used for TLS.
+		pub certificate: PathBuf,
 		pub peer_info: PathBuf,
 	}
 }
 
 pub mod net {
+	use crate::rpc::request::Codec;
 	use std::net::SocketAddr;
+	use std::time::Duration;
 
-	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+	#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 	pub struct Conf {
 		pub addr: SocketAddr,
+		pub codec: Codec,
+		pub share_peers: bool,
+		/// How long to wait for a request before dropping the connection.
+		pub read_timeout: Duration,
+		/// How long to wait for a peer to accept a connection and answer a ping.
+		pub connect_timeout: Duration,
+		/// Maximum difference between a ping's timestamp and local time.
+		pub max_clock_skew: Duration,
+		/// Whether connections to peers are encrypted with TLS.
+		pub tls: bool,
+		/// How long an online peer may go unseen before it is marked offline, which is also how
+		/// often peers are checked.
+		pub offline_after: Duration,
+		/// How often `connect --keepalive` pings the peer.
+		pub heartbeat_interval: Duration,
+		/// Whether known peers keep their stored addresses when they ping from new ones.
+		pub pin_addresses: bool,
+		/// Longest request accepted from a peer, in bytes.
+		pub max_request_size: usize,
+		/// How often the server saves what it learned about peers, at most.
+		pub save_interval: Duration,
+		/// How often the daemon pings known peers to keep them up to date.
+		pub ping_interval: Duration,
 	}
 }
 
 pub mod crypto {
+	use crate::crypto::{Algorithm, UuidVersion};
+
 	#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
 	pub struct Conf {
+		/// Algorithm of newly generated keys.
+		pub algorithm: Algorithm,
+		/// Modulus size of newly generated RSA keys.
 		pub rsa_bits: u32,
+		/// UUID version of our peer ID.
+		pub uuid_version: UuidVersion,
+		/// Whether peer info and chat history are encrypted at rest with keys derived from our
+		/// private key.
+		pub encrypt_peer_info: bool,
 	}
 }
 
 pub mod chat {
 	use std::net::SocketAddr;
+	use std::path::PathBuf;
+	use std::time::Duration;
 
-	#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 	pub struct Conf {
 		pub addr: SocketAddr,
+		pub show_timestamps: bool,
+		/// How long a chat connection may stay idle before it is dropped.
+		pub read_timeout: Duration,
+		/// File sent and received messages are appended to.
+		pub history_path: PathBuf,
+		/// Number of messages from the history shown when the chat starts.
+		pub history_lines: usize,
+		/// Longest chat message sent or accepted, in bytes of text.
+		pub max_message_size: usize,
+	}
+}
+
+pub mod transfer {
+	use std::path::PathBuf;
+
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+	pub struct Conf {
+		pub chunk_size: usize,
+		pub downloads: PathBuf,
+	}
+}
+
+pub mod peer {
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+	pub struct Conf {
+		/// Nickname we introduce ourselves with, overriding the one set with `p2p nick`.
+		pub nickname: Option<String>,
+	}
+}
+
+pub mod peers {
+	use std::time::Duration;
+
+	#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+	pub struct Conf {
+		/// How long a peer may go unseen before it is pruned, if at all.
+		pub expire_after: Option<Duration>,
+		/// How long a peer that was never seen is kept after it was added, if pruned at all.
+		pub never_seen_expire_after: Option<Duration>,
+		/// Number of failed connection attempts in a row after which a peer is backed off.
+		pub max_failed_attempts: u32,
 	}
 }
 
@@ -119,5 +539,196 @@ pub enum ErrorKind {
 	FileNotFound,
 	ReadError,
 	InvalidData,
+	MissingField,
 	HomeNotFound,
+	InvalidConfig,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::ffi::OsString;
+	use std::sync::{Mutex, MutexGuard, PoisonError};
+	use tempfile::TempDir;
+
+	/// Held by tests that read or change the environment variables paths are resolved from.
+	static ENV: Mutex<()> = Mutex::new(());
+
+	/// Environment variables set for a test, restored when dropped.
+	struct Env {
+		saved: Vec<(&'static str, Option<OsString>)>,
+		_lock: MutexGuard<'static, ()>,
+	}
+
+	impl Env {
+		fn set(vars: &[(&'static str, &Path)]) -> Self {
+			let lock = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+			let saved = vars.iter().map(|&(var, _)| (var, env::var_os(var))).collect();
+			for (var, value) in vars {
+				env::set_var(var, value);
+			}
+			Self { saved, _lock: lock }
+		}
+	}
+
+	impl Drop for Env {
+		fn drop(&mut self) {
+			for (var, value) in &self.saved {
+				match value {
+					Some(value) => env::set_var(var, value),
+					None => env::remove_var(var),
+				}
+			}
+		}
+	}
+
+	/// Loads config from a file with the contents.
+	fn load(toml: &str) -> Result<Conf, Error> {
+		let _env = ENV.lock().unwrap_or_else(PoisonError::into_inner);
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join(FILE_NAME);
+		fs::write(&path, toml).unwrap();
+		Conf::load(path)
+	}
+
+	#[test]
+	fn default_toml_round_trips() {
+		let conf = load(Conf::default_toml()).unwrap();
+		assert_eq!(conf, load("").unwrap());
+		assert_eq!(conf.net.addr, SocketAddr::from(([0, 0, 0, 0], 7040)));
+		assert_eq!(conf.chat.addr, SocketAddr::from(([0, 0, 0, 0], 7041)));
+		assert_eq!(conf.crypto.rsa_bits, 2048);
+		assert!(conf.path.private_key.starts_with(&conf.path.app));
+	}
+
+	#[test]
+	fn empty_config_takes_defaults() {
+		let conf = load("").unwrap();
+		assert_eq!(conf.net.addr, SocketAddr::from(([0, 0, 0, 0], 7040)));
+		assert_eq!(conf.chat.addr, SocketAddr::from(([0, 0, 0, 0], 7041)));
+		assert_eq!(conf.crypto.rsa_bits, 2048);
+	}
+
+	#[test]
+	fn chat_address_follows_network_address() {
+		let conf = load("[network]\naddress = \"127.0.0.1:7100\"\n").unwrap();
+		assert_eq!(conf.net.addr, SocketAddr::from(([127, 0, 0, 1], 7100)));
+		assert_eq!(conf.chat.addr, SocketAddr::from(([127, 0, 0, 1], 7101)));
+		assert_eq!(conf.crypto, load("").unwrap().crypto);
+	}
+
+	#[test]
+	fn unknown_keys_are_ignored() {
+		let conf = load(
+			"[network]\nadress = \"127.0.0.1:7100\"\nformat = \"msgpack\"\n\n[colors]\nprompt = \"red\"\n",
+		);
+		assert_eq!(conf.unwrap(), load("").unwrap());
+	}
+
+	#[test]
+	fn invalid_values_are_rejected() {
+		for toml in [
+			"[crypto]\nrsa_bits = 0",
+			"[crypto]\nrsa_bits = 17",
+			"[crypto]\nrsa_bits = 2049",
+			"[crypto]\nrsa_bits = 4100",
+			"[crypto]\nuuid_version = 5",
+			"[network]\naddress = \"127.0.0.1:7100\"\n[chat]\naddress = \"127.0.0.1:7100\"",
+			"[network]\naddress = \"127.0.0.1:65535\"",
+			"[network]\nread_timeout = 0",
+			"[network]\nconnect_timeout = 0",
+			"[network]\noffline_after = 0",
+			"[network]\nheartbeat_interval = 0",
+			"[network]\nsave_interval = 0",
+			"[network]\nping_interval = 0",
+			"[network]\nmax_request_size = 1024",
+			"[chat]\nread_timeout = 0",
+			"[chat]\nmax_message_size = 0",
+			"[transfer]\nchunk_size = 0",
+			"[peers]\nexpire_after = \"30\"",
+			"[peer]\nnickname = \"\"",
+		] {
+			let kind = load(toml).map(|_| ()).map_err(|e| e.kind);
+			assert_eq!(kind, Err(ErrorKind::InvalidConfig), "{toml}");
+		}
+	}
+
+	#[test]
+	fn valid_values_are_accepted() {
+		for toml in [
+			"[crypto]\nrsa_bits = 1024",
+			"[crypto]\nrsa_bits = 2048",
+			"[crypto]\nrsa_bits = 2056",
+			"[network]\naddress = \"127.0.0.1:0\"",
+			"[network]\naddress = \"127.0.0.1:65535\"\n[chat]\naddress = \"127.0.0.1:7041\"",
+			"[peers]\nexpire_after = \"30d\"",
+		] {
+			assert!(load(toml).is_ok(), "{toml}");
+		}
+	}
+
+	#[test]
+	fn parse_error_names_the_key() {
+		let e = load("[network]\naddress = 7100\n").unwrap_err();
+		assert_eq!(e.kind, ErrorKind::InvalidData);
+		assert!(e.to_string().contains("network.address"), "{e}");
+		assert!(e.to_string().contains("line 2"), "{e}");
+	}
+
+	#[test]
+	fn config_and_data_are_found_in_xdg_dirs() {
+		let dir = TempDir::new().unwrap();
+		let (config_home, data_home) = (dir.path().join("config"), dir.path().join("data"));
+		let _env = Env::set(&[
+			("HOME", dir.path()),
+			("USERPROFILE", dir.path()),
+			("XDG_CONFIG_HOME", &config_home),
+			("XDG_DATA_HOME", &data_home),
+		]);
+		let path = config_home.join(APP_NAME).join(FILE_NAME);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		fs::write(&path, "[network]\naddress = \"127.0.0.1:7100\"\n").unwrap();
+
+		assert_eq!(Conf::resolve_path(None).unwrap(), path);
+		let conf = Conf::load_default().unwrap();
+		assert_eq!(conf.net.addr, SocketAddr::from(([127, 0, 0, 1], 7100)));
+		if cfg!(unix) {
+			assert_eq!(conf.path.app, data_home.join(APP_NAME));
+		}
+	}
+
+	#[test]
+	fn custom_app_dir_is_kept() {
+		let dir = TempDir::new().unwrap();
+		let data_home = dir.path().join("data");
+		let _env = Env::set(&[
+			("HOME", dir.path()),
+			("USERPROFILE", dir.path()),
+			("XDG_DATA_HOME", &data_home),
+		]);
+		let path = dir.path().join(FILE_NAME);
+		let custom = dir.path().join("srv").join("p2p");
+		for (app, expected) in
+			[(custom.to_str().unwrap(), custom.clone()), ("p2p-data", dir.path().join("p2p-data"))]
+		{
+			fs::write(&path, format!("[path]\napp = {app:?}\n")).unwrap();
+			let conf = Conf::load(&path).unwrap();
+			assert_eq!(conf.path.app, expected);
+			assert_eq!(conf.path.peer_info, expected.join("peer_info.json"));
+		}
+	}
+
+	#[test]
+	fn relative_xdg_dir_is_ignored() {
+		let dir = TempDir::new().unwrap();
+		let _env = Env::set(&[
+			("HOME", dir.path()),
+			("USERPROFILE", dir.path()),
+			("XDG_CONFIG_HOME", Path::new("relative")),
+		]);
+		let expected = dir.path().join(".config").join(APP_NAME).join(FILE_NAME);
+		fs::create_dir_all(expected.parent().unwrap()).unwrap();
+		fs::write(&expected, "").unwrap();
+		assert_eq!(Conf::resolve_path(None).unwrap(), expected);
+	}
 }
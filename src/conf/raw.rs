@@ -1,50 +1,319 @@
 use serde::Deserialize;
 
-#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+/// Config as written in the file, where every section and most keys may be left out.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize)]
 pub struct Conf {
+	#[serde(default)]
 	pub path: path::Conf,
+	#[serde(default)]
 	pub network: network::Conf,
+	#[serde(default)]
 	pub crypto: crypto::Conf,
+	#[serde(default)]
 	pub chat: chat::Conf,
+	#[serde(default)]
+	pub transfer: transfer::Conf,
+	#[serde(default)]
+	pub peer: peer::Conf,
+	#[serde(default)]
+	pub peers: peers::Conf,
 }
 
 pub mod path {
 	use serde::Deserialize;
 
-	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize)]
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
 	pub struct Conf {
+		#[serde(default = "default_app")]
 		pub app: String,
+		#[serde(default = "default_private_key")]
 		pub private_key: String,
+		#[serde(default = "default_public_key")]
 		pub public_key: String,
+		#[serde(default = "default_certificate")]
+		pub certificate: String,
+		#[serde(default = "default_peer_info")]
 		pub peer_info: String,
 	}
+
+	impl Default for Conf {
+		fn default() -> Self {
+			Self {
+				app: default_app(),
+				private_key: default_private_key(),
+				public_key: default_public_key(),
+				certificate: default_certificate(),
+				peer_info: default_peer_info(),
+			}
+		}
+	}
+
+	fn default_app() -> String {
+		".p2p".to_string()
+	}
+
+	fn default_private_key() -> String {
+		"keys/private.pem".to_string()
+	}
+
+	fn default_public_key() -> String {
+		"keys/public.pem".to_string()
+	}
+
+	fn default_peer_info() -> String {
+		"peer_info.json".to_string()
+	}
+
+	fn default_certificate() -> String {
+		"keys/cert.pem".to_string()
+	}
 }
 
 pub mod network {
+	use crate::rpc::request::Codec;
 	use serde::Deserialize;
-	use std::net::SocketAddr;
+	use std::net::{Ipv4Addr, SocketAddr};
 
 	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
 	pub struct Conf {
-		pub address: SocketAddr,
+		/// Host and port, where the host may be a name.
+		#[serde(default = "default_address")]
+		pub address: String,
+		#[serde(default)]
+		pub codec: Codec,
+		#[serde(default = "default_share_peers")]
+		pub share_peers: bool,
+		#[serde(default = "default_read_timeout")]
+		pub read_timeout: u64,
+		#[serde(default = "default_connect_timeout")]
+		pub connect_timeout: u64,
+		#[serde(default = "default_max_clock_skew")]
+		pub max_clock_skew: u64,
+		#[serde(default)]
+		pub tls: bool,
+		#[serde(default = "default_offline_after")]
+		pub offline_after: u64,
+		#[serde(default = "default_heartbeat_interval")]
+		pub heartbeat_interval: u64,
+		#[serde(default)]
+		pub pin_addresses: bool,
+		#[serde(default = "default_max_request_size")]
+		pub max_request_size: usize,
+		#[serde(default = "default_save_interval")]
+		pub save_interval: u64,
+		#[serde(default = "default_ping_interval")]
+		pub ping_interval: u64,
+	}
+
+	impl Default for Conf {
+		fn default() -> Self {
+			Self {
+				address: default_address(),
+				codec: Codec::default(),
+				share_peers: default_share_peers(),
+				read_timeout: default_read_timeout(),
+				connect_timeout: default_connect_timeout(),
+				max_clock_skew: default_max_clock_skew(),
+				tls: false,
+				offline_after: default_offline_after(),
+				heartbeat_interval: default_heartbeat_interval(),
+				pin_addresses: false,
+				max_request_size: default_max_request_size(),
+				save_interval: default_save_interval(),
+				ping_interval: default_ping_interval(),
+			}
+		}
+	}
+
+	fn default_address() -> String {
+		SocketAddr::from((Ipv4Addr::UNSPECIFIED, 7040)).to_string()
+	}
+
+	fn default_share_peers() -> bool {
+		true
+	}
+
+	fn default_read_timeout() -> u64 {
+		30
+	}
+
+	fn default_connect_timeout() -> u64 {
+		5
+	}
+
+	fn default_max_clock_skew() -> u64 {
+		300
+	}
+
+	fn default_offline_after() -> u64 {
+		300
+	}
+
+	fn default_heartbeat_interval() -> u64 {
+		15
+	}
+
+	fn default_max_request_size() -> usize {
+		64 * 1024
+	}
+
+	fn default_save_interval() -> u64 {
+		5
+	}
+
+	fn default_ping_interval() -> u64 {
+		60
 	}
 }
 
 pub mod crypto {
+	use crate::crypto::Algorithm;
 	use serde::Deserialize;
 
-	#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize)]
+	#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
 	pub struct Conf {
+		#[serde(default)]
+		pub algorithm: Algorithm,
+		#[serde(default = "default_rsa_bits")]
 		pub rsa_bits: u32,
+		#[serde(default = "default_uuid_version")]
+		pub uuid_version: u8,
+		#[serde(default)]
+		pub encrypt_peer_info: bool,
+	}
+
+	impl Default for Conf {
+		fn default() -> Self {
+			Self {
+				algorithm: Algorithm::default(),
+				rsa_bits: default_rsa_bits(),
+				uuid_version: default_uuid_version(),
+				encrypt_peer_info: false,
+			}
+		}
+	}
+
+	fn default_rsa_bits() -> u32 {
+		2048
+	}
+
+	fn default_uuid_version() -> u8 {
+		4
 	}
 }
 
 pub mod chat {
 	use serde::Deserialize;
-	use std::net::SocketAddr;
 
-	#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+	pub struct Conf {
+		/// Defaults to the port after the network address.
+		#[serde(default)]
+		pub address: Option<String>,
+		#[serde(default = "default_show_timestamps")]
+		pub show_timestamps: bool,
+		#[serde(default = "default_read_timeout")]
+		pub read_timeout: u64,
+		#[serde(default = "default_history_path")]
+		pub history_path: String,
+		#[serde(default = "default_history_lines")]
+		pub history_lines: usize,
+		#[serde(default = "default_max_message_size")]
+		pub max_message_size: usize,
+	}
+
+	impl Default for Conf {
+		fn default() -> Self {
+			Self {
+				address: None,
+				show_timestamps: default_show_timestamps(),
+				read_timeout: default_read_timeout(),
+				history_path: default_history_path(),
+				history_lines: default_history_lines(),
+				max_message_size: default_max_message_size(),
+			}
+		}
+	}
+
+	fn default_show_timestamps() -> bool {
+		true
+	}
+
+	fn default_read_timeout() -> u64 {
+		3600
+	}
+
+	fn default_history_path() -> String {
+		"history.jsonl".to_string()
+	}
+
+	fn default_history_lines() -> usize {
+		100
+	}
+
+	fn default_max_message_size() -> usize {
+		4096
+	}
+}
+
+pub mod transfer {
+	use crate::rpc::transfer::DEFAULT_CHUNK_SIZE;
+	use serde::Deserialize;
+
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+	pub struct Conf {
+		#[serde(default = "default_chunk_size")]
+		pub chunk_size: usize,
+		#[serde(default = "default_downloads")]
+		pub downloads: String,
+	}
+
+	impl Default for Conf {
+		fn default() -> Self {
+			Self { chunk_size: default_chunk_size(), downloads: default_downloads() }
+		}
+	}
+
+	fn default_chunk_size() -> usize {
+		DEFAULT_CHUNK_SIZE
+	}
+
+	fn default_downloads() -> String {
+		"Downloads".to_string()
+	}
+}
+
+pub mod peer {
+	use serde::Deserialize;
+
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Deserialize)]
 	pub struct Conf {
-		pub address: SocketAddr,
+		pub nickname: Option<String>,
+	}
+}
+
+pub mod peers {
+	use serde::Deserialize;
+
+	#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+	pub struct Conf {
+		pub expire_after: Option<String>,
+		pub never_seen_expire_after: Option<String>,
+		#[serde(default = "default_max_failed_attempts")]
+		pub max_failed_attempts: u32,
+	}
+
+	impl Default for Conf {
+		fn default() -> Self {
+			Self {
+				expire_after: None,
+				never_seen_expire_after: None,
+				max_failed_attempts: default_max_failed_attempts(),
+			}
+		}
+	}
+
+	fn default_max_failed_attempts() -> u32 {
+		5
 	}
 }
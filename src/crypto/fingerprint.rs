@@ -0,0 +1,106 @@
+use openssl::error::ErrorStack;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+
+/// Number of SHA-256 digest bytes shown in a short fingerprint.
+const FINGERPRINT_LEN: usize = 8;
+
+/// Words used to spell out a fingerprint, one per 6 bits of the digest.
+const WORDS: [&str; 64] = [
+	"acid", "aqua", "atom", "axis", "bark", "bead", "bell", "bird", "bolt", "bone", "cake", "calm",
+	"cave", "claw", "coal", "cord", "crab", "dawn", "deer", "dice", "dove", "drum", "dune", "echo",
+	"fern", "fire", "flag", "foam", "fork", "frog", "gale", "gold", "harp", "hawk", "hill", "iris",
+	"jade", "kelp", "kite", "lake", "lamp", "leaf", "lime", "lion", "mint", "moon", "moss", "nest",
+	"oak", "opal", "owl", "pear", "pine", "plum", "rain", "reef", "rose", "ruby", "sage", "salt",
+	"seal", "snow", "tide", "wolf",
+];
+
+/// Number of words in a word fingerprint.
+const WORD_COUNT: usize = 8;
+
+/// Returns a short fingerprint of the PEM-encoded public key for out-of-band verification.
+///
+/// The fingerprint is the first [`FINGERPRINT_LEN`] bytes of the SHA-256 digest of the DER-encoded
+/// key, hex-encoded and grouped by colons, e.g. `3f:a2:09:7c:e1:44:b0:5d`.
+///
+/// # Errors
+///
+/// This function returns [`ErrorStack`] if the public key can't be parsed.
+pub fn fingerprint<K>(public_key: K) -> Result<String, ErrorStack>
+where
+	K: AsRef<[u8]>,
+{
+	Ok(to_hex(&digest(public_key)?[..FINGERPRINT_LEN]))
+}
+
+/// Returns the full SHA-256 fingerprint of the PEM-encoded public key, hex-encoded and grouped
+/// by colons.
+///
+/// # Errors
+///
+/// This function returns [`ErrorStack`] if the public key can't be parsed.
+pub fn full_fingerprint<K>(public_key: K) -> Result<String, ErrorStack>
+where
+	K: AsRef<[u8]>,
+{
+	Ok(to_hex(&digest(public_key)?))
+}
+
+/// Returns the fingerprint of the PEM-encoded public key spelled out as [`WORD_COUNT`] words,
+/// which is easier to compare by voice, e.g. `owl-fern-dice-moon-acid-snow-harp-lime`.
+///
+/// # Errors
+///
+/// This function returns [`ErrorStack`] if the public key can't be parsed.
+pub fn word_fingerprint<K>(public_key: K) -> Result<String, ErrorStack>
+where
+	K: AsRef<[u8]>,
+{
+	let digest = digest(public_key)?;
+	let bits = digest[..8].iter().fold(0u64, |bits, &b| (bits << 8) | b as u64);
+	let words: Vec<_> =
+		(0..WORD_COUNT).map(|i| WORDS[((bits >> (58 - 6 * i)) & 0x3f) as usize]).collect();
+	Ok(words.join("-"))
+}
+
+/// Returns the SHA-256 digest of the DER-encoded public key.
+fn digest<K>(public_key: K) -> Result<[u8; 32], ErrorStack>
+where
+	K: AsRef<[u8]>,
+{
+	let der = PKey::public_key_from_pem(public_key.as_ref())?.public_key_to_der()?;
+	Ok(sha256(&der))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Public key from the first Ed25519 test vector of RFC 8032.
+	const PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEA11qYAYKxCrfVS/7TyWQHOg7hcvPapiMlrwIaaPcHURo=
+-----END PUBLIC KEY-----
+";
+
+	#[test]
+	fn fingerprints_are_pinned() {
+		assert_eq!(fingerprint(PUBLIC_KEY).unwrap(), "06:e3:fd:8f:da:29:bb:60");
+		assert_eq!(
+			full_fingerprint(PUBLIC_KEY).unwrap(),
+			"06:e3:fd:8f:da:29:bb:60:ab:59:55:7d:e6:1e:db:0a:ec:db:23:11:34:be:30:e7:5b:45:5f:8e:1b:79:2f:a9"
+		);
+		assert_eq!(
+			word_fingerprint(PUBLIC_KEY).unwrap(),
+			"aqua-moss-cord-snow-iris-snow-lamp-leaf"
+		);
+	}
+
+	#[test]
+	fn invalid_key_has_no_fingerprint() {
+		assert!(fingerprint("not a key").is_err());
+	}
+}
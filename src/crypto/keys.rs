@@ -0,0 +1,251 @@
+use crate::crypto::signature;
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::x509::{X509Builder, X509NameBuilder};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
+
+/// Public key algorithm of a key pair.
+#[derive(
+	Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize,
+)]
+pub enum Algorithm {
+	#[default]
+	#[serde(rename = "rsa")]
+	Rsa,
+	#[serde(rename = "ed25519")]
+	Ed25519,
+}
+
+impl Display for Algorithm {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Rsa => write!(f, "RSA"),
+			Self::Ed25519 => write!(f, "Ed25519"),
+		}
+	}
+}
+
+/// Number of days a self-signed certificate is valid for.
+const CERT_VALIDITY_DAYS: u32 = 100 * 365;
+
+/// Our private key along with its public half.
+///
+/// Both algorithms can sign handshakes, but only RSA keys can receive session keys, so peers
+/// using Ed25519 keys chat unencrypted.
+#[derive(Clone)]
+pub struct KeyPair {
+	pkey: PKey<Private>,
+}
+
+impl KeyPair {
+	/// Generates a new key pair, using `rsa_bits` as the modulus size for RSA keys.
+	///
+	/// # Errors
+	///
+	/// If the key can't be generated, error kind is [`ErrorKind::GenerationError`].
+	pub fn generate(algorithm: Algorithm, rsa_bits: u32) -> Result<Self, Error> {
+		let pkey = match algorithm {
+			Algorithm::Rsa => Rsa::generate(rsa_bits).and_then(PKey::from_rsa),
+			Algorithm::Ed25519 => PKey::generate_ed25519(),
+		}
+		.map_err(|e| Error::new(ErrorKind::GenerationError, e))?;
+		Ok(Self { pkey })
+	}
+
+	/// Parses a PEM-encoded private key.
+	///
+	/// # Errors
+	///
+	/// If the key can't be parsed or uses an unsupported algorithm, error kind is
+	/// [`ErrorKind::InvalidKey`].
+	pub fn from_pem(pem: &[u8]) -> Result<Self, Error> {
+		let pkey =
+			PKey::private_key_from_pem(pem).map_err(|e| Error::new(ErrorKind::InvalidKey, e))?;
+		let key_pair = Self { pkey };
+		key_pair.algorithm()?;
+		Ok(key_pair)
+	}
+
+	/// Returns the algorithm of the key pair.
+	///
+	/// # Errors
+	///
+	/// If the key is neither RSA nor Ed25519, error kind is [`ErrorKind::InvalidKey`].
+	pub fn algorithm(&self) -> Result<Algorithm, Error> {
+		match self.pkey.id() {
+			Id::RSA => Ok(Algorithm::Rsa),
+			Id::ED25519 => Ok(Algorithm::Ed25519),
+			id => Err(Error::new(ErrorKind::InvalidKey, format!("unsupported key type {id:?}"))),
+		}
+	}
+
+	/// Returns the PEM-encoded private key.
+	///
+	/// # Errors
+	///
+	/// If the key can't be encoded, error kind is [`ErrorKind::InvalidKey`].
+	pub fn private_pem(&self) -> Result<Vec<u8>, Error> {
+		self.pkey.private_key_to_pem_pkcs8().map_err(|e| Error::new(ErrorKind::InvalidKey, e))
+	}
+
+	/// Returns the PEM-encoded public key.
+	///
+	/// # Errors
+	///
+	/// If the key can't be encoded, error kind is [`ErrorKind::InvalidKey`].
+	pub fn public_pem(&self) -> Result<String, Error> {
+		let pem =
+			self.pkey.public_key_to_pem().map_err(|e| Error::new(ErrorKind::InvalidKey, e))?;
+		String::from_utf8(pem).map_err(|e| Error::new(ErrorKind::InvalidKey, e))
+	}
+
+	/// Signs data with the private key.
+	///
+	/// # Errors
+	///
+	/// See [`signature::sign`].
+	pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, signature::Error> {
+		signature::sign(&self.pkey, data)
+	}
+
+	/// Verifies that the signature over data was made with this key pair.
+	///
+	/// # Errors
+	///
+	/// See [`signature::verify`].
+	pub fn verify(&self, data: &[u8], sig: &[u8]) -> Result<(), signature::Error> {
+		let public_key = self
+			.public_pem()
+			.map_err(|e| signature::Error::new(signature::ErrorKind::InvalidKey, e))?;
+		signature::verify(public_key, data, sig)
+	}
+
+	/// Returns a PEM-encoded self-signed certificate for the key pair, used for TLS.
+	///
+	/// Peers don't trust the certificate itself, they compare its public key with the pinned one.
+	///
+	/// # Errors
+	///
+	/// If the certificate can't be built, error kind is [`ErrorKind::GenerationError`].
+	pub fn self_signed_cert(&self, common_name: &str) -> Result<Vec<u8>, Error> {
+		build_cert(&self.pkey, common_name).map_err(|e| Error::new(ErrorKind::GenerationError, e))
+	}
+
+	/// Returns the underlying OpenSSL key.
+	pub fn pkey(&self) -> &PKey<Private> {
+		&self.pkey
+	}
+}
+
+/// Builds a self-signed certificate for the key, see [`KeyPair::self_signed_cert`].
+fn build_cert(pkey: &PKey<Private>, common_name: &str) -> Result<Vec<u8>, ErrorStack> {
+	let mut name = X509NameBuilder::new()?;
+	name.append_entry_by_text("CN", common_name)?;
+	let name = name.build();
+	let mut serial = BigNum::new()?;
+	serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+	let serial = Asn1Integer::from_bn(&serial)?;
+	let not_before = Asn1Time::days_from_now(0)?;
+	let not_after = Asn1Time::days_from_now(CERT_VALIDITY_DAYS)?;
+
+	let mut builder = X509Builder::new()?;
+	builder.set_version(2)?;
+	builder.set_serial_number(&serial)?;
+	builder.set_subject_name(&name)?;
+	builder.set_issuer_name(&name)?;
+	builder.set_not_before(&not_before)?;
+	builder.set_not_after(&not_after)?;
+	builder.set_pubkey(pkey)?;
+	let digest = match pkey.id() {
+		Id::ED25519 => MessageDigest::null(),
+		_ => MessageDigest::sha256(),
+	};
+	builder.sign(pkey, digest)?;
+	builder.build().to_pem()
+}
+
+/// Returns whether two PEM-encoded public keys are the same key, regardless of encoding details.
+pub fn same_public_key<A, B>(a: A, b: B) -> bool
+where
+	A: AsRef<[u8]>,
+	B: AsRef<[u8]>,
+{
+	match (PKey::public_key_from_pem(a.as_ref()), PKey::public_key_from_pem(b.as_ref())) {
+		(Ok(a), Ok(b)) => a.public_eq(&b),
+		_ => false,
+	}
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	InvalidKey,
+	GenerationError,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key_pairs() -> [KeyPair; 2] {
+		[
+			KeyPair::generate(Algorithm::Rsa, 1024).unwrap(),
+			KeyPair::generate(Algorithm::Ed25519, 0).unwrap(),
+		]
+	}
+
+	#[test]
+	fn signatures_round_trip_with_either_algorithm() {
+		for key_pair in key_pairs() {
+			let sig = key_pair.sign(b"ping").unwrap();
+			key_pair.verify(b"ping", &sig).unwrap();
+			assert!(key_pair.verify(b"pong", &sig).is_err());
+			signature::verify(key_pair.public_pem().unwrap(), b"ping", &sig).unwrap();
+		}
+		let [rsa, ed25519] = key_pairs();
+		assert!(ed25519.verify(b"ping", &rsa.sign(b"ping").unwrap()).is_err());
+	}
+
+	#[test]
+	fn private_key_round_trips_through_pem() {
+		for (key_pair, algorithm) in
+			key_pairs().into_iter().zip([Algorithm::Rsa, Algorithm::Ed25519])
+		{
+			let loaded = KeyPair::from_pem(&key_pair.private_pem().unwrap()).unwrap();
+			assert_eq!(loaded.algorithm().unwrap(), algorithm);
+			assert!(same_public_key(loaded.public_pem().unwrap(), key_pair.public_pem().unwrap()));
+			loaded.verify(b"ping", &key_pair.sign(b"ping").unwrap()).unwrap();
+			assert!(!key_pair.self_signed_cert("peer").unwrap().is_empty());
+		}
+	}
+}
@@ -1,3 +1,10 @@
-pub use uuid::{Uuid, UuidV4};
+pub use fingerprint::{fingerprint, full_fingerprint, word_fingerprint};
+pub use keys::{Algorithm, KeyPair};
+pub use session::{Sealed, Session};
+pub use uuid::{Uuid, UuidV4, UuidVersion};
 
+pub mod fingerprint;
+pub mod keys;
+pub mod session;
+pub mod signature;
 pub mod uuid;
@@ -0,0 +1,175 @@
+use crate::crypto::KeyPair;
+use openssl::rand::rand_bytes;
+use openssl::rsa::{Padding, Rsa};
+use openssl::sha::Sha256;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
+
+/// Length of a session key in bytes.
+const KEY_LEN: usize = 32;
+
+/// Symmetric AES-256-GCM key used to encrypt chat messages on a single connection.
+///
+/// A session is created by the sender for each connection and sent to the receiver wrapped with
+/// the receiver's RSA public key. It only lives in memory and is never persisted. Ed25519 keys
+/// can't encrypt, so peers using them get no session.
+///
+/// A session can also be derived from our own key pair to encrypt local files.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Session {
+	key: [u8; KEY_LEN],
+}
+
+/// Payload encrypted with a session key.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Sealed {
+	pub nonce: Vec<u8>,
+	pub tag: Vec<u8>,
+	pub ciphertext: Vec<u8>,
+}
+
+impl Session {
+	/// Creates a session with a random key.
+	///
+	/// # Errors
+	///
+	/// If the random key can't be generated, error kind is [`ErrorKind::EncryptionError`].
+	pub fn new() -> Result<Self, Error> {
+		let mut key = [0; KEY_LEN];
+		rand_bytes(&mut key).map_err(|e| Error::new(ErrorKind::EncryptionError, e))?;
+		Ok(Self { key })
+	}
+
+	/// Derives a session from the private key, so the same key pair and context always yield the
+	/// same session.
+	///
+	/// The key is the SHA-256 digest of the context followed by the DER-encoded private key.
+	///
+	/// # Errors
+	///
+	/// If the private key can't be encoded, error kind is [`ErrorKind::InvalidKey`].
+	pub fn derive(key_pair: &KeyPair, context: &[u8]) -> Result<Self, Error> {
+		let der = key_pair
+			.pkey()
+			.private_key_to_der()
+			.map_err(|e| Error::new(ErrorKind::InvalidKey, e))?;
+		let mut hasher = Sha256::new();
+		hasher.update(context);
+		hasher.update(&der);
+		Ok(Self { key: hasher.finish() })
+	}
+
+	/// Encrypts the session key for the owner of the PEM-encoded RSA public key.
+	///
+	/// # Errors
+	///
+	/// If the public key can't be parsed, error kind is [`ErrorKind::InvalidKey`].
+	/// If encryption fails, error kind is [`ErrorKind::EncryptionError`].
+	pub fn wrap<K>(&self, public_key: K) -> Result<Vec<u8>, Error>
+	where
+		K: AsRef<[u8]>,
+	{
+		let rsa = Rsa::public_key_from_pem(public_key.as_ref())
+			.map_err(|e| Error::new(ErrorKind::InvalidKey, e))?;
+		let mut wrapped = vec![0; rsa.size() as usize];
+		let len = rsa
+			.public_encrypt(&self.key, &mut wrapped, Padding::PKCS1_OAEP)
+			.map_err(|e| Error::new(ErrorKind::EncryptionError, e))?;
+		wrapped.truncate(len);
+		Ok(wrapped)
+	}
+
+	/// Decrypts a session key wrapped for the owner of the RSA key pair.
+	///
+	/// # Errors
+	///
+	/// If the key pair is not RSA, error kind is [`ErrorKind::InvalidKey`].
+	/// If the key wasn't wrapped for this private key or has the wrong length, error kind is
+	/// [`ErrorKind::DecryptionError`].
+	pub fn unwrap(key_pair: &KeyPair, wrapped: &[u8]) -> Result<Self, Error> {
+		let private_key = key_pair
+			.pkey()
+			.rsa()
+			.map_err(|_| Error::new(ErrorKind::InvalidKey, "session keys require an RSA key"))?;
+		let mut key = vec![0; private_key.size() as usize];
+		let len = private_key
+			.private_decrypt(wrapped, &mut key, Padding::PKCS1_OAEP)
+			.map_err(|e| Error::new(ErrorKind::DecryptionError, e))?;
+		let key = key[..len]
+			.try_into()
+			.map_err(|_| Error::new(ErrorKind::DecryptionError, "session key has wrong length"))?;
+		Ok(Self { key })
+	}
+
+	/// Encrypts data with the session key.
+	///
+	/// # Errors
+	///
+	/// If encryption fails, error kind is [`ErrorKind::EncryptionError`].
+	pub fn encrypt(&self, data: &[u8]) -> Result<Sealed, Error> {
+		let mut nonce = [0; 12];
+		let mut tag = [0; 16];
+		rand_bytes(&mut nonce).map_err(|e| Error::new(ErrorKind::EncryptionError, e))?;
+		let ciphertext =
+			encrypt_aead(Cipher::aes_256_gcm(), &self.key, Some(&nonce), &[], data, &mut tag)
+				.map_err(|e| Error::new(ErrorKind::EncryptionError, e))?;
+		Ok(Sealed { nonce: nonce.to_vec(), tag: tag.to_vec(), ciphertext })
+	}
+
+	/// Decrypts data sealed with the session key.
+	///
+	/// # Errors
+	///
+	/// If the data wasn't sealed with this key or was tampered with, error kind is
+	/// [`ErrorKind::DecryptionError`].
+	pub fn decrypt(&self, sealed: &Sealed) -> Result<Vec<u8>, Error> {
+		decrypt_aead(
+			Cipher::aes_256_gcm(),
+			&self.key,
+			Some(&sealed.nonce),
+			&[],
+			&sealed.ciphertext,
+			&sealed.tag,
+		)
+		.map_err(|e| Error::new(ErrorKind::DecryptionError, e))
+	}
+}
+
+impl Debug for Session {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Session").finish_non_exhaustive()
+	}
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	InvalidKey,
+	EncryptionError,
+	DecryptionError,
+}
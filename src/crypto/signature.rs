@@ -0,0 +1,89 @@
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private};
+use openssl::sign::{Signer, Verifier};
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
+
+/// Signs data with the private key.
+///
+/// RSA keys sign a SHA-256 digest of the data, Ed25519 keys sign the data itself.
+///
+/// # Errors
+///
+/// If signing fails, error kind is [`ErrorKind::SigningError`].
+pub fn sign(private_key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>, Error> {
+	let signer = match digest(private_key) {
+		Some(digest) => Signer::new(digest, private_key),
+		None => Signer::new_without_digest(private_key),
+	};
+	let mut signer = signer.map_err(|e| Error::new(ErrorKind::SigningError, e))?;
+	signer.sign_oneshot_to_vec(data).map_err(|e| Error::new(ErrorKind::SigningError, e))
+}
+
+/// Verifies that the signature over data was made with the private key matching the PEM-encoded
+/// RSA or Ed25519 public key.
+///
+/// # Errors
+///
+/// If the public key can't be parsed, error kind is [`ErrorKind::InvalidKey`].
+/// If the signature doesn't match, error kind is [`ErrorKind::InvalidSignature`].
+pub fn verify<K>(public_key: K, data: &[u8], signature: &[u8]) -> Result<(), Error>
+where
+	K: AsRef<[u8]>,
+{
+	let pkey = PKey::public_key_from_pem(public_key.as_ref())
+		.map_err(|e| Error::new(ErrorKind::InvalidKey, e))?;
+	let verifier = match digest(&pkey) {
+		Some(digest) => Verifier::new(digest, &pkey),
+		None => Verifier::new_without_digest(&pkey),
+	};
+	let mut verifier = verifier.map_err(|e| Error::new(ErrorKind::InvalidKey, e))?;
+	match verifier.verify_oneshot(signature, data) {
+		Ok(true) => Ok(()),
+		Ok(false) => Err(Error::new(ErrorKind::InvalidSignature, "signature doesn't match")),
+		Err(e) => Err(Error::new(ErrorKind::InvalidSignature, e)),
+	}
+}
+
+/// Returns the digest to sign with, or `None` for Ed25519 keys, which hash internally.
+fn digest<T>(pkey: &PKeyRef<T>) -> Option<MessageDigest>
+where
+	T: HasPublic,
+{
+	match pkey.id() {
+		Id::ED25519 => None,
+		_ => Some(MessageDigest::sha256()),
+	}
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	InvalidKey,
+	SigningError,
+	MissingSignature,
+	InvalidSignature,
+}
@@ -2,10 +2,80 @@ use rand::random;
 use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Timestamp and counter of the last [`UuidV7`] created, so the next one sorts after it.
+static LAST_V7: Mutex<(u64, u16)> = Mutex::new((0, 0));
+
+/// Largest value of the 12-bit counter following the timestamp of a [`UuidV7`].
+const MAX_V7_COUNTER: u16 = 0x0fff;
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub enum Uuid {
 	V4(UuidV4),
+	V7(UuidV7),
+}
+
+impl Uuid {
+	/// Creates a UUID of the given version.
+	pub fn new(version: UuidVersion) -> Self {
+		match version {
+			UuidVersion::V4 => Self::new_v4(),
+			UuidVersion::V7 => Self::now_v7(),
+		}
+	}
+
+	/// Creates a random UUID.
+	pub fn new_v4() -> Self {
+		Self::V4(UuidV4::new())
+	}
+
+	/// Creates a time-ordered UUID for the current time, which compares greater than UUIDs created
+	/// in earlier milliseconds.
+	pub fn now_v7() -> Self {
+		Self::V7(UuidV7::new())
+	}
+
+	/// Returns the version number of the UUID.
+	pub fn version(&self) -> u8 {
+		match self {
+			Self::V4(_) => 4,
+			Self::V7(_) => 7,
+		}
+	}
+
+	/// Returns the UUID bytes in network byte order.
+	pub fn as_bytes(&self) -> &[u8; 16] {
+		match self {
+			Self::V4(v4) => v4.as_bytes(),
+			Self::V7(v7) => v7.as_bytes(),
+		}
+	}
+}
+
+/// UUID version used for newly generated identifiers.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum UuidVersion {
+	#[default]
+	V4,
+	V7,
+}
+
+impl TryFrom<u8> for UuidVersion {
+	type Error = Error;
+
+	fn try_from(version: u8) -> Result<Self, Self::Error> {
+		match version {
+			4 => Ok(Self::V4),
+			7 => Ok(Self::V7),
+			_ => Err(Error::new(
+				ErrorKind::ParseError,
+				format!("unsupported UUID version {version}"),
+			)),
+		}
+	}
 }
 
 impl Default for Uuid {
@@ -18,6 +88,7 @@ impl Display for Uuid {
 	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
 		match self {
 			Self::V4(v4) => Display::fmt(v4, f),
+			Self::V7(v7) => Display::fmt(v7, f),
 		}
 	}
 }
@@ -27,9 +98,7 @@ impl Serialize for Uuid {
 	where
 		S: Serializer,
 	{
-		match self {
-			Self::V4(v4) => serializer.serialize_str(&v4.to_string()),
-		}
+		serializer.serialize_str(&self.to_string())
 	}
 }
 
@@ -38,9 +107,37 @@ impl<'de> Deserialize<'de> for Uuid {
 	where
 		D: Deserializer<'de>,
 	{
-		UuidV4::try_from(String::deserialize(deserializer)?)
-			.map_err(de::Error::custom)
-			.map(UuidV4::into)
+		String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+	}
+}
+
+impl FromStr for Uuid {
+	type Err = Error;
+
+	/// Parses a hyphenated UUID, detecting its version.
+	///
+	/// Hex digits may be upper or lower case, and the UUID may be prefixed with `urn:uuid:`. Only
+	/// UUIDs with both the version 7 and the RFC 4122 variant bits set are time-ordered, anything
+	/// else is parsed as [`UuidV4`].
+	///
+	/// # Errors
+	///
+	/// If the string isn't a valid UUID, error kind is [`ErrorKind::ParseError`].
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.parse::<UuidV7>() {
+			Ok(v7) => Ok(v7.into()),
+			Err(e) if e.kind == ErrorKind::VersionMismatch => s.parse::<UuidV4>().map(Into::into),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+/// Strips an optional case-insensitive `urn:uuid:` prefix.
+fn strip_urn(s: &str) -> &str {
+	const URN_PREFIX: &str = "urn:uuid:";
+	match s.get(..URN_PREFIX.len()) {
+		Some(prefix) if prefix.eq_ignore_ascii_case(URN_PREFIX) => &s[URN_PREFIX.len()..],
+		_ => s,
 	}
 }
 
@@ -50,6 +147,12 @@ impl From<UuidV4> for Uuid {
 	}
 }
 
+impl From<UuidV7> for Uuid {
+	fn from(v7: UuidV7) -> Self {
+		Self::V7(v7)
+	}
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Serialize, Deserialize)]
 pub struct UuidV4([u8; 16]);
 
@@ -60,20 +163,21 @@ impl UuidV4 {
 		rng[8] = (rng[8] & 0x3f) | 0x80;
 		Self(rng)
 	}
+
+	/// Creates a UUID from bytes in network byte order, without checking the version.
+	pub fn from_bytes(bytes: [u8; 16]) -> Self {
+		Self(bytes)
+	}
+
+	/// Returns the UUID bytes in network byte order.
+	pub fn as_bytes(&self) -> &[u8; 16] {
+		&self.0
+	}
 }
 
 impl Display for UuidV4 {
 	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-		let b = self.0;
-		write!(
-			f,
-			"{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
-			u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
-			u16::from_le_bytes([b[4], b[5]]),
-			u16::from_le_bytes([b[6], b[7]]),
-			u16::from_le_bytes([b[8], b[9]]),
-			u64::from_le_bytes([b[10], b[11], b[12], b[13], b[14], b[15], 0, 0])
-		)
+		fmt_hyphenated(&self.0, f)
 	}
 }
 
@@ -87,40 +191,165 @@ impl TryFrom<String> for UuidV4 {
 	type Error = Error;
 
 	fn try_from(s: String) -> Result<Self, Self::Error> {
-		let segments: Vec<_> = s.split('-').collect();
-		if segments.len() != 5 {
-			return Err(Error::new(ErrorKind::ParseError, "expected 5 hyphen-separated segments"));
+		s.parse()
+	}
+}
+
+impl FromStr for UuidV4 {
+	type Err = Error;
+
+	/// Parses a hyphenated UUID.
+	///
+	/// Older builds printed each segment in little-endian order, so their version and variant bits
+	/// land in the wrong places. A UUID without valid version and variant bits that has them once
+	/// its segments are reversed is read in that legacy order, keeping the bytes those builds had
+	/// and printing in network order from then on. Anything else is kept as written, whatever its
+	/// version.
+	///
+	/// # Errors
+	///
+	/// If the string isn't a valid UUID, error kind is [`ErrorKind::ParseError`].
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let bytes = parse_hyphenated(s)?;
+		if version(&bytes).is_none() {
+			let legacy = from_legacy_order(bytes);
+			if version(&legacy) == Some(4) {
+				return Ok(Self::from_bytes(legacy));
+			}
+		}
+		Ok(Self::from_bytes(bytes))
+	}
+}
+
+/// Time-ordered UUID made of a 48-bit Unix timestamp in milliseconds, a 12-bit counter and random
+/// bits.
+///
+/// The counter starts at a random value each millisecond and counts up for every UUID created
+/// within it, as in RFC 9562 section 6.2, method 1. Bytes are stored in network byte order, so
+/// UUIDs created later in the process compare greater, even within the same millisecond.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Serialize, Deserialize)]
+pub struct UuidV7([u8; 16]);
+
+impl UuidV7 {
+	pub fn new() -> Self {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+		let (millis, counter) = {
+			let mut last = LAST_V7.lock().unwrap_or_else(PoisonError::into_inner);
+			// The counter is seeded below half its range, leaving room to count up. If the clock
+			// went back or the counter runs out, the last timestamp is carried on.
+			*last = match *last {
+				(millis, _) if now as u64 > millis => (now as u64, random::<u16>() & 0x07ff),
+				(millis, counter) if counter < MAX_V7_COUNTER => (millis, counter + 1),
+				(millis, _) => (millis + 1, random::<u16>() & 0x07ff),
+			};
+			*last
+		};
+		let mut bytes: [u8; 16] = random();
+		bytes[0..6].copy_from_slice(&millis.to_be_bytes()[2..8]);
+		bytes[6..8].copy_from_slice(&(0x7000 | counter).to_be_bytes());
+		bytes[8] = (bytes[8] & 0x3f) | 0x80;
+		Self(bytes)
+	}
+
+	/// Creates a UUID from bytes in network byte order, without checking the version.
+	pub fn from_bytes(bytes: [u8; 16]) -> Self {
+		Self(bytes)
+	}
+
+	/// Returns the UUID bytes in network byte order.
+	pub fn as_bytes(&self) -> &[u8; 16] {
+		&self.0
+	}
+}
+
+impl Display for UuidV7 {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		fmt_hyphenated(&self.0, f)
+	}
+}
+
+impl Debug for UuidV7 {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		Display::fmt(self, f)
+	}
+}
+
+impl TryFrom<String> for UuidV7 {
+	type Error = Error;
+
+	fn try_from(s: String) -> Result<Self, Self::Error> {
+		s.parse()
+	}
+}
+
+impl FromStr for UuidV7 {
+	type Err = Error;
+
+	/// Parses a hyphenated version 7 UUID.
+	///
+	/// # Errors
+	///
+	/// If the string isn't a valid UUID, error kind is [`ErrorKind::ParseError`].
+	/// If its version isn't 7 or its variant isn't RFC 4122, error kind is
+	/// [`ErrorKind::VersionMismatch`].
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let bytes = parse_hyphenated(s)?;
+		if version(&bytes) != Some(7) {
+			return Err(Error::new(ErrorKind::VersionMismatch, "expected version 7"));
 		}
-		let mut bytes = [0; 16];
-		bytes[0..4].copy_from_slice(
-			&u32::from_str_radix(segments[0], 16)
-				.map_err(|_| Error::new(ErrorKind::ParseError, "found invalid hexadecimal"))?
-				.to_le_bytes(),
-		);
-		bytes[4..6].copy_from_slice(
-			&u16::from_str_radix(segments[1], 16)
-				.map_err(|_| Error::new(ErrorKind::ParseError, "found invalid hexadecimal"))?
-				.to_le_bytes(),
-		);
-		bytes[6..8].copy_from_slice(
-			&u16::from_str_radix(segments[2], 16)
-				.map_err(|_| Error::new(ErrorKind::ParseError, "found invalid hexadecimal"))?
-				.to_le_bytes(),
-		);
-		bytes[8..10].copy_from_slice(
-			&u16::from_str_radix(segments[3], 16)
-				.map_err(|_| Error::new(ErrorKind::ParseError, "found invalid hexadecimal"))?
-				.to_le_bytes(),
-		);
-		bytes[10..16].copy_from_slice(
-			&u64::from_str_radix(segments[4], 16)
-				.map_err(|_| Error::new(ErrorKind::ParseError, "found invalid hexadecimal"))?
-				.to_le_bytes()[0..6],
-		);
-		Ok(Self(bytes))
+		Ok(Self::from_bytes(bytes))
 	}
 }
 
+/// Returns the version of UUID bytes in network byte order, or `None` if the variant isn't
+/// RFC 4122 or the version is neither 4 nor 7.
+fn version(bytes: &[u8; 16]) -> Option<u8> {
+	// The version is the high nibble of byte 6, e.g. `xxxxxxxx-xxxx-7xxx-...`, and the variant is
+	// the top two bits of byte 8, which are `10`.
+	match (bytes[6] >> 4, bytes[8] >> 6) {
+		(version @ (4 | 7), 0b10) => Some(version),
+		_ => None,
+	}
+}
+
+/// Converts bytes parsed from the text form printed by older builds, whose segments were
+/// little-endian integers, back into the bytes those builds had.
+fn from_legacy_order(mut bytes: [u8; 16]) -> [u8; 16] {
+	for segment in [0..4, 4..6, 6..8, 8..10, 10..16] {
+		bytes[segment].reverse();
+	}
+	bytes
+}
+
+/// Formats UUID bytes in the RFC 4122 hyphenated form, in network byte order.
+fn fmt_hyphenated(bytes: &[u8; 16], f: &mut Formatter<'_>) -> fmt::Result {
+	for (i, byte) in bytes.iter().enumerate() {
+		if matches!(i, 4 | 6 | 8 | 10) {
+			f.write_str("-")?;
+		}
+		write!(f, "{byte:02x}")?;
+	}
+	Ok(())
+}
+
+/// Parses UUID bytes from the RFC 4122 hyphenated form with an optional `urn:uuid:` prefix.
+fn parse_hyphenated(s: &str) -> Result<[u8; 16], Error> {
+	let segments: Vec<_> = strip_urn(s).split('-').collect();
+	if segments.iter().map(|segment| segment.len()).ne([8, 4, 4, 4, 12]) {
+		return Err(Error::new(ErrorKind::ParseError, "expected 8-4-4-4-12 hex digits"));
+	}
+	let hex = segments.concat();
+	if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+		return Err(Error::new(ErrorKind::ParseError, "found invalid hexadecimal"));
+	}
+	let mut bytes = [0; 16];
+	for (i, byte) in bytes.iter_mut().enumerate() {
+		*byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+			.map_err(|_| Error::new(ErrorKind::ParseError, "found invalid hexadecimal"))?;
+	}
+	Ok(bytes)
+}
+
 #[derive(Debug)]
 pub struct Error {
 	pub kind: ErrorKind,
@@ -148,4 +377,147 @@ impl std::error::Error for Error {}
 pub enum ErrorKind {
 	#[default]
 	ParseError,
+	VersionMismatch,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::prelude::*;
+
+	/// Formats bytes the way builds before network byte order did.
+	fn fmt_legacy(b: &[u8; 16]) -> String {
+		format!(
+			"{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+			u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+			u16::from_le_bytes([b[4], b[5]]),
+			u16::from_le_bytes([b[6], b[7]]),
+			u16::from_le_bytes([b[8], b[9]]),
+			u64::from_le_bytes([b[10], b[11], b[12], b[13], b[14], b[15], 0, 0])
+		)
+	}
+
+	#[test]
+	fn legacy_v4_keeps_its_bytes() {
+		let bytes: [u8; 16] = [
+			0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x47, 0x08, 0x89, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+			0x0f, 0x10,
+		];
+		let legacy = fmt_legacy(&bytes);
+		assert_eq!(legacy, "04030201-0605-0847-0a89-100f0e0d0c0b");
+		let parsed: UuidV4 = legacy.parse().unwrap();
+		assert_eq!(parsed.as_bytes(), &bytes);
+		assert_eq!(parsed.to_string(), "01020304-0506-4708-890a-0b0c0d0e0f10");
+		assert_eq!(legacy.parse::<Uuid>().unwrap(), Uuid::V4(parsed));
+	}
+
+	#[test]
+	fn legacy_ids_migrate() {
+		for _ in 0..1000 {
+			let id = UuidV4::new();
+			let legacy = fmt_legacy(id.as_bytes());
+			let parsed: UuidV4 = legacy.parse().unwrap();
+			// A legacy ID that happens to have valid bits in network order can't be told apart.
+			if version(&parse_hyphenated(&legacy).unwrap()).is_none() {
+				assert_eq!(parsed, id);
+			}
+		}
+	}
+
+	#[test]
+	fn version_needs_variant() {
+		let v7 = "01890a5d-ac96-774b-bcce-b302099a8057";
+		assert!(matches!(v7.parse::<Uuid>().unwrap(), Uuid::V7(_)));
+		// Same version nibble, but the NCS variant.
+		let ncs = "01890a5d-ac96-774b-4cce-b302099a8057";
+		assert!(matches!(ncs.parse::<Uuid>().unwrap(), Uuid::V4(_)));
+		assert_eq!(ncs.parse::<UuidV7>().unwrap_err().kind, ErrorKind::VersionMismatch);
+		assert_eq!("not-a-uuid".parse::<UuidV7>().unwrap_err().kind, ErrorKind::ParseError);
+	}
+
+	#[test]
+	fn foreign_versions_are_kept() {
+		let v1 = "6ba7b810-9dad-11d1-80b4-00c04fd430c8";
+		let parsed: Uuid = v1.parse().unwrap();
+		assert_eq!(parsed.to_string(), v1);
+	}
+
+	#[test]
+	fn parses_with_str_parse() {
+		let id = "0f8fad5b-d9cb-469f-a165-70867728950e";
+		let parsed: Uuid = id.parse().unwrap();
+		assert_eq!(parsed.to_string(), id);
+		assert_eq!("0F8FAD5B-D9CB-469F-A165-70867728950E".parse::<Uuid>().unwrap(), parsed);
+		assert_eq!(format!("urn:uuid:{id}").parse::<Uuid>().unwrap(), parsed);
+		assert_eq!(format!("URN:UUID:{id}").parse::<UuidV4>().unwrap().to_string(), id);
+	}
+
+	#[test]
+	fn rejects_malformed_strings() {
+		for s in [
+			"",
+			"0f8fad5bd9cb469fa16570867728950e",
+			"0f8fad5b-d9cb-469f-a165-70867728950",
+			"0f8fad5b-d9cb-469f-a165-70867728950e0",
+			"0f8fad5-bd9cb-469f-a165-70867728950e",
+			"0f8fad5b-d9cb-469f-a165-70867728950g",
+			"+f8fad5b-d9cb-469f-a165-70867728950e",
+			"urn:0f8fad5b-d9cb-469f-a165-70867728950e",
+			" 0f8fad5b-d9cb-469f-a165-70867728950e",
+		] {
+			assert_eq!(s.parse::<Uuid>().unwrap_err().kind, ErrorKind::ParseError, "{s:?}");
+		}
+	}
+
+	#[test]
+	fn v7_orders_by_creation_time() {
+		// Far more than fit in a millisecond's counter, created back to back.
+		let ids: Vec<_> = (0..10_000).map(|_| UuidV7::new()).collect();
+		assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+		assert!(ids.iter().all(|id| version(id.as_bytes()) == Some(7)));
+
+		let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+		let mut timestamp = [0; 8];
+		timestamp[2..].copy_from_slice(&ids[ids.len() - 1].as_bytes()[..6]);
+		assert!(millis.abs_diff(u64::from_be_bytes(timestamp)) < 1000);
+	}
+
+	#[test]
+	fn v7_round_trips_as_v7() {
+		let id = Uuid::now_v7();
+		assert_eq!(id.version(), 7);
+		assert_eq!(id.to_string().parse::<Uuid>().unwrap(), id);
+		let json = serde_json::to_string(&id).unwrap();
+		assert_eq!(serde_json::from_str::<Uuid>(&json).unwrap(), id);
+		let v4 = Uuid::new_v4();
+		assert_eq!(serde_json::from_str::<Uuid>(&serde_json::to_string(&v4).unwrap()).unwrap(), v4);
+	}
+
+	proptest! {
+		#[test]
+		fn round_trips_through_text(bytes: [u8; 16], version in prop_oneof![Just(0x40), Just(0x70)]) {
+			let mut bytes = bytes;
+			bytes[6] = (bytes[6] & 0x0f) | version;
+			bytes[8] = (bytes[8] & 0x3f) | 0x80;
+			let parsed: Uuid = UuidV4::from_bytes(bytes).to_string().parse().unwrap();
+			prop_assert_eq!(parsed.as_bytes(), &bytes);
+			prop_assert_eq!(parsed.version(), version >> 4);
+		}
+
+		#[test]
+		fn text_form_agrees_with_uuid_crate(bytes: [u8; 16]) {
+			let reference = uuid::Uuid::from_bytes(bytes);
+			prop_assert_eq!(UuidV4::from_bytes(bytes).to_string(), reference.to_string());
+			prop_assert_eq!(UuidV7::from_bytes(bytes).to_string(), reference.to_string());
+		}
+
+		#[test]
+		fn parses_uuid_crate_output(bytes: [u8; 16]) {
+			let reference = uuid::Builder::from_random_bytes(bytes).into_uuid();
+			let parsed: Uuid = reference.to_string().parse().unwrap();
+			prop_assert_eq!(parsed.as_bytes(), reference.as_bytes());
+			let parsed: Uuid = reference.urn().to_string().parse().unwrap();
+			prop_assert_eq!(parsed.as_bytes(), reference.as_bytes());
+		}
+	}
 }
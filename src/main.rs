@@ -1,128 +1,998 @@
-use crate::args::{gen_completion, Args, Command, ConnectArgs};
-use crate::conf::Conf;
-use crate::crypto::Uuid;
-use crate::peer::info::PeerInfo;
-use crate::peer::Peer;
+use crate::args::{
+	gen_completion, Args, BlockArgs, Command, ConfigArgs, ConfigCommand, ConnectArgs,
+	DisconnectArgs, FingerprintArgs, ForgetArgs, Format, ListArgs, NickArgs, PeerTarget, PeersArgs,
+	PeersCommand, PingArgs, PruneArgs, RefreshArgs, RenameArgs, SendArgs, StatusArgs,
+};
+use crate::conf::{Conf, Error as ConfError, ErrorKind as ConfErrorKind};
+use crate::crypto::keys::ErrorKind as KeysErrorKind;
+use crate::crypto::{KeyPair, Session, Uuid};
+use crate::node::Node;
+use crate::output::{
+	format_duration_ago, render_json, render_status, render_status_json, render_table,
+	select_peers, PeerCounts, SelfStatus,
+};
+use crate::peer::info::{ImportMode, PeerInfo};
+use crate::peer::{check_alias, find_peer, sanitize_nickname, MAX_NICKNAME_LEN};
+use crate::rpc::chat::ErrorKind as ChatErrorKind;
+use crate::rpc::daemon::ErrorKind as DaemonErrorKind;
+use crate::rpc::server::ErrorKind as ServerErrorKind;
+use crate::rpc::tls::{ErrorKind as TlsErrorKind, Tls};
 use clap::Parser;
-use log::error;
-use openssl::rsa::Rsa;
-use std::collections::HashMap;
+use futures::future::join_all;
+use log::{error, info, warn};
+use std::io::{IsTerminal, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::time::Duration;
-use tokio::fs::{create_dir_all, File};
-use tokio::io::AsyncWriteExt;
+use std::{env, io};
+use tokio::fs::{create_dir_all, read, read_to_string, write, File};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
 
 mod args;
 mod conf;
 mod crypto;
+mod node;
+mod output;
 mod peer;
 mod rpc;
 
+/// How long the status command waits for a connection to our own address.
+const LISTENING_TIMEOUT: Duration = Duration::from_millis(500);
+
 #[tokio::main]
 async fn main() {
 	env_logger::builder().format_timestamp(None).format_target(false).init();
 
 	let args = Args::parse();
-	match args.command {
+	match &args.command {
 		Command::Init => init(&args).await,
 		Command::Listen => listen(&args).await,
-		Command::Connect(connect_args) => connect(&args, &connect_args).await,
-		Command::List => list(&args).await,
+		Command::Daemon => daemon(&args).await,
+		Command::Connect(connect_args) => connect(&args, connect_args).await,
+		Command::Ping(ping_args) => ping(&args, ping_args).await,
+		Command::Refresh(refresh_args) => refresh(&args, refresh_args).await,
+		Command::Disconnect(disconnect_args) => disconnect(&args, disconnect_args).await,
+		Command::Forget(forget_args) => forget(&args, forget_args).await,
+		Command::Prune(prune_args) => prune(&args, prune_args).await,
+		Command::Peers(peers_args) => peers(&args, peers_args).await,
+		Command::Config(config_args) => config(&args, config_args).await,
+		Command::List(list_args) => list(&args, list_args).await,
+		Command::Fingerprint(fingerprint_args) => fingerprint(&args, fingerprint_args).await,
+		Command::Nick(nick_args) => nick(&args, nick_args).await,
+		Command::Rename(rename_args) => rename(&args, rename_args).await,
+		Command::Block(block_args) => block(&args, block_args, true).await,
+		Command::Unblock(block_args) => block(&args, block_args, false).await,
+		Command::Status(status_args) => status(&args, status_args).await,
 		Command::Chat => chat(&args).await,
+		Command::Send(send_args) => send(&args, send_args).await,
 		Command::Completion(completion_args) => gen_completion(completion_args.shell),
 	}
 }
 
 async fn init(args: &Args) {
-	let conf = Conf::load(&args.conf_path).unwrap_or_else(|e| {
+	let conf = match load_conf(args) {
+		Err(e) if e.kind == ConfErrorKind::FileNotFound => {
+			let path = conf_path(args);
+			write_default_conf(&path).await;
+			Conf::load(path)
+		}
+		conf => conf,
+	};
+	let conf = conf.unwrap_or_else(|e| {
 		error!("failed to load config: {e}");
 		exit(1);
 	});
 
-	let peer_info = PeerInfo::new(conf.net.addr, conf.chat.addr, &conf.path.peer_info).await;
-	if let Err(e) = peer_info.save().await {
-		error!("failed to save peer info: {e}");
+	if let Ok(pem) = read(&conf.path.private_key).await {
+		match KeyPair::from_pem(&pem).and_then(|key_pair| key_pair.algorithm()) {
+			Ok(algorithm) if algorithm != conf.crypto.algorithm => {
+				error!(
+					"existing key is {algorithm} but config asks for {}, remove {} to switch",
+					conf.crypto.algorithm,
+					conf.path.private_key.display()
+				);
+				exit(1);
+			}
+			Err(e) if e.kind == KeysErrorKind::InvalidKey => {
+				error!(
+					"existing key can't be read ({e}), remove {} to generate a new one",
+					conf.path.private_key.display()
+				);
+				exit(1);
+			}
+			_ => {}
+		}
+	}
+	let id = Uuid::new(conf.crypto.uuid_version);
+	let key_pair =
+		KeyPair::generate(conf.crypto.algorithm, conf.crypto.rsa_bits).unwrap_or_else(|e| {
+			error!("failed to generate {} key: {e}", conf.crypto.algorithm);
+			exit(1);
+		});
+	let probe = id.as_bytes();
+	if let Err(e) = key_pair.sign(probe).and_then(|sig| key_pair.verify(probe, &sig)) {
+		error!("generated {} key failed to verify its own signature: {e}", conf.crypto.algorithm);
 		exit(1);
 	}
 
-	let rsa = Rsa::generate(conf.crypto.rsa_bits).unwrap();
-
-	let private_key = rsa.private_key_to_pem().unwrap();
+	let private_key = key_pair.private_pem().unwrap();
 	create_dir_all(conf.path.private_key.parent().unwrap()).await.unwrap();
 	File::create(&conf.path.private_key).await.unwrap().write_all(&private_key).await.unwrap();
 
-	let public_key = rsa.public_key_to_pem().unwrap();
+	let public_key = key_pair.public_pem().unwrap();
 	create_dir_all(conf.path.public_key.parent().unwrap()).await.unwrap();
-	File::create(&conf.path.public_key).await.unwrap().write_all(&public_key).await.unwrap();
+	File::create(&conf.path.public_key)
+		.await
+		.unwrap()
+		.write_all(public_key.as_bytes())
+		.await
+		.unwrap();
+
+	let cert = key_pair.self_signed_cert(&id.to_string()).unwrap();
+	create_dir_all(conf.path.certificate.parent().unwrap()).await.unwrap();
+	File::create(&conf.path.certificate).await.unwrap().write_all(&cert).await.unwrap();
+
+	let mut peer_info =
+		PeerInfo::new(id, conf.net.addr, conf.chat.addr, &conf.path.peer_info).await;
+	if conf.crypto.encrypt_peer_info {
+		peer_info.set_storage_key(Some(PeerInfo::storage_key(&key_pair).unwrap()));
+	}
+	if let Err(e) = peer_info.save().await {
+		error!("failed to save peer info: {e}");
+		exit(1);
+	}
+	info!("initialized peer {id} (UUID version {})", id.version());
+}
+
+async fn config(args: &Args, config_args: &ConfigArgs) {
+	match config_args.command {
+		ConfigCommand::Init(init_args) => {
+			let path = conf_path(args);
+			if !init_args.force && path.exists() {
+				error!("config already exists at {}, pass --force to overwrite it", path.display());
+				exit(1);
+			}
+			write_default_conf(&path).await;
+		}
+		ConfigCommand::Path => println!("{}", conf_path(args).display()),
+	}
+}
+
+/// Loads config from the path given with `--config`, or from the default locations.
+///
+/// Addresses and the data directory given on the command line or in the environment take
+/// precedence over the file.
+fn load_conf(args: &Args) -> Result<Conf, ConfError> {
+	let mut conf = match &args.conf_path {
+		Some(path) => Conf::load(path),
+		None => Conf::load_default(),
+	}?;
+	if let Some(addr) = args.listen_addr {
+		conf.net.addr = addr;
+	}
+	if let Some(addr) = args.chat_addr {
+		conf.chat.addr = addr;
+	}
+	if let Some(dir) = &args.data_dir {
+		conf.set_app_dir(dir);
+	}
+	Ok(conf)
+}
+
+/// Replaces our stored addresses with ones given on the command line or in the environment,
+/// saving them so they are also advertised by other running commands, exiting on failure.
+async fn override_addrs(args: &Args, peer_info: &mut PeerInfo) {
+	if args.listen_addr.is_none() && args.chat_addr.is_none() {
+		return;
+	}
+	let saved = peer_info.load_and_modify(|peer_info| {
+		if let Some(addr) = args.listen_addr {
+			peer_info.addr = addr;
+		}
+		if let Some(addr) = args.chat_addr {
+			peer_info.chat_addr = addr;
+		}
+	});
+	if let Err(e) = saved.await {
+		error!("failed to save peer info: {e}");
+		exit(1);
+	}
+}
+
+/// Returns the config file [`load_conf`] reads, exiting on failure.
+fn conf_path(args: &Args) -> PathBuf {
+	Conf::resolve_path(args.conf_path.as_deref()).unwrap_or_else(|e| {
+		error!("failed to resolve config path: {e}");
+		exit(1);
+	})
+}
+
+/// Writes the default config to the path, creating its directory, exiting on failure.
+async fn write_default_conf(path: &Path) {
+	if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+		if let Err(e) = create_dir_all(dir).await {
+			error!("failed to create {}: {e}", dir.display());
+			exit(1);
+		}
+	}
+	if let Err(e) = write(path, Conf::default_toml()).await {
+		error!("failed to write config to {}: {e}", path.display());
+		exit(1);
+	}
+	info!("wrote default config to {}", path.display());
 }
 
 async fn listen(args: &Args) {
-	let conf = Conf::load(&args.conf_path).unwrap_or_else(|e| {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	prune_stale(&mut peer_info, &conf).await;
+	override_addrs(args, &mut peer_info).await;
+	let node = load_node(conf, peer_info).await;
+	match node.start_server().await {
+		Ok(()) => info!("stopped listening"),
+		Err(e) if e.kind == ServerErrorKind::AddrInUse => {
+			error!("{e}, p2p may already be listening, see p2p status");
+			exit(1);
+		}
+		Err(e) => {
+			error!("{e}");
+			exit(1);
+		}
+	}
+}
+
+async fn daemon(args: &Args) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
 		error!("failed to load config: {e}");
 		exit(1);
 	});
-	let peer_info = PeerInfo::load(&conf.path.peer_info).await.unwrap_or_else(|e| {
-		error!("failed to load peer info: {e}");
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	prune_stale(&mut peer_info, &conf).await;
+	override_addrs(args, &mut peer_info).await;
+	let node = load_node(conf, peer_info).await;
+	node.run_daemon().await.unwrap_or_else(|e| {
+		match e.kind {
+			DaemonErrorKind::AddrInUse => error!("{e}, p2p may already be running, see p2p status"),
+			_ => error!("{e}"),
+		}
 		exit(1);
 	});
-	rpc::server::listen(&peer_info).await;
+	info!("stopped daemon");
 }
 
 async fn connect(args: &Args, connect_args: &ConnectArgs) {
-	let conf = Conf::load(&args.conf_path).unwrap_or_else(|e| {
+	let conf = load_conf(args).unwrap_or_else(|e| {
 		error!("failed to load config: {e}");
 		exit(1);
 	});
-	let mut peer_info = PeerInfo::load(&conf.path.peer_info).await.unwrap_or_else(|e| {
-		error!("failed to load peer info: {e}");
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	override_addrs(args, &mut peer_info).await;
+	let options = rpc::client::Options {
+		trust_new_key: connect_args.trust_new_key,
+		heartbeat: connect_args.keepalive.then_some(conf.net.heartbeat_interval),
+		keep_going: connect_args.keep_going,
+		prefer_ipv6: connect_args.prefer_ipv6,
+		retries: connect_args.retry,
+		retry_delay: Duration::from_millis(connect_args.retry_delay),
+	};
+	if peer_info.addr.port() == 0 || peer_info.chat_addr.port() == 0 {
+		warn!("our address has port 0 until listen or chat binds it, so peers can't connect back");
+	}
+	let node = load_node(conf, peer_info).await;
+	let attempts = connect_args.addrs.iter().map(|addr| async {
+		let connected = node.connect(addr, options).await;
+		(addr.as_str(), connected.map_err(|e| error!("{e}")).is_ok())
+	});
+	let results = join_all(attempts).await;
+	if results.len() > 1 {
+		print_connect_results(&results);
+	}
+	if !results.iter().any(|&(_, connected)| connected) {
+		exit(1);
+	}
+}
+
+async fn ping(args: &Args, ping_args: &PingArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
 		exit(1);
 	});
-	rpc::client::connect(connect_args.addr, &mut peer_info).await;
+	let peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let (id, host) = match find_peer(&ping_args.peer, &peer_info.peers, peer_info.peers.keys()) {
+		Ok(id) => {
+			let peer = &peer_info.peers[&id];
+			(Some(id), peer.host.clone().unwrap_or_else(|| peer.addr.to_string()))
+		}
+		Err(_) if ping_args.peer.contains(':') => (None, ping_args.peer.clone()),
+		Err(e) => {
+			error!("{e}");
+			exit(1);
+		}
+	};
+	let node = load_node(conf, peer_info).await;
+	let limit = Duration::from_secs(ping_args.timeout);
+	let mut latencies = Vec::new();
+	let mut last = None;
+	for sent in 0..ping_args.count {
+		if sent > 0 {
+			sleep(Duration::from_millis(ping_args.interval)).await;
+		}
+		match timeout(limit, node.ping(&host, ping_args.prefer_ipv6)).await {
+			Ok(Ok((addr, pong, latency))) => {
+				println!("reply from {addr}: time={}", format_latency(latency));
+				latencies.push(latency);
+				last = Some((addr, pong, latency));
+			}
+			Ok(Err(e)) => error!("{e}"),
+			Err(_) => error!("peer at {host} didn't answer within {}s", limit.as_secs()),
+		}
+	}
+
+	if let Some(id) = id.or(last.as_ref().map(|(_, pong, _)| pong.peer_id)) {
+		let answer = last.as_ref().map(|(addr, pong, latency)| (*addr, pong, *latency));
+		if let Err(e) = node.record_ping(id, &host, answer).await {
+			error!("{e}");
+		}
+	}
+	println!();
+	if let Some((addr, pong, _)) = &last {
+		let nickname = pong.nickname.as_deref().and_then(sanitize_nickname);
+		println!("ID:         {}", pong.peer_id);
+		println!("Address:    {addr}");
+		println!("Name:       {}", nickname.as_deref().unwrap_or("-"));
+		println!("Chat:       {}", pong.peer_chat_addr);
+	}
+	let received = latencies.len() as u32;
+	let loss = (ping_args.count - received) * 100 / ping_args.count;
+	println!("Sent:       {}, received {received} ({loss}% loss)", ping_args.count);
+	let (Some(min), Some(max)) = (latencies.iter().min(), latencies.iter().max()) else {
+		exit(1);
+	};
+	let avg = latencies.iter().sum::<Duration>() / received;
+	println!(
+		"Round trip: min {}, avg {}, max {}",
+		format_latency(*min),
+		format_latency(avg),
+		format_latency(*max)
+	);
 }
 
-async fn list(args: &Args) {
-	let conf = Conf::load(&args.conf_path).unwrap_or_else(|e| {
+async fn refresh(args: &Args, refresh_args: &RefreshArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
 		error!("failed to load config: {e}");
 		exit(1);
 	});
-	let peer_info = PeerInfo::load(&conf.path.peer_info).await.unwrap_or_else(|e| {
-		error!("failed to load peer info: {e}");
+	let peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let node = load_node(conf, peer_info).await;
+	if node.peers().await.is_empty() {
+		println!("no known peers");
+		return;
+	}
+	let (online, unreachable) = node.refresh(refresh_args.jobs.into()).await.unwrap_or_else(|e| {
+		error!("{e}");
+		exit(1);
+	});
+	println!("{online} online, {unreachable} unreachable");
+}
+
+async fn disconnect(args: &Args, disconnect_args: &DisconnectArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
 		exit(1);
 	});
-	print_peers(peer_info.peers);
+	let peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let addrs = match disconnect_args.peer {
+		Some(PeerTarget::Addr(addr)) => vec![addr],
+		Some(PeerTarget::Id(id)) => match peer_info.peers.get(&id) {
+			Some(peer) => vec![peer.addr],
+			None => {
+				error!("peer {id} not found");
+				exit(1);
+			}
+		},
+		None => peer_info.peers.values().map(|p| p.addr).collect(),
+	};
+	let node = load_node(conf, peer_info).await;
+	for addr in addrs {
+		node.disconnect(addr).await;
+	}
+}
+
+async fn forget(args: &Args, forget_args: &ForgetArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let Some(peer) = &forget_args.peer else {
+		if !confirm(&format!("forget all {} known peer(s)?", peer_info.peers.len())) {
+			return;
+		}
+		let removed = peer_info.load_and_modify(|peer_info| {
+			let removed = peer_info.peers.len();
+			peer_info.peers.clear();
+			removed
+		});
+		match removed.await {
+			Ok(removed) => info!("removed {removed} peer(s)"),
+			Err(e) => {
+				error!("failed to save peer info: {e}");
+				exit(1);
+			}
+		}
+		return;
+	};
+
+	let id = match peer.parse() {
+		Ok(addr) => match peer_info.peers.values().find(|p| p.addr == addr) {
+			Some(peer) => peer.id,
+			None => {
+				error!("no peer at {addr}");
+				exit(1);
+			}
+		},
+		Err(_) => find_peer(peer, &peer_info.peers, peer_info.peers.keys()).unwrap_or_else(|e| {
+			error!("{e}");
+			exit(1);
+		}),
+	};
+	match peer_info.load_and_modify(|peer_info| peer_info.remove_peer(&id)).await {
+		Ok(Some(peer)) => info!("removed peer {} at {}", peer.id, peer.addr),
+		Ok(None) => info!("peer {id} was already removed"),
+		Err(e) => {
+			error!("failed to save peer info: {e}");
+			exit(1);
+		}
+	}
+}
+
+/// Asks the user a yes/no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> bool {
+	print!("{question} [y/N] ");
+	let _ = io::stdout().flush();
+	let mut answer = String::new();
+	if io::stdin().read_line(&mut answer).is_err() {
+		return false;
+	}
+	matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+async fn prune(args: &Args, prune_args: &PruneArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let peers = conf.peers;
+	let max_age = prune_args.days.map(|days| Duration::from_secs(days.saturating_mul(86400)));
+	if max_age.is_none() && peers.expire_after.is_none() && peers.never_seen_expire_after.is_none()
+	{
+		error!(
+			"set expire_after or never_seen_expire_after in [peers] of the config, or pass --days"
+		);
+		exit(1);
+	}
+	let prune = |peer_info: &mut PeerInfo| match max_age {
+		Some(max_age) => peer_info.prune_older_than(max_age),
+		None => peer_info.prune(peers.expire_after, peers.never_seen_expire_after),
+	};
+	let removed = if prune_args.dry_run {
+		prune(&mut peer_info.clone())
+	} else {
+		let pruned = peer_info.load_and_modify(prune);
+		pruned.await.unwrap_or_else(|e| {
+			error!("failed to save peer info: {e}");
+			exit(1);
+		})
+	};
+	let verb = if prune_args.dry_run { "would remove" } else { "removed" };
+	for peer in &removed {
+		let last_seen = match peer.last_seen {
+			Some(last_seen) => {
+				last_seen.elapsed().map_or("just now".to_owned(), format_duration_ago)
+			}
+			None => "never".to_owned(),
+		};
+		info!("{verb} peer {} at {}, last seen {last_seen}", peer.id, peer.addr);
+	}
+	info!("{verb} {} peer(s)", removed.len());
+}
+
+/// Forgets stale peers as configured, keeping the peer info if it can't be saved.
+async fn prune_stale(peer_info: &mut PeerInfo, conf: &Conf) {
+	let peers = conf.peers;
+	if peer_info.clone().prune(peers.expire_after, peers.never_seen_expire_after).is_empty() {
+		return;
+	}
+	let pruned = peer_info.load_and_modify(|peer_info| {
+		peer_info.prune(peers.expire_after, peers.never_seen_expire_after)
+	});
+	match pruned.await {
+		Ok(removed) => info!("pruned {} stale peer(s)", removed.len()),
+		Err(e) => warn!("failed to prune stale peers: {e}"),
+	}
+}
+
+async fn peers(args: &Args, peers_args: &PeersArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	match &peers_args.command {
+		PeersCommand::Export(export_args) => {
+			let json = peer_info.export_peers().unwrap_or_else(|e| {
+				error!("failed to export peers: {e}");
+				exit(1);
+			});
+			let Some(output) = &export_args.output else {
+				println!("{json}");
+				return;
+			};
+			if let Err(e) = write(output, json).await {
+				error!("failed to write {}: {e}", output.display());
+				exit(1);
+			}
+			info!("exported {} peer(s) to {}", peer_info.peers.len(), output.display());
+		}
+		PeersCommand::Import(import_args) => {
+			let json = read_to_string(&import_args.path).await.unwrap_or_else(|e| {
+				error!("failed to read {}: {e}", import_args.path.display());
+				exit(1);
+			});
+			let mode = if import_args.replace { ImportMode::Replace } else { ImportMode::Merge };
+			let imported =
+				peer_info.load_and_modify(|peer_info| peer_info.import_peers(&json, mode));
+			match imported.await {
+				Ok(Ok(summary)) => info!(
+					"added {} peer(s), updated {} peer(s), skipped {} peer(s)",
+					summary.added, summary.updated, summary.skipped
+				),
+				Ok(Err(e)) => {
+					error!("failed to import {}: {e}", import_args.path.display());
+					exit(1);
+				}
+				Err(e) => {
+					error!("failed to save peer info: {e}");
+					exit(1);
+				}
+			}
+		}
+	}
+}
+
+async fn list(args: &Args, list_args: &ListArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let peers = select_peers(&peer_info, list_args);
+	match list_args.format {
+		Format::Table => {
+			let colored = use_color(args) && io::stdout().is_terminal();
+			let (keys, absolute) = (list_args.keys, list_args.absolute);
+			print!("{}", render_table(&peer_info, &peers, keys, absolute, colored));
+		}
+		Format::Json => println!("{}", render_json(&peer_info, &peers)),
+	}
+}
+
+async fn status(args: &Args, status_args: &StatusArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let fingerprint = match read(&conf.path.public_key).await {
+		Ok(public_key) => crypto::full_fingerprint(public_key)
+			.map_err(|e| warn!("failed to parse public key: {e}"))
+			.ok(),
+		Err(e) => {
+			warn!("failed to load public key: {e}");
+			None
+		}
+	};
+	let addr = args.listen_addr.unwrap_or(peer_info.addr);
+	let chat_addr = args.chat_addr.unwrap_or(peer_info.chat_addr);
+	let (listening, chat_listening) = tokio::join!(is_listening(addr), is_listening(chat_addr));
+	let status = SelfStatus {
+		id: peer_info.id,
+		nickname: conf.peer.nickname.as_deref().or(peer_info.nickname.as_deref()),
+		addr,
+		chat_addr,
+		listening,
+		chat_listening,
+		fingerprint,
+		peers: PeerCounts::new(&peer_info),
+	};
+	match status_args.format {
+		Format::Table => print!("{}", render_status(&status)),
+		Format::Json => println!("{}", render_status_json(&status)),
+	}
+}
+
+/// Returns whether something on this machine accepts connections at our address, trying
+/// loopback if we listen on all interfaces.
+async fn is_listening(mut addr: SocketAddr) -> bool {
+	if addr.port() == 0 {
+		return false;
+	}
+	if addr.ip().is_unspecified() {
+		addr.set_ip(match addr {
+			SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+			SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+		});
+	}
+	matches!(timeout(LISTENING_TIMEOUT, TcpStream::connect(addr)).await, Ok(Ok(_)))
+}
+
+async fn fingerprint(args: &Args, fingerprint_args: &FingerprintArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let public_key = match fingerprint_args.peer {
+		Some(id) => {
+			let peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+				.await
+				.unwrap_or_else(|e| {
+					error!("failed to load peer info: {e}");
+					exit(1);
+				});
+			peer_info.peers.get(&id).and_then(|p| p.public_key.clone()).unwrap_or_else(|| {
+				error!("no public key pinned for peer {id}");
+				exit(1);
+			})
+		}
+		None => read(&conf.path.public_key).await.unwrap_or_else(|e| {
+			error!("failed to load public key: {e}");
+			exit(1);
+		}),
+	};
+	match (crypto::full_fingerprint(&public_key), crypto::word_fingerprint(&public_key)) {
+		(Ok(fingerprint), Ok(words)) => println!("SHA256:{fingerprint}\n{words}"),
+		(Err(e), _) | (_, Err(e)) => {
+			error!("failed to parse public key: {e}");
+			exit(1);
+		}
+	}
+}
+
+async fn nick(args: &Args, nick_args: &NickArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	if let Some(name) = &nick_args.name {
+		if sanitize_nickname(name).as_ref() != Some(name) {
+			error!(
+				"nickname must be 1 to {MAX_NICKNAME_LEN} characters without control characters"
+			);
+			exit(1);
+		}
+	}
+	let saved =
+		peer_info.load_and_modify(|peer_info| peer_info.nickname.clone_from(&nick_args.name));
+	if let Err(e) = saved.await {
+		error!("failed to save peer info: {e}");
+		exit(1);
+	}
+	if conf.peer.nickname.is_some() {
+		warn!("nickname from config takes precedence over the one set here");
+	}
+	match &peer_info.nickname {
+		Some(name) => info!("nickname set to {name}"),
+		None => info!("nickname cleared"),
+	}
+}
+
+async fn rename(args: &Args, rename_args: &RenameArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let id = find_peer(&rename_args.peer, &peer_info.peers, peer_info.peers.keys()).unwrap_or_else(
+		|e| {
+			error!("{e}");
+			exit(1);
+		},
+	);
+	let alias = rename_args.alias.clone();
+	let renamed = peer_info.load_and_modify(|peer_info| {
+		if let Some(alias) = &alias {
+			check_alias(alias, id, peer_info.peers.values())?;
+		}
+		let peer = peer_info.peers.get_mut(&id).ok_or(format!("peer {id} was removed"))?;
+		peer.alias = alias;
+		Ok::<_, String>(())
+	});
+	match renamed.await {
+		Ok(Ok(())) => match &rename_args.alias {
+			Some(alias) => info!("alias of {id} set to {alias}"),
+			None => info!("alias of {id} cleared"),
+		},
+		Ok(Err(e)) => {
+			error!("{e}");
+			exit(1);
+		}
+		Err(e) => {
+			error!("failed to save peer info: {e}");
+			exit(1);
+		}
+	}
+}
+
+/// Blocks or unblocks a peer by its ID or alias, or anyone connecting from an IP address.
+async fn block(args: &Args, block_args: &BlockArgs, block: bool) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let peer = &block_args.peer;
+	let changed = if let Ok(ip) = peer.parse::<IpAddr>() {
+		let changed = peer_info.load_and_modify(|peer_info| match block {
+			true => peer_info.blocked_addrs.insert(ip),
+			false => peer_info.blocked_addrs.remove(&ip),
+		});
+		changed.await.map(|changed| (ip.to_string(), changed))
+	} else {
+		// A full ID may belong to a peer we don't know yet.
+		let id = peer
+			.parse::<Uuid>()
+			.or_else(|_| find_peer(peer, &peer_info.peers, peer_info.peers.keys()));
+		let id = id.unwrap_or_else(|e| {
+			error!("{e}");
+			exit(1);
+		});
+		let changed = peer_info.load_and_modify(|peer_info| match block {
+			true => peer_info.blocked.insert(id),
+			false => peer_info.blocked.remove(&id),
+		});
+		changed.await.map(|changed| (id.to_string(), changed))
+	};
+	match changed {
+		Ok((peer, true)) if block => info!("blocked {peer}"),
+		Ok((peer, true)) => info!("unblocked {peer}"),
+		Ok((peer, false)) if block => info!("{peer} is already blocked"),
+		Ok((peer, false)) => info!("{peer} isn't blocked"),
+		Err(e) => {
+			error!("failed to save peer info: {e}");
+			exit(1);
+		}
+	}
 }
 
 async fn chat(args: &Args) {
-	let conf = Conf::load(&args.conf_path).unwrap_or_else(|e| {
+	let conf = load_conf(args).unwrap_or_else(|e| {
+		error!("failed to load config: {e}");
+		exit(1);
+	});
+	let mut peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	prune_stale(&mut peer_info, &conf).await;
+	if conf.peer.nickname.is_some() {
+		peer_info.nickname.clone_from(&conf.peer.nickname);
+	}
+	override_addrs(args, &mut peer_info).await;
+	let private_key = load_private_key(&conf).await;
+	let tls = load_tls(&conf).await;
+	let colored = use_color(args);
+	match rpc::chat::start(&peer_info, &conf, &private_key, tls, colored).await {
+		Ok(()) => {}
+		Err(e) if e.kind == ChatErrorKind::AddrInUse => {
+			error!("{e}, a daemon or another chat may already be receiving messages");
+			exit(1);
+		}
+		Err(e) => {
+			error!("{e}");
+			exit(1);
+		}
+	}
+}
+
+async fn send(args: &Args, send_args: &SendArgs) {
+	let conf = load_conf(args).unwrap_or_else(|e| {
 		error!("failed to load config: {e}");
 		exit(1);
 	});
-	let peer_info = PeerInfo::load(&conf.path.peer_info).await.unwrap_or_else(|e| {
-		error!("failed to load peer info: {e}");
+	let peer_info = PeerInfo::load(&conf.path.peer_info, load_storage_key(&conf).await)
+		.await
+		.unwrap_or_else(|e| {
+			error!("failed to load peer info: {e}");
+			exit(1);
+		});
+	let to =
+		find_peer(&send_args.peer, &peer_info.peers, peer_info.peers.keys()).unwrap_or_else(|e| {
+			error!("{e}");
+			exit(1);
+		});
+	let text = match send_args.text.as_str() {
+		"-" => {
+			let mut text = String::new();
+			if let Err(e) = tokio::io::stdin().read_to_string(&mut text).await {
+				error!("failed to read message from stdin: {e}");
+				exit(1);
+			}
+			text
+		}
+		text => text.to_owned(),
+	};
+	let text = text.trim();
+	if text.is_empty() {
+		error!("message is empty");
+		exit(1);
+	}
+	let node = load_node(conf, peer_info).await;
+	let msg = node.send_message(to, text, send_args.wait).await.unwrap_or_else(|e| {
+		error!("{e}");
 		exit(1);
 	});
-	rpc::chat::start(&peer_info).await;
+	info!("sent message {} to {to}", msg.message_id);
 }
 
-fn print_peers(peers: HashMap<Uuid, Peer>) {
-	println!("{:<38} {:<23} {:<20} {:<10}", "ID", "Address", "Last Seen", "Status");
-	println!("{}", "-".repeat(100));
+/// Reads our private key, exiting if there is none.
+async fn load_private_key(conf: &Conf) -> KeyPair {
+	let pem = read(&conf.path.private_key).await.unwrap_or_else(|e| {
+		error!("failed to load private key: {e}");
+		exit(1);
+	});
+	KeyPair::from_pem(&pem).unwrap_or_else(|e| {
+		error!("failed to parse private key: {e}");
+		exit(1);
+	})
+}
+
+/// Loads our key pair and certificate for TLS if it is enabled, exiting if they are unusable.
+async fn load_tls(conf: &Conf) -> Option<Tls> {
+	if !conf.net.tls {
+		return None;
+	}
+	let key_pair = load_private_key(conf).await;
+	let cert = read(&conf.path.certificate).await.unwrap_or_else(|e| {
+		error!("failed to load certificate, run init to create one: {e}");
+		exit(1);
+	});
+	match Tls::new(&key_pair, &cert) {
+		Ok(tls) => Some(tls),
+		Err(e) if e.kind == TlsErrorKind::KeyMismatch => {
+			error!(
+				"certificate at {} wasn't made for our key, run init to create a new one",
+				conf.path.certificate.display()
+			);
+			exit(1);
+		}
+		Err(e) => {
+			error!("failed to set up TLS: {e}");
+			exit(1);
+		}
+	}
+}
 
-	for (id, peer) in peers {
-		let time_ago = peer
-			.last_seen
-			.map(|l| format_duration_ago(l.elapsed().unwrap()))
-			.unwrap_or("never".to_owned());
-		println!("{:<38} {:<23} {:<20} {:<10}", id.to_string(), peer.addr, time_ago, peer.status);
+/// Derives the key peer info is encrypted with at rest, if encryption is enabled.
+async fn load_storage_key(conf: &Conf) -> Option<Session> {
+	if !conf.crypto.encrypt_peer_info {
+		return None;
 	}
+	let key_pair = load_private_key(conf).await;
+	let storage_key = PeerInfo::storage_key(&key_pair).unwrap_or_else(|e| {
+		error!("failed to derive peer info key: {e}");
+		exit(1);
+	});
+	Some(storage_key)
+}
+
+/// Loads our keys and TLS setup into a node for the peer info, exiting if they are unusable.
+async fn load_node(conf: Conf, peer_info: PeerInfo) -> Node {
+	let public_key = read_to_string(&conf.path.public_key).await.unwrap_or_else(|e| {
+		error!("failed to load public key: {e}");
+		exit(1);
+	});
+	let key_pair = load_private_key(&conf).await;
+	let tls = load_tls(&conf).await;
+	Node::new(conf, peer_info, public_key, key_pair, tls)
 }
 
-fn format_duration_ago(duration: Duration) -> String {
-	let secs = duration.as_secs();
-	match secs {
-		0..=59 => format!("{secs} second(s) ago"),
-		60..=3599 => format!("{} minute(s) ago", secs / 60),
-		3600..=86399 => format!("{} hour(s) ago", secs / 3600),
-		_ => format!("{} day(s) ago", secs / 86400),
+/// Prints whether connecting to each address succeeded, the errors having been logged already.
+fn print_connect_results(results: &[(&str, bool)]) {
+	let width = results.iter().map(|(addr, _)| addr.len()).max().unwrap_or(0).max(23);
+	println!("{:<width$} Result", "Address");
+	println!("{}", "-".repeat(width + 10));
+	for &(addr, connected) in results {
+		println!("{addr:<width$} {}", if connected { "connected" } else { "failed" });
 	}
 }
+
+/// Formats a round-trip time in milliseconds, keeping a decimal for fast local networks.
+fn format_latency(latency: Duration) -> String {
+	format!("{:.1}ms", latency.as_secs_f64() * 1000.0)
+}
+
+/// Returns whether output may be colored, which `--no-color` and a non-empty `NO_COLOR` variable
+/// turn off.
+fn use_color(args: &Args) -> bool {
+	!args.no_color && env::var_os("NO_COLOR").is_none_or(|var| var.is_empty())
+}
@@ -0,0 +1,274 @@
+use crate::conf::Conf;
+use crate::crypto::{KeyPair, Uuid};
+use crate::peer::info::PeerInfo;
+use crate::peer::Peer;
+use crate::rpc::request::{Message, Pong};
+use crate::rpc::tls::Tls;
+use crate::rpc::{chat, client, daemon, server};
+use log::warn;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Peer on the network, for driving the protocol from code rather than the command line.
+///
+/// Peer info is shared by every clone and saved to disk by each operation that changes it, so a
+/// node can be used alongside running commands.
+///
+/// # Examples
+///
+/// ```rust
+/// let node = Node::new(conf, peer_info, public_key, key_pair, tls);
+/// let server = tokio::spawn({
+///     let node = node.clone();
+///     async move { node.start_server().await }
+/// });
+/// node.connect("example.com:7000", Options::default()).await?;
+/// for peer in node.peers().await {
+///     node.send_message(peer.id, "hello", false).await?;
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Node {
+	conf: Conf,
+	peer_info: Arc<Mutex<PeerInfo>>,
+	public_key: String,
+	key_pair: KeyPair,
+	tls: Option<Tls>,
+}
+
+impl Node {
+	/// Creates a node for our peer info, taking the nickname from config if it sets one.
+	pub fn new(
+		conf: Conf,
+		mut peer_info: PeerInfo,
+		public_key: String,
+		key_pair: KeyPair,
+		tls: Option<Tls>,
+	) -> Self {
+		if conf.peer.nickname.is_some() {
+			peer_info.nickname.clone_from(&conf.peer.nickname);
+		}
+		Self { conf, peer_info: Arc::new(Mutex::new(peer_info)), public_key, key_pair, tls }
+	}
+
+	/// Accepts connections from peers until Ctrl-C is pressed, see [`server::listen`].
+	///
+	/// The server works on its own copy of peer info, which is picked up again once it stops.
+	///
+	/// # Errors
+	///
+	/// See [`server::listen`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// node.start_server().await?;
+	/// ```
+	pub async fn start_server(&self) -> Result<(), server::Error> {
+		let peer_info = self.peer_info.lock().await.clone();
+		let public_key = self.public_key.clone();
+		server::listen(&peer_info, self.conf.net, public_key, &self.key_pair, self.tls.clone())
+			.await?;
+		self.reload().await;
+		Ok(())
+	}
+
+	/// Runs the server and chat listeners with keepalive pings until Ctrl-C is pressed or SIGTERM
+	/// is received, see [`daemon::run`].
+	///
+	/// Like [`Node::start_server`], the daemon works on its own copy of peer info.
+	///
+	/// # Errors
+	///
+	/// See [`daemon::run`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// node.run_daemon().await?;
+	/// ```
+	pub async fn run_daemon(&self) -> Result<(), daemon::Error> {
+		let peer_info = self.peer_info.lock().await.clone();
+		let public_key = self.public_key.clone();
+		daemon::run(&peer_info, &self.conf, public_key, &self.key_pair, self.tls.clone()).await?;
+		self.reload().await;
+		Ok(())
+	}
+
+	/// Connects to the peer at `host:port`, exchanging pings and known peers, see
+	/// [`client::connect`].
+	///
+	/// Several connections may be made at once, each saving what it learns as it goes.
+	///
+	/// # Errors
+	///
+	/// See [`client::connect`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// node.connect("192.0.2.1:7000", Options { retries: 3, ..Options::default() }).await?;
+	/// ```
+	pub async fn connect(&self, host: &str, options: client::Options) -> Result<(), client::Error> {
+		let mut peer_info = self.peer_info.lock().await.clone();
+		let connected = client::connect(
+			host,
+			&mut peer_info,
+			&self.conf.net,
+			self.public_key.clone(),
+			&self.key_pair,
+			options,
+			self.tls.as_ref(),
+		)
+		.await;
+		self.reload().await;
+		connected
+	}
+
+	/// Pings the peer at `host:port` without recording anything, returning the address that
+	/// answered, its pong and the round-trip time, see [`client::probe`].
+	///
+	/// # Errors
+	///
+	/// See [`client::probe`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// let (addr, pong, latency) = node.ping("example.com:7000", false).await?;
+	/// ```
+	pub async fn ping(
+		&self,
+		host: &str,
+		prefer_ipv6: bool,
+	) -> Result<(SocketAddr, Pong, Duration), client::Error> {
+		let peer_info = self.peer_info.lock().await.clone();
+		client::probe(
+			host,
+			prefer_ipv6,
+			&peer_info,
+			&self.conf.net,
+			&self.public_key,
+			&self.key_pair,
+			self.tls.as_ref(),
+		)
+		.await
+	}
+
+	/// Records whether the known peer answered pings sent to `host:port`, see
+	/// [`client::record_ping`].
+	///
+	/// # Errors
+	///
+	/// See [`client::record_ping`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// let answer = node.ping(&host, false).await.ok();
+	/// let answer = answer.as_ref().map(|(addr, pong, latency)| (*addr, pong, *latency));
+	/// node.record_ping(peer_id, &host, answer).await?;
+	/// ```
+	pub async fn record_ping(
+		&self,
+		id: Uuid,
+		host: &str,
+		answer: Option<(SocketAddr, &Pong, Duration)>,
+	) -> Result<(), client::Error> {
+		let mut peer_info = self.peer_info.lock().await.clone();
+		let recorded = client::record_ping(id, host, answer, &mut peer_info).await;
+		self.reload().await;
+		recorded
+	}
+
+	/// Pings every known peer that isn't blocked, at most `jobs` at a time, returning the number
+	/// found online and unreachable, see [`client::refresh`].
+	///
+	/// # Errors
+	///
+	/// See [`client::refresh`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// let (online, unreachable) = node.refresh(16).await?;
+	/// ```
+	pub async fn refresh(&self, jobs: usize) -> Result<(usize, usize), client::Error> {
+		let mut peer_info = self.peer_info.lock().await.clone();
+		let refreshed = client::refresh(
+			&mut peer_info,
+			&self.conf.net,
+			&self.public_key,
+			&self.key_pair,
+			self.tls.as_ref(),
+			jobs,
+		)
+		.await;
+		self.reload().await;
+		refreshed
+	}
+
+	/// Tells the peer at the address that we are going away, pinging it first so it knows the
+	/// request is ours, see [`client::disconnect`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// node.disconnect(peer.addr).await;
+	/// ```
+	pub async fn disconnect(&self, addr: SocketAddr) {
+		let peer_info = self.peer_info.lock().await.clone();
+		let (public_key, tls) = (&self.public_key, self.tls.as_ref());
+		client::disconnect(addr, &peer_info, &self.conf.net, public_key, &self.key_pair, tls).await
+	}
+
+	/// Sends a direct message to the peer's chat without starting one ourselves, returning the
+	/// message as sent, see [`chat::send_once`].
+	///
+	/// With `wait`, the peer must acknowledge the message before this returns.
+	///
+	/// # Errors
+	///
+	/// See [`chat::send_once`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// let msg = node.send_message(peer_id, "hello", true).await?;
+	/// ```
+	pub async fn send_message(
+		&self,
+		to: Uuid,
+		text: &str,
+		wait: bool,
+	) -> Result<Message, chat::Error> {
+		let peer_info = self.peer_info.lock().await.clone();
+		let (conf, tls) = (&self.conf, self.tls.as_ref());
+		chat::send_once(to, text, wait, &peer_info, conf, &self.key_pair, tls).await
+	}
+
+	/// Returns the known peers as last saved by anything using the same peer info.
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// let online = node.peers().await.into_iter().filter(|p| p.status == Status::Online);
+	/// ```
+	pub async fn peers(&self) -> Vec<Peer> {
+		self.reload().await;
+		self.peer_info.lock().await.peers.values().cloned().collect()
+	}
+
+	/// Picks up changes saved since peer info was loaded, keeping what we have if that fails.
+	async fn reload(&self) {
+		let mut peer_info = self.peer_info.lock().await;
+		if let Err(e) = peer_info.reload().await {
+			warn!("failed to reload peer info: {e}");
+		}
+		if self.conf.peer.nickname.is_some() {
+			peer_info.nickname.clone_from(&self.conf.peer.nickname);
+		}
+	}
+}
@@ -0,0 +1,371 @@
+use crate::args::{ListArgs, Sort};
+use crate::crypto::Uuid;
+use crate::peer::info::PeerInfo;
+use crate::peer::{display_name, Peer, Status};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde::Serialize;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Known peer as written by [`render_json`], whose fields are only ever added to.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize)]
+struct PeerJson<'a> {
+	id: Uuid,
+	addr: SocketAddr,
+	chat_addr: SocketAddr,
+	status: Status,
+	/// RFC 3339 timestamp in UTC.
+	last_seen: Option<String>,
+	alias: Option<&'a str>,
+	nickname: Option<&'a str>,
+	host: Option<&'a str>,
+	latency_ms: Option<u128>,
+	failed_attempts: u32,
+	blocked: bool,
+	fingerprint: Option<String>,
+}
+
+/// Our own peer as shown by the status command.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Serialize)]
+pub struct SelfStatus<'a> {
+	pub id: Uuid,
+	pub nickname: Option<&'a str>,
+	pub addr: SocketAddr,
+	pub chat_addr: SocketAddr,
+	/// Whether something accepts connections at the address.
+	pub listening: bool,
+	/// Whether something accepts connections at the chat address.
+	pub chat_listening: bool,
+	pub fingerprint: Option<String>,
+	pub peers: PeerCounts,
+}
+
+/// Numbers of known peers by status, with blocked peers counted apart.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default, Serialize)]
+pub struct PeerCounts {
+	pub total: usize,
+	pub online: usize,
+	pub offline: usize,
+	pub unreachable: usize,
+	pub unknown: usize,
+	pub blocked: usize,
+}
+
+impl PeerCounts {
+	pub fn new(peer_info: &PeerInfo) -> Self {
+		let mut counts = Self { total: peer_info.peers.len(), ..Self::default() };
+		for peer in peer_info.peers.values() {
+			if peer_info.is_blocked(peer.id, peer.addr.ip()) {
+				counts.blocked += 1;
+				continue;
+			}
+			match peer.status {
+				Status::Online => counts.online += 1,
+				Status::Offline => counts.offline += 1,
+				Status::Unreachable => counts.unreachable += 1,
+				Status::Unknown => counts.unknown += 1,
+			}
+		}
+		counts
+	}
+}
+
+/// Renders our status as lines of labeled values for the terminal.
+pub fn render_status(status: &SelfStatus) -> String {
+	let listening = |listening| if listening { "listening" } else { "not listening" };
+	let peers = status.peers;
+	let mut out = String::new();
+	let _ = writeln!(out, "ID:          {}", status.id);
+	let _ = writeln!(out, "Name:        {}", display_name(status.id, status.nickname));
+	let _ = writeln!(out, "Address:     {} ({})", status.addr, listening(status.listening));
+	let _ =
+		writeln!(out, "Chat:        {} ({})", status.chat_addr, listening(status.chat_listening));
+	let _ = writeln!(
+		out,
+		"Fingerprint: {}",
+		status.fingerprint.as_ref().map_or("unknown".to_owned(), |f| format!("SHA256:{f}"))
+	);
+	let _ = writeln!(
+		out,
+		"Peers:       {} known, {} online, {} offline, {} unreachable, {} unknown, {} blocked",
+		peers.total, peers.online, peers.offline, peers.unreachable, peers.unknown, peers.blocked
+	);
+	out
+}
+
+/// Renders our status as a JSON object for scripts.
+pub fn render_status_json(status: &SelfStatus) -> String {
+	// Only plain data goes in, so serialization can't fail.
+	serde_json::to_string_pretty(status).unwrap_or_else(|_| "{}".to_owned())
+}
+
+/// Returns the known peers that pass the filters of the list command, in the order it asks for
+/// and at most as many as its limit.
+pub fn select_peers<'a>(peer_info: &'a PeerInfo, list_args: &ListArgs) -> Vec<&'a Peer> {
+	let mut peers: Vec<_> = peer_info
+		.peers
+		.values()
+		.filter(|peer| !list_args.online || peer.status == Status::Online)
+		.filter(|peer| !list_args.offline || peer.status != Status::Online)
+		.collect();
+	match list_args.sort {
+		// Never seen sorts before any time, so it ends up last.
+		Sort::LastSeen => peers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.id.cmp(&b.id))),
+		Sort::Id => peers.sort_by_key(|peer| peer.id),
+		Sort::Addr => peers.sort_by_key(|peer| (peer.addr, peer.id)),
+	}
+	if let Some(limit) = list_args.limit {
+		peers.truncate(limit);
+	}
+	peers
+}
+
+/// Renders the peers in order as a table for the terminal, followed by blocked peers that aren't
+/// known.
+///
+/// With `keys`, a column with the fingerprint of each peer's public key is added. With
+/// `absolute`, last seen times are RFC 3339 timestamps rather than how long ago they were.
+pub fn render_table(
+	peer_info: &PeerInfo,
+	peers: &[&Peer],
+	keys: bool,
+	absolute: bool,
+	colored: bool,
+) -> String {
+	let mut out = String::new();
+	let seen_width = if absolute { 25 } else { 20 };
+	let _ = write!(
+		out,
+		"{:<38} {:<17} {:<17} {:<23} {:<seen_width$} {:<12} {:<10} {:<8}",
+		"ID", "Alias", "Name", "Address", "Last Seen", "Status", "Latency", "Failures"
+	);
+	if keys {
+		let _ = write!(out, " {:<23}", "Fingerprint");
+	}
+	let _ = writeln!(out);
+	let _ = writeln!(out, "{}", "-".repeat(138 + seen_width + if keys { 24 } else { 0 }));
+
+	for peer in peers {
+		let time_ago = match peer.last_seen {
+			Some(last_seen) if absolute => {
+				DateTime::<Local>::from(last_seen).to_rfc3339_opts(SecondsFormat::Secs, false)
+			}
+			// The clock moved backwards since, or the peer info came from a skewed machine.
+			Some(last_seen) => {
+				last_seen.elapsed().map_or("just now".to_owned(), format_duration_ago)
+			}
+			None => "never".to_owned(),
+		};
+		let blocked = peer_info.is_blocked(peer.id, peer.addr.ip());
+		let mut status =
+			format!("{:<12}", if blocked { "blocked".to_owned() } else { peer.status.to_string() });
+		if colored {
+			let color = if blocked { "\x1b[35m" } else { status_color(peer.status) };
+			status = format!("{color}{status}\x1b[0m");
+		}
+		let latency =
+			peer.latency.map_or("-".to_owned(), |latency| format!("{}ms", latency.as_millis()));
+		let _ = write!(
+			out,
+			"{:<38} {:<17} {:<17} {:<23} {:<seen_width$} {} {:<10} {:<8}",
+			peer.id.to_string(),
+			peer.alias.as_deref().unwrap_or("-"),
+			display_name(peer.id, peer.nickname.as_deref()),
+			peer.addr,
+			time_ago,
+			status,
+			latency,
+			peer.failed_attempts
+		);
+		if keys {
+			let _ = write!(out, " {:<23}", peer.fingerprint().unwrap_or("unknown".to_owned()));
+		}
+		let _ = writeln!(out);
+	}
+
+	let unknown = peer_info.blocked.iter().filter(|id| !peer_info.peers.contains_key(id));
+	let mut blocked: Vec<_> = unknown.map(Uuid::to_string).collect();
+	blocked.extend(peer_info.blocked_addrs.iter().map(IpAddr::to_string));
+	if !blocked.is_empty() {
+		let _ = writeln!(out);
+		let _ = writeln!(out, "Blocked: {}", blocked.join(", "));
+	}
+	out
+}
+
+/// Renders the peers in order as a JSON array for scripts, which is `[]` if there are none.
+pub fn render_json(peer_info: &PeerInfo, peers: &[&Peer]) -> String {
+	let peers: Vec<_> = peers
+		.iter()
+		.map(|peer| PeerJson {
+			id: peer.id,
+			addr: peer.addr,
+			chat_addr: peer.chat_addr,
+			status: peer.status,
+			last_seen: peer.last_seen.map(|last_seen| {
+				DateTime::<Utc>::from(last_seen).to_rfc3339_opts(SecondsFormat::Secs, true)
+			}),
+			alias: peer.alias.as_deref(),
+			nickname: peer.nickname.as_deref(),
+			host: peer.host.as_deref(),
+			latency_ms: peer.latency.map(|latency| latency.as_millis()),
+			failed_attempts: peer.failed_attempts,
+			blocked: peer_info.is_blocked(peer.id, peer.addr.ip()),
+			fingerprint: peer.fingerprint(),
+		})
+		.collect();
+	// Only plain data goes in, so serialization can't fail.
+	serde_json::to_string_pretty(&peers).unwrap_or_else(|_| "[]".to_owned())
+}
+
+/// Returns the escape sequence the status is colored with in a terminal.
+fn status_color(status: Status) -> &'static str {
+	match status {
+		Status::Online => "\x1b[32m",
+		Status::Offline => "\x1b[2;31m",
+		Status::Unreachable => "\x1b[31m",
+		Status::Unknown => "\x1b[33m",
+	}
+}
+
+pub fn format_duration_ago(duration: Duration) -> String {
+	let secs = duration.as_secs();
+	match secs {
+		0..=59 => format!("{secs} second(s) ago"),
+		60..=3599 => format!("{} minute(s) ago", secs / 60),
+		3600..=86399 => format!("{} hour(s) ago", secs / 3600),
+		_ => format!("{} day(s) ago", secs / 86400),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::args::Format;
+	use crate::crypto::UuidV4;
+	use std::time::SystemTime;
+
+	async fn peer_info() -> PeerInfo {
+		let (addr, chat_addr) = (([127, 0, 0, 1], 7040), ([127, 0, 0, 1], 7041));
+		PeerInfo::new(UuidV4::new(), addr, chat_addr, "peer_info.json").await
+	}
+
+	#[test]
+	fn durations_are_rounded_down_to_largest_unit() {
+		let ago = |secs| format_duration_ago(Duration::from_secs(secs));
+		assert_eq!(ago(0), "0 second(s) ago");
+		assert_eq!(ago(59), "59 second(s) ago");
+		assert_eq!(ago(60), "1 minute(s) ago");
+		assert_eq!(ago(3599), "59 minute(s) ago");
+		assert_eq!(ago(3600), "1 hour(s) ago");
+		assert_eq!(ago(86399), "23 hour(s) ago");
+		assert_eq!(ago(86400), "1 day(s) ago");
+		assert_eq!(ago(10 * 86400 + 1), "10 day(s) ago");
+	}
+
+	#[tokio::test]
+	async fn last_seen_in_future_is_just_now() {
+		let peer_info = peer_info().await;
+		let mut peer = Peer::new(UuidV4::new(), ([192, 0, 2, 1], 7040), ([192, 0, 2, 1], 7041));
+		peer.last_seen = Some(SystemTime::now() + Duration::from_secs(3600));
+		let table = render_table(&peer_info, &[&peer], false, false, false);
+		assert!(table.contains("just now"), "{table}");
+		let table = render_table(&peer_info, &[&peer], false, true, false);
+		assert!(!table.contains("just now"), "{table}");
+	}
+
+	#[tokio::test]
+	async fn json_output_is_stable() {
+		let mut peer_info = peer_info().await;
+		let id: Uuid = "0f8fad5b-d9cb-469f-a165-70867728950e".parse().unwrap();
+		let mut seen = Peer::new(id, ([192, 0, 2, 1], 7040), ([192, 0, 2, 1], 7041));
+		seen.status = Status::Online;
+		seen.last_seen = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+		seen.alias = Some("alice".to_owned());
+		seen.latency = Some(Duration::from_micros(12_500));
+		let id: Uuid = "01890a5d-ac96-774b-bcce-b302099a8057".parse().unwrap();
+		let never_seen = Peer::new(id, ([192, 0, 2, 2], 7040), ([192, 0, 2, 2], 7041));
+		peer_info.blocked.insert(never_seen.id);
+
+		let json = render_json(&peer_info, &[&seen, &never_seen]);
+		let expected = r#"[
+  {
+    "id": "0f8fad5b-d9cb-469f-a165-70867728950e",
+    "addr": "192.0.2.1:7040",
+    "chat_addr": "192.0.2.1:7041",
+    "status": "online",
+    "last_seen": "2023-11-14T22:13:20Z",
+    "alias": "alice",
+    "nickname": null,
+    "host": null,
+    "latency_ms": 12,
+    "failed_attempts": 0,
+    "blocked": false,
+    "fingerprint": null
+  },
+  {
+    "id": "01890a5d-ac96-774b-bcce-b302099a8057",
+    "addr": "192.0.2.2:7040",
+    "chat_addr": "192.0.2.2:7041",
+    "status": "unknown",
+    "last_seen": null,
+    "alias": null,
+    "nickname": null,
+    "host": null,
+    "latency_ms": null,
+    "failed_attempts": 0,
+    "blocked": true,
+    "fingerprint": null
+  }
+]"#;
+		assert_eq!(json, expected);
+		assert_eq!(render_json(&peer_info, &[]), "[]");
+	}
+
+	fn list_args(online: bool, offline: bool, sort: Sort, limit: Option<usize>) -> ListArgs {
+		ListArgs {
+			keys: false,
+			absolute: false,
+			format: Format::Json,
+			online,
+			offline,
+			sort,
+			limit,
+		}
+	}
+
+	#[tokio::test]
+	async fn filters_sorting_and_limit_compose() {
+		let mut peer_info = peer_info().await;
+		let now = SystemTime::now();
+		let ids: Vec<Uuid> = (1..=4)
+			.map(|i| format!("0000000{i}-d9cb-469f-a165-70867728950e").parse().unwrap())
+			.collect();
+		// Online and seen a minute ago, offline and seen now, never seen, online and seen now.
+		let seen = [Some(now - Duration::from_secs(60)), Some(now), None, Some(now)];
+		let status = [Status::Online, Status::Offline, Status::Unknown, Status::Online];
+		for (i, id) in ids.iter().enumerate() {
+			let port = 7040 + i as u16;
+			let mut peer = Peer::new(*id, ([192, 0, 2, 1], port), ([192, 0, 2, 1], port));
+			(peer.last_seen, peer.status) = (seen[i], status[i]);
+			peer_info.peers.insert(*id, peer);
+		}
+		let select = |args| -> Vec<Uuid> {
+			select_peers(&peer_info, &args).iter().map(|peer| peer.id).collect()
+		};
+
+		let [a, b, c, d] = ids[..] else { unreachable!() };
+		assert_eq!(select(list_args(false, false, Sort::LastSeen, None)), [b, d, a, c]);
+		assert_eq!(select(list_args(true, false, Sort::LastSeen, None)), [d, a]);
+		assert_eq!(select(list_args(false, true, Sort::LastSeen, None)), [b, c]);
+		assert_eq!(select(list_args(false, true, Sort::Id, Some(1))), [b]);
+		assert_eq!(select(list_args(true, false, Sort::Addr, Some(5))), [a, d]);
+		assert_eq!(select(list_args(false, false, Sort::Id, Some(0))), []);
+		let json = render_json(
+			&peer_info,
+			&select_peers(&peer_info, &list_args(true, false, Sort::Id, Some(1))),
+		);
+		assert!(json.contains(&a.to_string()) && !json.contains(&d.to_string()));
+	}
+}
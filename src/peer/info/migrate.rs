@@ -0,0 +1,146 @@
+use crate::peer::info::{Error, ErrorKind};
+use serde_json::{Map, Value};
+
+/// Version of the peer info schema written by this build.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Upgrades peer info read from a file to [`SCHEMA_VERSION`] one version at a time, returning
+/// `true` if it was upgraded.
+///
+/// Files without a `schema_version` are version 0.
+///
+/// # Errors
+///
+/// If the file was written by a newer version, error kind is [`ErrorKind::UnsupportedVersion`].
+/// If the file isn't an object or its version isn't a number, error kind is
+/// [`ErrorKind::InvalidData`].
+pub fn migrate(peer_info: &mut Value) -> Result<bool, Error> {
+	let Value::Object(peer_info) = peer_info else {
+		return Err(Error::new(ErrorKind::InvalidData, "file is malformed: not an object"));
+	};
+	let version = match peer_info.get("schema_version") {
+		Some(version) => version.as_u64().and_then(|v| u32::try_from(v).ok()).ok_or_else(|| {
+			Error::new(ErrorKind::InvalidData, "file is malformed: invalid schema version")
+		})?,
+		None => 0,
+	};
+	if version > SCHEMA_VERSION {
+		return Err(Error::new(
+			ErrorKind::UnsupportedVersion,
+			format!("file has schema version {version}, this build only supports up to {SCHEMA_VERSION}"),
+		));
+	}
+
+	for from in version..SCHEMA_VERSION {
+		match from {
+			0 => v0_to_v1(peer_info),
+			_ => unreachable!("no migration from schema version {from}"),
+		}
+		peer_info.insert("schema_version".to_owned(), (from + 1).into());
+	}
+	Ok(version < SCHEMA_VERSION)
+}
+
+/// Fills in fields added to peer info and peers before versioning, so every version 1 file has
+/// them.
+fn v0_to_v1(peer_info: &mut Map<String, Value>) {
+	peer_info.entry("nickname").or_insert(Value::Null);
+	peer_info.entry("blocked").or_insert_with(|| Value::Array(Vec::new()));
+	peer_info.entry("blocked_addrs").or_insert_with(|| Value::Array(Vec::new()));
+
+	let Some(Value::Object(peers)) = peer_info.get_mut("peers") else { return };
+	for peer in peers.values_mut() {
+		let Value::Object(peer) = peer else { continue };
+		peer.entry("codec").or_insert_with(|| "json".into());
+		for field in ["nickname", "public_key", "alias", "added_at", "last_attempt"] {
+			peer.entry(field).or_insert(Value::Null);
+		}
+		peer.entry("failed_attempts").or_insert(0.into());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::crypto::Uuid;
+	use crate::peer::info::{sibling, PeerInfo};
+	use crate::peer::Status;
+	use crate::rpc::request::Codec;
+	use std::time::{Duration, UNIX_EPOCH};
+	use tempfile::TempDir;
+
+	/// Our ID and the IDs of an online and an offline peer, as builds before versioning printed
+	/// them, with each segment in little-endian order.
+	const ID: &str = "04030201-0605-0847-0a89-100f0e0d0c0b";
+	const ONLINE_ID: &str = "13121110-1514-1746-1998-1f1e1d1c1b1a";
+	const OFFLINE_ID: &str = "23222120-2524-2746-2999-2f2e2d2c2b2a";
+
+	/// Peer info saved at the path by builds before versioning.
+	fn v0(path: &str) -> String {
+		let path = serde_json::to_string(path).unwrap();
+		format!(
+			r#"{{"id":"{ID}","addr":"127.0.0.1:7040","chat_addr":"127.0.0.1:7041","peers":{{"{ONLINE_ID}":{{"id":"{ONLINE_ID}","addr":"192.0.2.1:7040","chat_addr":"192.0.2.1:7041","status":"online","last_seen":{{"secs_since_epoch":1700000000,"nanos_since_epoch":0}}}},"{OFFLINE_ID}":{{"id":"{OFFLINE_ID}","addr":"192.0.2.2:7040","chat_addr":"192.0.2.2:7041","status":"offline","last_seen":null}}}},"path":{path}}}"#
+		)
+	}
+
+	fn uuid(s: &str) -> Uuid {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn v0_is_upgraded_to_current_schema() {
+		let mut value: Value = serde_json::from_str(&v0("peer_info.json")).unwrap();
+		assert!(migrate(&mut value).unwrap());
+		assert_eq!(value["schema_version"], SCHEMA_VERSION);
+
+		let peer_info: PeerInfo = serde_json::from_value(value).unwrap();
+		assert_eq!(peer_info.id, uuid("01020304-0506-4708-890a-0b0c0d0e0f10"));
+		assert!(peer_info.nickname.is_none() && peer_info.blocked.is_empty());
+		let online = &peer_info.peers[&uuid("10111213-1415-4617-9819-1a1b1c1d1e1f")];
+		assert_eq!(online.status, Status::Online);
+		assert_eq!(online.last_seen, Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+		assert_eq!((online.codec, online.public_key.as_ref()), (Codec::Json, None));
+		let offline = &peer_info.peers[&uuid("20212223-2425-4627-9929-2a2b2c2d2e2f")];
+		assert_eq!((offline.status, offline.last_seen), (Status::Offline, None));
+	}
+
+	#[test]
+	fn current_schema_is_left_alone() {
+		let mut value: Value = serde_json::from_str(&v0("peer_info.json")).unwrap();
+		migrate(&mut value).unwrap();
+		let upgraded = value.clone();
+		assert!(!migrate(&mut value).unwrap());
+		assert_eq!(value, upgraded);
+	}
+
+	#[test]
+	fn newer_or_invalid_schema_is_rejected() {
+		let mut value: Value = serde_json::from_str(&v0("peer_info.json")).unwrap();
+		value["schema_version"] = (SCHEMA_VERSION + 1).into();
+		assert_eq!(migrate(&mut value).unwrap_err().kind, ErrorKind::UnsupportedVersion);
+
+		value["schema_version"] = "1".into();
+		assert_eq!(migrate(&mut value).unwrap_err().kind, ErrorKind::InvalidData);
+		assert_eq!(
+			migrate(&mut Value::Array(Vec::new())).unwrap_err().kind,
+			ErrorKind::InvalidData
+		);
+	}
+
+	#[tokio::test]
+	async fn loading_v0_saves_upgrade_and_backs_up_original() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("peer_info.json");
+		let original = v0(path.to_str().unwrap());
+		std::fs::write(&path, &original).unwrap();
+
+		let peer_info = PeerInfo::load(&path, None).await.unwrap();
+		assert_eq!(std::fs::read_to_string(sibling(&path, "bak")).unwrap(), original);
+		let saved = std::fs::read_to_string(&path).unwrap();
+		let value: Value = serde_json::from_str(&saved).unwrap();
+		assert_eq!(value["schema_version"], SCHEMA_VERSION);
+		// IDs are written in network order from then on.
+		assert!(saved.contains("01020304-0506-4708-890a-0b0c0d0e0f10") && !saved.contains(ID));
+		assert_eq!(PeerInfo::load(&path, None).await.unwrap(), peer_info);
+	}
+}
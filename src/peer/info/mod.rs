@@ -0,0 +1,692 @@
+use crate::crypto::{KeyPair, Sealed, Session, Uuid};
+use crate::peer::info::migrate::{migrate, SCHEMA_VERSION};
+use crate::peer::{sanitize_nickname, Peer, Status};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::TryLockError;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs::{read, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::time::{sleep, Instant};
+use tokio::{fs, io};
+
+pub mod migrate;
+
+/// Header that marks an encrypted peer info file.
+const MAGIC: &[u8] = b"P2PENC01";
+
+/// Context the storage key is derived under, so it differs from any other derived key.
+const STORAGE_CONTEXT: &[u8] = b"p2p peer info";
+
+/// Lengths of the AES-256-GCM nonce and tag following the header of an encrypted file.
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// How long to wait for another process to release the peer info lock.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to retry taking the peer info lock while another process holds it.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PeerInfo {
+	/// Version of the schema the file was written with, see [`migrate`].
+	schema_version: u32,
+	pub id: Uuid,
+	pub addr: SocketAddr,
+	pub chat_addr: SocketAddr,
+	/// Nickname we introduce ourselves with.
+	#[serde(default)]
+	pub nickname: Option<String>,
+	pub peers: HashMap<Uuid, Peer>,
+	/// IDs of peers whose pings and messages are refused, whether they are known or not.
+	#[serde(default)]
+	pub blocked: HashSet<Uuid>,
+	/// IP addresses whose pings and messages are refused, whatever ID they claim.
+	#[serde(default)]
+	pub blocked_addrs: HashSet<IpAddr>,
+	path: PathBuf,
+	/// Key the file is encrypted with on save, if encryption at rest is enabled.
+	#[serde(skip)]
+	storage_key: Option<Session>,
+}
+
+impl PeerInfo {
+	pub async fn new<I, A, P>(id: I, addr: A, chat_addr: A, path: P) -> Self
+	where
+		I: Into<Uuid>,
+		A: Into<SocketAddr>,
+		P: AsRef<Path>,
+	{
+		Self {
+			schema_version: SCHEMA_VERSION,
+			id: id.into(),
+			addr: addr.into(),
+			chat_addr: chat_addr.into(),
+			nickname: None,
+			peers: HashMap::new(),
+			blocked: HashSet::new(),
+			blocked_addrs: HashSet::new(),
+			path: path.as_ref().to_path_buf(),
+			storage_key: None,
+		}
+	}
+
+	/// Derives the key peer info is encrypted with at rest from our key pair.
+	///
+	/// # Errors
+	///
+	/// If the key can't be derived, error kind is [`ErrorKind::InvalidKey`].
+	pub fn storage_key(key_pair: &KeyPair) -> Result<Session, Error> {
+		Session::derive(key_pair, STORAGE_CONTEXT).map_err(|e| Error::new(ErrorKind::InvalidKey, e))
+	}
+
+	/// Loads peer info from a file, decrypting it with the storage key if it is encrypted.
+	///
+	/// The storage key is kept for saving, so a plaintext file gets encrypted on the next save
+	/// if a key is given. If the file is malformed, missing or doesn't decrypt with the key, the
+	/// `.bak` copy kept by [`Self::save`] is loaded instead, if there is one. A file written with an older schema is
+	/// upgraded by [`migrate`] and saved, keeping the original as the `.bak` copy.
+	///
+	/// # Errors
+	///
+	/// If the file doesn't exist, error kind is [`ErrorKind::FileNotFound`].
+	/// If there is an error while reading from the file, error kind is [`ErrorKind::ReadError`].
+	/// If the file is encrypted and there is no storage key or it is the wrong one, error kind is
+	/// [`ErrorKind::DecryptError`].
+	/// If a required field is missing from the file, error kind is [`ErrorKind::MissingField`].
+	/// If the file can't be parsed into peer info otherwise, error kind is
+	/// [`ErrorKind::InvalidData`]. Both keep the parse error with its line and column.
+	/// If the file was written with a newer schema, error kind is
+	/// [`ErrorKind::UnsupportedVersion`].
+	/// If another process holds the file locked for too long, error kind is
+	/// [`ErrorKind::LockError`].
+	pub async fn load<P>(path: P, storage_key: Option<Session>) -> Result<Self, Error>
+	where
+		P: AsRef<Path>,
+	{
+		let path = path.as_ref();
+		let lock = lock(path, false).await?;
+		let (peer_info, migrated) = Self::read(path, storage_key).await?;
+		drop(lock);
+		if migrated {
+			match peer_info.save().await {
+				Ok(()) => info!(
+					"upgraded peer info at {} to schema version {SCHEMA_VERSION}",
+					path.display()
+				),
+				Err(e) => warn!("failed to save upgraded peer info at {}: {e}", path.display()),
+			}
+		}
+		Ok(peer_info)
+	}
+
+	/// Re-reads peer info from its file under an exclusive lock, applies `modify` and saves the
+	/// result, so changes saved by other processes since `self` was loaded aren't overwritten.
+	///
+	/// `self` is replaced with the saved peer info. If the file was removed, `self` is modified
+	/// instead.
+	///
+	/// # Errors
+	///
+	/// See [`Self::load`] and [`Self::save`].
+	pub async fn load_and_modify<F, T>(&mut self, modify: F) -> Result<T, Error>
+	where
+		F: FnOnce(&mut Self) -> T,
+	{
+		let _lock = lock(&self.path, true).await?;
+		let mut peer_info = match Self::read(&self.path, self.storage_key.clone()).await {
+			Ok((peer_info, _)) => peer_info,
+			Err(e) if e.kind == ErrorKind::FileNotFound => self.clone(),
+			Err(e) => return Err(e),
+		};
+		let result = modify(&mut peer_info);
+		peer_info.write().await?;
+		*self = peer_info;
+		Ok(result)
+	}
+
+	/// Replaces `self` with peer info read from its file, picking up changes saved by other
+	/// processes. If the file was removed, `self` is kept.
+	///
+	/// # Errors
+	///
+	/// See [`Self::load`].
+	pub async fn reload(&mut self) -> Result<(), Error> {
+		let _lock = lock(&self.path, false).await?;
+		match Self::read(&self.path, self.storage_key.clone()).await {
+			Ok((peer_info, _)) => *self = peer_info,
+			Err(e) if e.kind == ErrorKind::FileNotFound => {}
+			Err(e) => return Err(e),
+		}
+		Ok(())
+	}
+
+	/// Reads peer info from the file or its backup, see [`Self::load`], returning `true` along
+	/// with it if it was upgraded from an older schema version.
+	async fn read(path: &Path, storage_key: Option<Session>) -> Result<(Self, bool), Error> {
+		let has_key = storage_key.is_some();
+		match Self::load_file(path, storage_key.clone()).await {
+			Err(e)
+				if matches!(
+					e.kind,
+					ErrorKind::InvalidData | ErrorKind::MissingField | ErrorKind::FileNotFound
+				) || (e.kind == ErrorKind::DecryptError && has_key) =>
+			{
+				let bak_path = sibling(path, "bak");
+				match Self::load_file(&bak_path, storage_key).await {
+					// The backup decrypts with the key, so the file was corrupt rather than
+					// encrypted with another key.
+					Ok(peer_info) if e.kind == ErrorKind::DecryptError => {
+						warn!("peer info at {} is corrupt, using backup", path.display());
+						Ok(peer_info)
+					}
+					Ok(peer_info) => {
+						warn!("peer info at {} is unusable ({e}), using backup", path.display());
+						Ok(peer_info)
+					}
+					Err(_) => Err(e),
+				}
+			}
+			result => result,
+		}
+	}
+
+	async fn load_file(path: &Path, storage_key: Option<Session>) -> Result<(Self, bool), Error> {
+		let data = read(path).await.map_err(|e| match e.kind() {
+			io::ErrorKind::NotFound => Error::new(ErrorKind::FileNotFound, "file not found"),
+			_ => Error::new(ErrorKind::ReadError, e),
+		})?;
+		let data = match data.strip_prefix(MAGIC) {
+			Some(encrypted) => decrypt(encrypted, storage_key.as_ref())?,
+			None => data,
+		};
+		let malformed = |e: serde_json::Error| {
+			let kind = if e.is_data() && e.to_string().starts_with("missing field") {
+				ErrorKind::MissingField
+			} else {
+				ErrorKind::InvalidData
+			};
+			Error::new(kind, e)
+		};
+		let mut value = serde_json::from_slice(&data).map_err(malformed)?;
+		let migrated = migrate(&mut value)?;
+		let mut peer_info = serde_json::from_value::<Self>(value).map_err(malformed)?;
+		for peer in peer_info.peers.values_mut() {
+			peer.added_at.get_or_insert_with(SystemTime::now);
+		}
+		Ok((Self { storage_key, ..peer_info }, migrated))
+	}
+
+	/// Sets the key peer info is encrypted with on save, or disables encryption if `None`.
+	pub fn set_storage_key(&mut self, storage_key: Option<Session>) {
+		self.storage_key = storage_key;
+	}
+
+	/// Saves peer info to the file.
+	///
+	/// Recursively creates file if it doesn't exist. Peer info is first written and synced to a
+	/// sibling `.tmp` file, the previous file is moved to a sibling `.bak` file, and the `.tmp`
+	/// file is renamed in its place, so a crash never leaves a truncated file behind. If there is
+	/// a storage key, the file is encrypted with it and starts with [`MAGIC`].
+	///
+	/// # Errors
+	///
+	/// If peer info serialization fails, error kind is [`ErrorKind::InvalidData`].
+	/// If there is an error while encrypting peer info, recursively creating the file, writing to
+	/// it or renaming it, error kind is [`ErrorKind::WriteError`].
+	/// If another process holds the file locked for too long, error kind is
+	/// [`ErrorKind::LockError`].
+	pub async fn save(&self) -> Result<(), Error> {
+		let _lock = lock(&self.path, true).await?;
+		self.write().await
+	}
+
+	/// Writes peer info to the file, see [`Self::save`].
+	async fn write(&self) -> Result<(), Error> {
+		let mut data = serde_json::to_vec(&self)
+			.map_err(|_| Error::new(ErrorKind::InvalidData, "peer info is malformed"))?;
+		if let Some(storage_key) = &self.storage_key {
+			let sealed =
+				storage_key.encrypt(&data).map_err(|e| Error::new(ErrorKind::WriteError, e))?;
+			data = [MAGIC, &sealed.nonce, &sealed.tag, &sealed.ciphertext].concat();
+		}
+		let tmp_path = sibling(&self.path, "tmp");
+		let mut file =
+			File::create(&tmp_path).await.map_err(|e| Error::new(ErrorKind::WriteError, e))?;
+		file.write_all(&data).await.map_err(|e| Error::new(ErrorKind::WriteError, e))?;
+		file.sync_all().await.map_err(|e| Error::new(ErrorKind::WriteError, e))?;
+		match fs::rename(&self.path, sibling(&self.path, "bak")).await {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => {
+				return Err(Error::new(ErrorKind::WriteError, e))
+			}
+			_ => {}
+		}
+		fs::rename(&tmp_path, &self.path).await.map_err(|e| Error::new(ErrorKind::WriteError, e))
+	}
+
+	/// Returns whether the peer is blocked by its ID or by the IP address it is reached at.
+	pub fn is_blocked(&self, id: Uuid, ip: IpAddr) -> bool {
+		self.blocked.contains(&id) || self.blocked_addrs.contains(&ip)
+	}
+
+	/// Forgets the peer along with its pinned public key, returning the peer if it was known.
+	pub fn remove_peer(&mut self, id: &Uuid) -> Option<Peer> {
+		self.peers.remove(id)
+	}
+
+	/// Removes offline peers last seen more than `max_age` ago, and peers never seen that were
+	/// added more than `never_seen_max_age` ago, returning the removed peers.
+	///
+	/// Peers are never pruned without the respective age, nor while they are online or if their
+	/// time is in the future.
+	pub fn prune(
+		&mut self,
+		max_age: Option<Duration>,
+		never_seen_max_age: Option<Duration>,
+	) -> Vec<Peer> {
+		let is_older = |time: Option<SystemTime>, max_age: Option<Duration>| match (time, max_age) {
+			(Some(time), Some(max_age)) => time.elapsed().is_ok_and(|age| age > max_age),
+			_ => false,
+		};
+		let expired: Vec<_> = self
+			.peers
+			.values()
+			.filter(|peer| peer.status != Status::Online)
+			.filter(|peer| match peer.last_seen {
+				Some(last_seen) => is_older(Some(last_seen), max_age),
+				None => is_older(peer.added_at, never_seen_max_age),
+			})
+			.map(|peer| peer.id)
+			.collect();
+		expired.iter().filter_map(|id| self.peers.remove(id)).collect()
+	}
+
+	/// Removes offline peers last seen more than `max` ago, keeping peers never seen, see
+	/// [`Self::prune`].
+	pub fn prune_older_than(&mut self, max: Duration) -> Vec<Peer> {
+		self.prune(Some(max), None)
+	}
+
+	/// Returns known peers as a JSON document that [`Self::import_peers`] accepts, without any of
+	/// our own details.
+	///
+	/// # Errors
+	///
+	/// If peers can't be serialized, error kind is [`ErrorKind::ExportError`].
+	pub fn export_peers(&self) -> Result<String, Error> {
+		let peers: Vec<_> = self.peers.values().collect();
+		serde_json::to_string_pretty(&peers).map_err(|e| Error::new(ErrorKind::ExportError, e))
+	}
+
+	/// Imports peers from a JSON document written by [`Self::export_peers`].
+	///
+	/// Peers are merged as in [`Self::merge_peer`], after forgetting all known peers if the mode
+	/// is [`ImportMode::Replace`]. Our own ID and peers that add nothing new are skipped.
+	///
+	/// # Errors
+	///
+	/// If the document isn't a list of peers or lists a peer twice, error kind is
+	/// [`ErrorKind::ImportError`].
+	pub fn import_peers(&mut self, json: &str, mode: ImportMode) -> Result<ImportSummary, Error> {
+		let peers: Vec<Peer> =
+			serde_json::from_str(json).map_err(|e| Error::new(ErrorKind::ImportError, e))?;
+		let mut ids = HashSet::new();
+		if let Some(peer) = peers.iter().find(|peer| !ids.insert(peer.id)) {
+			let err = format!("peer {} is listed more than once", peer.id);
+			return Err(Error::new(ErrorKind::ImportError, err));
+		}
+
+		if mode == ImportMode::Replace {
+			self.peers.clear();
+		}
+		let mut summary = ImportSummary::default();
+		for peer in peers {
+			if peer.id == self.id {
+				summary.skipped += 1;
+				continue;
+			}
+			let conflict = self.peers.values().find(|p| p.addr == peer.addr && p.id != peer.id);
+			if let Some(other) = conflict {
+				warn!(
+					"imported peer {} shares address {} with peer {}, keeping both",
+					peer.id, peer.addr, other.id
+				);
+			}
+			let id = peer.id;
+			let known = self.peers.get(&id).cloned();
+			if self.merge_peer(peer) {
+				summary.added += 1;
+			} else if known.as_ref() != self.peers.get(&id) {
+				summary.updated += 1;
+			} else {
+				summary.skipped += 1;
+			}
+		}
+		Ok(summary)
+	}
+
+	/// Merges a peer imported from another machine, returning `true` if it wasn't known.
+	///
+	/// A new peer is added with unknown status, as we never contacted it. For a known peer, only a
+	/// more recent `last_seen` and details missing locally are taken over, so local state is never
+	/// replaced by stale values. The imported public key is never taken over, as a key is only
+	/// pinned once the peer proves it holds it.
+	pub fn merge_peer(&mut self, imported: Peer) -> bool {
+		let is_new = !self.peers.contains_key(&imported.id);
+		let peer = self.peer_or_insert(imported.id, imported.addr, imported.chat_addr);
+		if is_new {
+			peer.codec = imported.codec;
+		}
+		if imported.last_seen > peer.last_seen {
+			peer.last_seen = imported.last_seen;
+		}
+		if peer.nickname.is_none() {
+			peer.nickname = imported.nickname.as_deref().and_then(sanitize_nickname);
+		}
+		is_new
+	}
+
+	/// Retrieves an existing peer, or creates a new one if it doesn't exist.
+	pub fn peer_or_insert<I, A>(
+		&mut self,
+		id: I,
+		default_addr: A,
+		default_chat_addr: A,
+	) -> &mut Peer
+	where
+		I: Into<Uuid>,
+		A: Into<SocketAddr>,
+	{
+		let id = id.into();
+		self.peers.entry(id).or_insert(Peer::new(id, default_addr, default_chat_addr))
+	}
+}
+
+/// Takes an advisory lock on a sibling `.lock` file of the peer info file, waiting up to
+/// [`LOCK_TIMEOUT`] for other processes to release it.
+///
+/// The lock is exclusive for writing and shared for reading, and is released when the returned
+/// file is dropped. Writing creates the parent directory if it doesn't exist.
+async fn lock(path: &Path, exclusive: bool) -> Result<std::fs::File, Error> {
+	if exclusive {
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).await.map_err(|e| Error::new(ErrorKind::WriteError, e))?;
+		}
+	}
+	let file = OpenOptions::new()
+		.create(true)
+		.truncate(false)
+		.write(true)
+		.open(sibling(path, "lock"))
+		.await
+		.map_err(|e| match e.kind() {
+			io::ErrorKind::NotFound => Error::new(ErrorKind::FileNotFound, "file not found"),
+			_ => Error::new(ErrorKind::LockError, e),
+		})?
+		.into_std()
+		.await;
+	let deadline = Instant::now() + LOCK_TIMEOUT;
+	loop {
+		let result = if exclusive { file.try_lock() } else { file.try_lock_shared() };
+		match result {
+			Ok(()) => return Ok(file),
+			Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+				sleep(LOCK_RETRY_INTERVAL).await
+			}
+			Err(TryLockError::WouldBlock) => {
+				return Err(Error::new(
+					ErrorKind::LockError,
+					format!(
+						"file is locked by another process (gave up after {}s)",
+						LOCK_TIMEOUT.as_secs()
+					),
+				))
+			}
+			Err(TryLockError::Error(e)) => return Err(Error::new(ErrorKind::LockError, e)),
+		}
+	}
+}
+
+/// How imported peers are combined with known peers.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ImportMode {
+	/// Imported peers are merged into known peers.
+	#[default]
+	Merge,
+	/// Known peers are forgotten before importing.
+	Replace,
+}
+
+/// Number of imported peers by what happened to them.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct ImportSummary {
+	pub added: usize,
+	pub updated: usize,
+	pub skipped: usize,
+}
+
+/// Returns the path with the extension appended, e.g. `peer_info.json.bak`.
+fn sibling(path: &Path, extension: &str) -> PathBuf {
+	let mut path = path.as_os_str().to_owned();
+	path.push(".");
+	path.push(extension);
+	PathBuf::from(path)
+}
+
+/// Decrypts the contents of an encrypted peer info file following the header.
+fn decrypt(encrypted: &[u8], storage_key: Option<&Session>) -> Result<Vec<u8>, Error> {
+	let Some(storage_key) = storage_key else {
+		return Err(Error::new(ErrorKind::DecryptError, "file is encrypted but no key was given"));
+	};
+	if encrypted.len() < NONCE_LEN + TAG_LEN {
+		return Err(Error::new(ErrorKind::InvalidData, "file is truncated"));
+	}
+	let (nonce, rest) = encrypted.split_at(NONCE_LEN);
+	let (tag, ciphertext) = rest.split_at(TAG_LEN);
+	let sealed =
+		Sealed { nonce: nonce.to_vec(), tag: tag.to_vec(), ciphertext: ciphertext.to_vec() };
+	storage_key
+		.decrypt(&sealed)
+		.map_err(|_| Error::new(ErrorKind::DecryptError, "file was encrypted with another key"))
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	FileNotFound,
+	ReadError,
+	WriteError,
+	InvalidData,
+	MissingField,
+	InvalidKey,
+	DecryptError,
+	LockError,
+	UnsupportedVersion,
+	ExportError,
+	ImportError,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::crypto::keys::Algorithm;
+	use crate::crypto::UuidV4;
+	use tempfile::TempDir;
+
+	async fn peer_info() -> PeerInfo {
+		let addr = SocketAddr::from(([127, 0, 0, 1], 7000));
+		let chat_addr = SocketAddr::from(([127, 0, 0, 1], 7001));
+		PeerInfo::new(UuidV4::new(), addr, chat_addr, "peer_info.json").await
+	}
+
+	/// Saves peer info with one peer into a new directory.
+	async fn saved(dir: &TempDir) -> PeerInfo {
+		let (addr, chat_addr) = (([127, 0, 0, 1], 7000), ([127, 0, 0, 1], 7001));
+		let path = dir.path().join("peer_info.json");
+		let mut peer_info = PeerInfo::new(UuidV4::new(), addr, chat_addr, path).await;
+		let peer = peer(None);
+		peer_info.peers.insert(peer.id, peer);
+		peer_info.save().await.unwrap();
+		peer_info
+	}
+
+	#[tokio::test]
+	async fn partial_write_leaves_saved_file_intact() {
+		let dir = TempDir::new().unwrap();
+		let mut peer_info = saved(&dir).await;
+		// A save that crashed partway through leaves only a truncated temp file behind.
+		let data = std::fs::read(&peer_info.path).unwrap();
+		std::fs::write(sibling(&peer_info.path, "tmp"), &data[..data.len() / 2]).unwrap();
+		assert_eq!(PeerInfo::load(&peer_info.path, None).await.unwrap(), peer_info);
+
+		// The next save replaces the leftover temp file.
+		peer_info.nickname = Some("alice".to_owned());
+		peer_info.save().await.unwrap();
+		assert_eq!(PeerInfo::load(&peer_info.path, None).await.unwrap(), peer_info);
+		assert!(!sibling(&peer_info.path, "tmp").exists());
+	}
+
+	#[tokio::test]
+	async fn truncated_file_falls_back_to_backup() {
+		let dir = TempDir::new().unwrap();
+		let mut peer_info = saved(&dir).await;
+		let previous = peer_info.clone();
+		peer_info.nickname = Some("alice".to_owned());
+		peer_info.save().await.unwrap();
+
+		let data = std::fs::read(&peer_info.path).unwrap();
+		std::fs::write(&peer_info.path, &data[..data.len() / 2]).unwrap();
+		assert_eq!(PeerInfo::load(&peer_info.path, None).await.unwrap(), previous);
+
+		// Without a usable backup, the error of the file itself is reported.
+		std::fs::write(sibling(&peer_info.path, "bak"), "").unwrap();
+		let e = PeerInfo::load(&peer_info.path, None).await.unwrap_err();
+		assert_eq!(e.kind, ErrorKind::InvalidData);
+	}
+
+	#[tokio::test]
+	async fn missing_field_is_named() {
+		let dir = TempDir::new().unwrap();
+		let peer_info = saved(&dir).await;
+		let mut value: serde_json::Value =
+			serde_json::from_slice(&std::fs::read(&peer_info.path).unwrap()).unwrap();
+		value.as_object_mut().unwrap().remove("addr");
+		std::fs::write(&peer_info.path, value.to_string()).unwrap();
+
+		let e = PeerInfo::load(&peer_info.path, None).await.unwrap_err();
+		assert_eq!(e.kind, ErrorKind::MissingField);
+		assert!(e.to_string().contains("addr"), "{e}");
+	}
+
+	#[tokio::test]
+	async fn truncated_encrypted_file_falls_back_to_backup() {
+		let dir = TempDir::new().unwrap();
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let storage_key = PeerInfo::storage_key(&key_pair).unwrap();
+		let mut peer_info = saved(&dir).await;
+		peer_info.set_storage_key(Some(storage_key.clone()));
+		peer_info.save().await.unwrap();
+		let previous = peer_info.clone();
+		peer_info.nickname = Some("alice".to_owned());
+		peer_info.save().await.unwrap();
+
+		// Long enough to hold a nonce and tag, so only the tag check catches it.
+		let data = std::fs::read(&peer_info.path).unwrap();
+		std::fs::write(&peer_info.path, &data[..data.len() - 10]).unwrap();
+		let loaded = PeerInfo::load(&peer_info.path, Some(storage_key)).await.unwrap();
+		assert_eq!(loaded, previous);
+
+		// With another key, neither decrypts and the key is blamed.
+		let other_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let other_key = PeerInfo::storage_key(&other_key).unwrap();
+		let e = PeerInfo::load(&peer_info.path, Some(other_key)).await.unwrap_err();
+		assert_eq!(e.kind, ErrorKind::DecryptError);
+	}
+
+	fn peer(last_seen: Option<SystemTime>) -> Peer {
+		let mut peer = Peer::new(UuidV4::new(), ([192, 0, 2, 1], 7000), ([192, 0, 2, 1], 7001));
+		peer.last_seen = last_seen;
+		peer
+	}
+
+	fn import(peer_info: &mut PeerInfo, peers: &[Peer]) -> ImportSummary {
+		let json = serde_json::to_string(peers).unwrap();
+		peer_info.import_peers(&json, ImportMode::Merge).unwrap()
+	}
+
+	#[tokio::test]
+	async fn merge_keeps_newer_last_seen() {
+		let now = SystemTime::now();
+		let mut peer_info = peer_info().await;
+		let known = peer(Some(now));
+		peer_info.peers.insert(known.id, known.clone());
+
+		let stale = Peer { last_seen: Some(now - Duration::from_secs(60)), ..known.clone() };
+		let summary = import(&mut peer_info, &[stale]);
+		assert_eq!(peer_info.peers[&known.id].last_seen, Some(now));
+		assert_eq!((summary.added, summary.updated, summary.skipped), (0, 0, 1));
+
+		let later = Some(now + Duration::from_secs(60));
+		let summary = import(&mut peer_info, &[Peer { last_seen: later, ..known.clone() }]);
+		assert_eq!(peer_info.peers[&known.id].last_seen, later);
+		assert_eq!((summary.added, summary.updated, summary.skipped), (0, 1, 0));
+	}
+
+	#[tokio::test]
+	async fn merge_keeps_local_nickname_and_skips_own_id() {
+		let mut peer_info = peer_info().await;
+		let mut known = peer(None);
+		known.nickname = Some("alice".to_owned());
+		peer_info.peers.insert(known.id, known.clone());
+
+		let renamed = Peer { nickname: Some("mallory".to_owned()), ..known.clone() };
+		let ours = Peer { id: peer_info.id, ..peer(None) };
+		let summary = import(&mut peer_info, &[renamed, ours]);
+		assert_eq!(peer_info.peers[&known.id].nickname.as_deref(), Some("alice"));
+		assert!(!peer_info.peers.contains_key(&peer_info.id));
+		assert_eq!(summary.skipped, 2);
+	}
+
+	#[tokio::test]
+	async fn merge_does_not_pin_imported_key() {
+		let mut peer_info = peer_info().await;
+		let known = peer(None);
+		peer_info.peers.insert(known.id, known.clone());
+
+		let keyed = Peer { public_key: Some(b"imported".to_vec()), ..known.clone() };
+		let new = Peer { public_key: Some(b"imported".to_vec()), ..peer(None) };
+		let summary = import(&mut peer_info, &[keyed, new.clone()]);
+		assert_eq!(peer_info.peers[&known.id].public_key, None);
+		assert_eq!(peer_info.peers[&new.id].public_key, None);
+		assert_eq!(summary.added, 1);
+	}
+}
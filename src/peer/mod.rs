@@ -1,19 +1,66 @@
-use crate::crypto::Uuid;
+use crate::crypto::{fingerprint, Uuid};
+use crate::rpc::request::Codec;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 pub mod info;
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+/// Maximum number of characters in a nickname.
+pub const MAX_NICKNAME_LEN: usize = 16;
+
+/// Colors peer names are drawn from, skipping ones that are hard to read on dark or light
+/// backgrounds.
+const NAME_COLORS: [&str; 10] = [
+	"\x1b[31m", "\x1b[32m", "\x1b[33m", "\x1b[34m", "\x1b[35m", "\x1b[36m", "\x1b[91m", "\x1b[92m",
+	"\x1b[94m", "\x1b[95m",
+];
+
+/// Maximum number of characters in an alias.
+pub const MAX_ALIAS_LEN: usize = 16;
+
+/// How long to wait before the first retry once a peer is backed off.
+pub const BACKOFF_BASE: Duration = Duration::from_secs(60);
+
+/// Longest wait between retries of a backed-off peer.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct Peer {
 	pub id: Uuid,
 	pub addr: SocketAddr,
 	pub chat_addr: SocketAddr,
 	pub status: Status,
 	pub last_seen: Option<SystemTime>,
+	#[serde(default)]
+	pub codec: Codec,
+	/// Nickname the peer introduced itself with.
+	#[serde(default)]
+	pub nickname: Option<String>,
+	/// PEM-encoded public key pinned on first contact.
+	#[serde(default)]
+	pub public_key: Option<Vec<u8>>,
+	/// Name we gave the peer locally, never shared with other peers.
+	#[serde(default)]
+	pub alias: Option<String>,
+	/// When we learned about the peer, used to prune peers that are never seen.
+	#[serde(default)]
+	pub added_at: Option<SystemTime>,
+	/// Number of failed connection attempts in a row.
+	#[serde(default)]
+	pub failed_attempts: u32,
+	/// When we last tried to connect to the peer.
+	#[serde(default)]
+	pub last_attempt: Option<SystemTime>,
+	/// Round-trip time of the last ping the peer answered.
+	#[serde(default)]
+	pub latency: Option<Duration>,
+	/// Host name and port we connected to the peer by, kept to look up its address again.
+	#[serde(default)]
+	pub host: Option<String>,
 }
 
 impl Peer {
@@ -26,8 +73,175 @@ impl Peer {
 			id: id.into(),
 			addr: addr.into(),
 			chat_addr: chat_addr.into(),
-			status: Status::Offline,
+			status: Status::Unknown,
 			last_seen: None,
+			codec: Codec::default(),
+			nickname: None,
+			public_key: None,
+			alias: None,
+			added_at: Some(SystemTime::now()),
+			failed_attempts: 0,
+			last_attempt: None,
+			latency: None,
+			host: None,
+		}
+	}
+
+	/// Records an attempt to connect to the peer, counting failures in a row.
+	pub fn record_attempt(&mut self, connected: bool) {
+		self.last_attempt = Some(SystemTime::now());
+		self.failed_attempts = if connected { 0 } else { self.failed_attempts.saturating_add(1) };
+	}
+
+	/// Marks the peer unreachable after connecting to it failed or its connection broke, unless it
+	/// is offline, as a peer that said it was going away is expected to be gone.
+	pub fn mark_unreachable(&mut self) {
+		if self.status != Status::Offline {
+			self.status = Status::Unreachable;
+		}
+	}
+
+	/// Returns whether it is time to try connecting to the peer.
+	///
+	/// Once the peer has failed more than `max_failed_attempts` times in a row, attempts are
+	/// spaced out starting at [`BACKOFF_BASE`] and doubling with every further failure, up to
+	/// [`MAX_BACKOFF`].
+	pub fn should_attempt(&self, max_failed_attempts: u32) -> bool {
+		let excess = self.failed_attempts.saturating_sub(max_failed_attempts);
+		let Some(last_attempt) = self.last_attempt.filter(|_| excess > 0) else { return true };
+		let backoff = BACKOFF_BASE.saturating_mul(2u32.saturating_pow(excess - 1)).min(MAX_BACKOFF);
+		last_attempt.elapsed().map_or(true, |elapsed| elapsed >= backoff)
+	}
+
+	/// Updates the addresses the peer is reached at, returning `true` if either changed.
+	pub fn set_addrs<A>(&mut self, addr: A, chat_addr: A) -> bool
+	where
+		A: Into<SocketAddr>,
+	{
+		let (addr, chat_addr) = (addr.into(), chat_addr.into());
+		let changed = self.addr != addr || self.chat_addr != chat_addr;
+		self.addr = addr;
+		self.chat_addr = chat_addr;
+		changed
+	}
+
+	/// Returns the fingerprint of the peer's public key, if the key is known and valid.
+	pub fn fingerprint(&self) -> Option<String> {
+		self.public_key.as_ref().and_then(|key| fingerprint(key).ok())
+	}
+
+	/// Returns the peer's alias, or else its nickname, or else its short ID.
+	pub fn name(&self) -> String {
+		match &self.alias {
+			Some(alias) => alias.clone(),
+			None => display_name(self.id, self.nickname.as_deref()),
+		}
+	}
+}
+
+/// Returns the nickname, or the first group of the ID if there is none.
+pub fn display_name(id: Uuid, nickname: Option<&str>) -> String {
+	match nickname {
+		Some(nickname) => nickname.to_owned(),
+		None => id.to_string()[..8].to_owned(),
+	}
+}
+
+/// Returns the escape sequence the peer's name is colored with, which is the same for the ID on
+/// every run.
+pub fn name_color(id: Uuid) -> &'static str {
+	// FNV-1a, since the standard hashers aren't guaranteed to be stable across releases.
+	let hash = id.as_bytes().iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+		(hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+	});
+	NAME_COLORS[(hash % NAME_COLORS.len() as u64) as usize]
+}
+
+/// Strips control characters from a nickname received from a peer and truncates it to
+/// [`MAX_NICKNAME_LEN`] characters, returning `None` if nothing is left.
+pub fn sanitize_nickname(nickname: &str) -> Option<String> {
+	let nickname: String =
+		nickname.chars().filter(|c| !c.is_control()).take(MAX_NICKNAME_LEN).collect();
+	let nickname = nickname.trim();
+	(!nickname.is_empty()).then(|| nickname.to_owned())
+}
+
+/// Strips control characters from text received from a peer, such as a message or a file name,
+/// so it can't move the cursor or restyle the terminal, turning tabs and line breaks into spaces.
+pub fn sanitize_text(text: &str) -> String {
+	text.chars()
+		.filter_map(|c| match c {
+			c if c.is_control() && c.is_whitespace() => Some(' '),
+			c if c.is_control() => None,
+			c => Some(c),
+		})
+		.collect()
+}
+
+/// Checks that the alias is 1 to [`MAX_ALIAS_LEN`] characters without whitespace or control
+/// characters, and that no peer other than the one with the ID has it.
+///
+/// # Errors
+///
+/// Returns a message for the user if the alias is invalid or taken.
+pub fn check_alias<'a, I>(alias: &str, id: Uuid, peers: I) -> Result<(), String>
+where
+	I: IntoIterator<Item = &'a Peer>,
+{
+	let len = alias.chars().count();
+	if len == 0 || len > MAX_ALIAS_LEN || alias.chars().any(|c| c.is_whitespace() || c.is_control())
+	{
+		return Err(format!(
+			"alias must be 1 to {MAX_ALIAS_LEN} characters without whitespace or control characters"
+		));
+	}
+	match peers.into_iter().find(|p| p.id != id && p.alias.as_deref() == Some(alias)) {
+		Some(peer) => Err(format!("alias {alias} is already used for {}", peer.id)),
+		None => Ok(()),
+	}
+}
+
+/// Finds the peer among the IDs whose alias is the selector, or else the only ID that starts
+/// with it, ignoring case.
+///
+/// # Errors
+///
+/// See [`find_by_prefix`].
+pub fn find_peer<'a, I>(selector: &str, peers: &HashMap<Uuid, Peer>, ids: I) -> Result<Uuid, String>
+where
+	I: IntoIterator<Item = &'a Uuid>,
+{
+	let ids: Vec<_> = ids.into_iter().collect();
+	let aliased =
+		ids.iter().find(|id| peers.get(id).and_then(|p| p.alias.as_deref()) == Some(selector));
+	match aliased {
+		Some(id) => Ok(**id),
+		None => find_by_prefix(selector, ids),
+	}
+}
+
+/// Finds the only peer ID that starts with the prefix, ignoring case.
+///
+/// # Errors
+///
+/// Returns a message for the user if no ID or more than one ID matches.
+pub fn find_by_prefix<'a, I>(prefix: &str, ids: I) -> Result<Uuid, String>
+where
+	I: IntoIterator<Item = &'a Uuid>,
+{
+	if prefix.is_empty() {
+		return Err("peer ID prefix is empty".to_owned());
+	}
+	let lowercase = prefix.to_lowercase();
+	let mut matches: Vec<_> =
+		ids.into_iter().filter(|id| id.to_string().starts_with(&lowercase)).collect();
+	match matches.as_slice() {
+		[id] => Ok(**id),
+		[] => Err(format!("no peer matches {prefix}")),
+		_ => {
+			matches.sort();
+			let candidates: Vec<_> = matches.iter().map(ToString::to_string).collect();
+			Err(format!("peer ID prefix {prefix} is ambiguous, matches {}", candidates.join(", ")))
 		}
 	}
 }
@@ -36,11 +250,18 @@ impl Peer {
 	Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize,
 )]
 pub enum Status {
-	#[default]
 	#[serde(rename = "online")]
 	Online,
+	/// Went away or stopped answering after we were in contact.
 	#[serde(rename = "offline")]
 	Offline,
+	/// We tried to connect and failed, or the connection broke.
+	#[serde(rename = "unreachable")]
+	Unreachable,
+	/// Never contacted.
+	#[default]
+	#[serde(rename = "unknown")]
+	Unknown,
 }
 
 impl Display for Status {
@@ -48,6 +269,19 @@ impl Display for Status {
 		match self {
 			Self::Online => write!(f, "online"),
 			Self::Offline => write!(f, "offline"),
+			Self::Unreachable => write!(f, "unreachable"),
+			Self::Unknown => write!(f, "unknown"),
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sanitize_text_strips_escape_sequences() {
+		assert_eq!(sanitize_text("\x1b[2Jhi\x07 there"), "[2Jhi there");
+		assert_eq!(sanitize_text("two\nlines\tand tab"), "two lines and tab");
+	}
+}
@@ -1,81 +1,1997 @@
+use crate::conf::{chat, Conf};
+use crate::crypto::keys::same_public_key;
+use crate::crypto::{session, KeyPair, Session, Uuid, UuidV4};
 use crate::peer::info::PeerInfo;
-use crate::rpc::request::{Message, ReadRequest, Request, WriteRequest};
-use crossterm::terminal;
-use log::error;
-use std::collections::{HashMap, VecDeque};
-use std::process::exit;
-use tokio::io::{stdin, stdout, AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+use crate::peer::{
+	display_name, find_peer, name_color, sanitize_nickname, sanitize_text, Peer, Status,
+	MAX_NICKNAME_LEN,
+};
+use crate::rpc::request::{
+	Ack, Codec, Disconnect, FileChunk, FileDone, FileOffer, Message, Ping, Pong, ReadRequest,
+	Request, SessionKey, WriteRequest,
+};
+use crate::rpc::server::State;
+use crate::rpc::tls::{MaybeTlsStream, Tls};
+use crate::rpc::transfer::Transfer;
+use crate::rpc::{history, tls, transfer};
+use chrono::{DateTime, Local};
+use crossterm::event::{Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{event, terminal};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use std::{fmt, future, panic};
+use tokio::fs::File;
+use tokio::io::{
+	split, stdout, AsyncReadExt, AsyncWriteExt, BufReader, BufStream, BufWriter, ReadHalf,
+	WriteHalf,
+};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::{mpsc, watch, Mutex};
 use tokio::task;
+use tokio::task::JoinSet;
+use tokio::time::{interval, sleep_until, timeout, Instant, MissedTickBehavior};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-pub async fn start(peer_info: &PeerInfo) {
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to retry connecting to peers without a live stream.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of lines kept for scrolling back in the chat view.
+const SCROLLBACK_LINES: usize = 1000;
+
+/// Terminal size assumed when the real one can't be determined.
+const FALLBACK_SIZE: (u16, u16) = (80, 24);
+
+/// How often the key reader checks whether the chat has ended.
+const KEY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum difference between a sender's timestamp and local time before local time is used.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(3600);
+
+/// Buffered write half of a chat connection to a peer with the negotiated codec and session.
+type ChatStream = (BufWriter<WriteHalf<MaybeTlsStream>>, Codec, Option<Session>);
+
+/// Chat connections to peers by peer ID.
+type Streams = HashMap<Uuid, ChatStream>;
+
+enum Event {
+	Sent(Message),
+	Received {
+		msg: Message,
+		encrypted: bool,
+	},
+	Ack(Uuid),
+	/// Name of a peer connected to during the session.
+	Named {
+		id: Uuid,
+		name: String,
+	},
+	/// Request to scroll the chat view by a page.
+	Scroll(Scroll),
+	/// Line being typed at the prompt, with the cursor as a character index.
+	Prompt {
+		text: Vec<char>,
+		cursor: usize,
+	},
+	/// Request to remove every line from the chat view.
+	Clear,
+	/// The terminal was resized, so the view is redrawn to fit.
+	Resize,
+	/// Status line, replacing any previous status line with the same ID.
+	System {
+		id: Uuid,
+		text: String,
+	},
+}
+
+enum Line {
+	/// Chat message, marked if it was received without encryption.
+	Message {
+		msg: Message,
+		unencrypted: bool,
+	},
+	System {
+		id: Uuid,
+		text: String,
+	},
+}
+
+impl Line {
+	fn id(&self) -> Uuid {
+		match self {
+			Self::Message { msg, .. } => msg.message_id,
+			Self::System { id, .. } => *id,
+		}
+	}
+
+	/// Returns the sender and ID of the chat message on the line, if it isn't a status line.
+	///
+	/// IDs are chosen by senders, so only the pair identifies a message.
+	fn message_key(&self) -> Option<(Uuid, Uuid)> {
+		match self {
+			Self::Message { msg, .. } => Some((msg.peer_id, msg.message_id)),
+			Self::System { .. } => None,
+		}
+	}
+}
+
+/// Runs the chat until input ends or Ctrl-C is pressed.
+///
+/// On shutdown, connected peers are told we are going away, incomplete file transfers are
+/// discarded and the chat screen is cleared.
+///
+/// # Errors
+///
+/// If the chat listener can't be bound to our chat address, see [`bind`].
+/// If the terminal can't be switched to raw mode, error kind is [`ErrorKind::TerminalError`].
+pub async fn start(
+	peer_info: &PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	tls: Option<Tls>,
+	colored: bool,
+) -> Result<(), Error> {
+	let mut peer_info = peer_info.clone();
+	let listener = bind(&mut peer_info).await?;
+	let _terminal = TerminalGuard::new().map_err(|e| {
+		Error::new(ErrorKind::TerminalError, format!("failed to set up terminal for chat: {e}"))
+	})?;
+	let (key_tx, key_rx) = mpsc::channel(32);
 	let (tx, rx) = mpsc::channel(32);
+	let resize_tx = tx.clone();
+	thread::spawn(move || read_keys(key_tx, resize_tx));
+	let (shutdown_tx, shutdown_rx) = watch::channel(false);
 	let tx_clone = tx.clone();
 	let peer_info_clone = peer_info.clone();
-	task::spawn(async move { handle_input(tx_clone, &peer_info_clone).await });
-	task::spawn(handle_output(rx));
-	listen(tx, peer_info).await;
+	let conf_clone = conf.clone();
+	let tls_clone = tls.clone();
+	let key_pair_clone = key_pair.clone();
+	let shutdown = shutdown_rx.clone();
+	let mut input = task::spawn(async move {
+		let (conf, key_pair) = (&conf_clone, &key_pair_clone);
+		handle_input(tx_clone, peer_info_clone, conf, key_pair, tls_clone, key_rx, shutdown).await
+	});
+	let mut names: HashMap<_, _> = peer_info.peers.values().map(|p| (p.id, p.name())).collect();
+	names.insert(peer_info.id, display_name(peer_info.id, peer_info.nickname.as_deref()));
+	let history_key = match history_key(conf, key_pair) {
+		Ok(key) => Some(key),
+		Err(e) => {
+			let text = format!("not saving chat history: {e}");
+			tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+			None
+		}
+	};
+	let (path, count) = (&conf.chat.history_path, conf.chat.history_lines);
+	let recent = match &history_key {
+		Some(key) => history::read_last(path, count, key.as_ref()).await,
+		None => Ok(Vec::new()),
+	};
+	let recent = match recent {
+		Ok(recent) => recent,
+		Err(e) => {
+			let text = format!("failed to load chat history: {e}");
+			tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+			Vec::new()
+		}
+	};
+	let chat_conf = conf.chat.clone();
+	let output = task::spawn(handle_output(
+		rx,
+		peer_info.id,
+		names,
+		chat_conf,
+		recent,
+		history_key,
+		colored,
+	));
+
+	let stop = async {
+		tokio::select! {
+			_ = signal::ctrl_c() => {
+				let _ = shutdown_tx.send(true);
+				let _ = input.await;
+			}
+			_ = &mut input => {
+				let _ = shutdown_tx.send(true);
+			}
+		}
+	};
+	let peers = Peers::Saved(Box::new(peer_info));
+	tokio::join!(listen(tx, listener, peers, conf, key_pair, tls, shutdown_rx), stop);
+
+	output.abort();
+	let _ = output.await;
+	Ok(())
+}
+
+/// Derives the key chat history is encrypted with, if peer info is encrypted as well.
+fn history_key(conf: &Conf, key_pair: &KeyPair) -> io::Result<Option<Session>> {
+	conf.crypto.encrypt_peer_info.then(|| history::storage_key(key_pair)).transpose()
+}
+
+/// Binds the chat listener to our chat address, saving the address it was bound to if the port
+/// was picked by the system.
+///
+/// # Errors
+///
+/// If our chat address is taken, error kind is [`ErrorKind::AddrInUse`].
+/// If the listener can't be bound otherwise, error kind is [`ErrorKind::BindError`].
+pub async fn bind(peer_info: &mut PeerInfo) -> Result<TcpListener, Error> {
+	let listener = TcpListener::bind(peer_info.chat_addr).await.map_err(|e| {
+		let kind = match e.kind() {
+			io::ErrorKind::AddrInUse => ErrorKind::AddrInUse,
+			_ => ErrorKind::BindError,
+		};
+		let message = format!("failed to start chat listener on {}: {e}", peer_info.chat_addr);
+		Error::new(kind, message)
+	})?;
+	if let Ok(bound) = listener.local_addr() {
+		if bound != peer_info.chat_addr {
+			let saved = peer_info.load_and_modify(|peer_info| peer_info.chat_addr = bound);
+			if let Err(e) = saved.await {
+				warn!("failed to save bound chat address {bound}: {e}");
+			}
+		}
+	}
+	Ok(listener)
+}
+
+/// Receives messages and files from peers on the listener without the chat screen until a
+/// shutdown is signaled, logging what arrives and saving messages to chat history.
+///
+/// Peers are looked up in the server's state, so peers blocked or renamed while serving are
+/// seen without reading peer info again.
+pub async fn serve(
+	listener: TcpListener,
+	state: &Arc<Mutex<State>>,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	tls: Option<Tls>,
+	shutdown: watch::Receiver<bool>,
+) {
+	let (tx, rx) = mpsc::channel(32);
+	let history_key =
+		history_key(conf, key_pair).inspect_err(|e| warn!("not saving chat history: {e}")).ok();
+	let output = task::spawn(log_events(rx, Arc::clone(state), conf.chat.clone(), history_key));
+	let peers = Peers::Shared(Arc::clone(state));
+	listen(tx, listener, peers, conf, key_pair, tls, shutdown).await;
+	let _ = output.await;
+}
+
+/// Logs events in place of the chat screen until every sender is dropped.
+///
+/// Messages are saved to chat history encrypted with the key, unless there is no key to use
+/// because deriving it failed.
+async fn log_events(
+	mut rx: mpsc::Receiver<Event>,
+	state: Arc<Mutex<State>>,
+	conf: chat::Conf,
+	history_key: Option<Option<Session>>,
+) {
+	while let Some(event) = rx.recv().await {
+		match event {
+			Event::Received { msg, encrypted } => {
+				let name = state.lock().await.peer_info().peers.get(&msg.peer_id).map(Peer::name);
+				let name = name.unwrap_or_else(|| display_name(msg.peer_id, None));
+				let unencrypted = if encrypted { "" } else { " (unencrypted)" };
+				// Escaped so a message can't move the cursor or recolor the log.
+				info!("message from {name}{unencrypted}: {}", msg.text.escape_debug());
+				let Some(key) = &history_key else { continue };
+				let entry = history::Entry { msg, unencrypted: !encrypted };
+				if let Err(e) = history::append(&conf.history_path, &entry, key.as_ref()).await {
+					warn!("failed to save chat history: {e}");
+				}
+			}
+			Event::System { text, .. } => info!("{text}"),
+			_ => {}
+		}
+	}
+}
+
+/// Keeps the terminal in raw mode for the chat, restoring it when dropped or on a panic.
+struct TerminalGuard;
+
+impl TerminalGuard {
+	fn new() -> io::Result<Self> {
+		terminal::enable_raw_mode()?;
+		let hook = panic::take_hook();
+		panic::set_hook(Box::new(move |info| {
+			restore_terminal();
+			hook(info);
+		}));
+		Ok(Self)
+	}
+}
+
+impl Drop for TerminalGuard {
+	fn drop(&mut self) {
+		restore_terminal();
+		// Puts back the default hook, dropping the one set in `new`.
+		let _ = panic::take_hook();
+	}
+}
+
+/// Clears the chat screen and leaves raw mode.
+fn restore_terminal() {
+	let mut stdout = io::stdout();
+	let _ = stdout.write_all(b"\x1b[2J\x1b[H");
+	let _ = stdout.flush();
+	let _ = terminal::disable_raw_mode();
+}
+
+/// Line being typed at the prompt.
+#[derive(Default)]
+struct Editor {
+	text: Vec<char>,
+	/// Character index the next typed character is inserted at.
+	cursor: usize,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Scroll {
+	Up,
+	Down,
+}
+
+enum Edit {
+	Unchanged,
+	Changed,
+	/// PageUp or PageDown was pressed, which scrolls the chat view instead of the prompt.
+	Scroll(Scroll),
+	/// The line was entered and the prompt cleared.
+	Submit(String),
+	/// Ctrl-C, or Ctrl-D at an empty prompt.
+	Quit,
+}
+
+impl Editor {
+	fn handle_key(&mut self, key: KeyEvent) -> Edit {
+		let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+		match key.code {
+			KeyCode::Char('c') if ctrl => Edit::Quit,
+			KeyCode::Char('d') if ctrl && self.text.is_empty() => Edit::Quit,
+			KeyCode::Char(_) if ctrl => Edit::Unchanged,
+			KeyCode::Char(c) => {
+				self.text.insert(self.cursor, c);
+				self.cursor += 1;
+				Edit::Changed
+			}
+			KeyCode::Backspace if self.cursor > 0 => {
+				self.cursor -= 1;
+				self.text.remove(self.cursor);
+				Edit::Changed
+			}
+			KeyCode::Delete if self.cursor < self.text.len() => {
+				self.text.remove(self.cursor);
+				Edit::Changed
+			}
+			KeyCode::Left if self.cursor > 0 => {
+				self.cursor -= 1;
+				Edit::Changed
+			}
+			KeyCode::Right if self.cursor < self.text.len() => {
+				self.cursor += 1;
+				Edit::Changed
+			}
+			KeyCode::Home => {
+				self.cursor = 0;
+				Edit::Changed
+			}
+			KeyCode::End => {
+				self.cursor = self.text.len();
+				Edit::Changed
+			}
+			KeyCode::PageUp => Edit::Scroll(Scroll::Up),
+			KeyCode::PageDown => Edit::Scroll(Scroll::Down),
+			KeyCode::Enter => {
+				self.cursor = 0;
+				Edit::Submit(self.text.drain(..).collect())
+			}
+			_ => Edit::Unchanged,
+		}
+	}
+}
+
+/// Sends key presses from the terminal until the receiver is dropped or the terminal fails, and
+/// tells the output when the terminal is resized.
+///
+/// Runs on its own thread, since reading terminal events blocks.
+fn read_keys(tx: mpsc::Sender<KeyEvent>, output: mpsc::Sender<Event>) {
+	while !tx.is_closed() {
+		match event::poll(KEY_POLL_INTERVAL) {
+			Ok(true) => {}
+			Ok(false) => continue,
+			Err(_) => return,
+		}
+		match event::read() {
+			Ok(TermEvent::Key(key)) if key.kind == KeyEventKind::Press => {
+				if tx.blocking_send(key).is_err() {
+					return;
+				}
+			}
+			Ok(TermEvent::Resize(..)) => {
+				if output.blocking_send(Event::Resize).is_err() {
+					return;
+				}
+			}
+			Ok(_) => continue,
+			Err(_) => return,
+		}
+	}
+}
+
+async fn handle_input(
+	tx: mpsc::Sender<Event>,
+	mut peer_info: PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	tls: Option<Tls>,
+	mut keys: mpsc::Receiver<KeyEvent>,
+	mut shutdown: watch::Receiver<bool>,
+) {
+	let chunk_size = conf.transfer.chunk_size;
+	let max_message_size = conf.chat.max_message_size;
+	let mut streams = Streams::new();
+	let mut connects = Connects::default();
+	let mut reconnect = interval(RECONNECT_INTERVAL);
+	reconnect.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	let mut editor = Editor::default();
+
+	loop {
+		let key = tokio::select! {
+			key = keys.recv() => key,
+			_ = reconnect.tick() => {
+				let tls = tls.as_ref();
+				connect_peers(&mut connects, &mut streams, &mut peer_info, conf, key_pair, tls, &tx)
+					.await;
+				continue;
+			}
+			(id, connected, stream) = connects.next() => {
+				if let Some(stream) = stream {
+					streams.insert(id, stream);
+				}
+				record_attempts(&[(id, connected)], &mut peer_info, &tx).await;
+				continue;
+			}
+			_ = shutdown.changed() => None,
+		};
+		let input = match key.map(|key| editor.handle_key(key)) {
+			Some(Edit::Unchanged) => continue,
+			Some(Edit::Scroll(scroll)) => {
+				tx.send(Event::Scroll(scroll)).await.unwrap();
+				continue;
+			}
+			Some(Edit::Changed) => {
+				let (text, cursor) = (editor.text.clone(), editor.cursor);
+				tx.send(Event::Prompt { text, cursor }).await.unwrap();
+				continue;
+			}
+			Some(Edit::Submit(input)) => {
+				tx.send(Event::Prompt { text: Vec::new(), cursor: 0 }).await.unwrap();
+				input
+			}
+			Some(Edit::Quit) | None => {
+				broadcast(&mut streams, Disconnect::new(peer_info.id)).await;
+				return;
+			}
+		};
+		if let Some(command) = input.trim().strip_prefix('/') {
+			let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+			let args = args.trim();
+			match name {
+				"send" => send_command(args, chunk_size, &mut streams, &mut peer_info, &tx).await,
+				"msg" => {
+					msg_command(args, max_message_size, &mut streams, &mut peer_info, &tx).await
+				}
+				"peers" => peers_command(&streams, &peer_info, &tx).await,
+				"nick" => nick_command(args, &mut peer_info, conf, &tx).await,
+				"clear" => tx.send(Event::Clear).await.unwrap(),
+				"quit" => {
+					broadcast(&mut streams, Disconnect::new(peer_info.id)).await;
+					return;
+				}
+				_ => {
+					let text = format!(
+						"unknown command /{name}, expected /send, /msg, /peers, /nick, /clear or /quit"
+					);
+					tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+				}
+			}
+			continue;
+		}
+		// Leading spaces are kept as typed.
+		if input.trim().is_empty() {
+			continue;
+		}
+		if input.len() > max_message_size {
+			let text = too_long(input.len(), max_message_size);
+			tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+			continue;
+		}
+		let msg = Message::new(peer_info.id, &input);
+		tx.send(Event::Sent(msg.clone())).await.unwrap();
+
+		let mut lost = Vec::new();
+		for (id, (stream, codec, session)) in &mut streams {
+			match seal_for(&msg, session.as_ref()) {
+				Ok(msg) => {
+					if stream.send_req(msg, *codec).await.is_err() {
+						lost.push(*id);
+					}
+				}
+				Err(e) => {
+					let text = format!("failed to encrypt message for {id}: {e}");
+					tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+				}
+			}
+		}
+		for id in &lost {
+			streams.remove(id);
+		}
+		mark_lost(&lost, &mut peer_info, &tx).await;
+	}
+}
+
+/// Sends the file at the path to every connected peer, for `/send <PATH>`.
+async fn send_command(
+	path: &str,
+	chunk_size: usize,
+	streams: &mut Streams,
+	peer_info: &mut PeerInfo,
+	tx: &mpsc::Sender<Event>,
+) {
+	let id = UuidV4::new().into();
+	if path.is_empty() {
+		let text = "usage: /send <PATH>".to_owned();
+		tx.send(Event::System { id, text }).await.unwrap();
+		return;
+	}
+	let mut lost = Vec::new();
+	let sent = send_file(id, path, chunk_size, streams, &mut lost, tx).await;
+	// Whatever was written goes out even if the transfer failed, so the peers learn of it.
+	lost.extend(flush_all(streams).await);
+	if let Err(e) = sent {
+		let text = format!("failed to send {path}: {e}");
+		tx.send(Event::System { id, text }).await.unwrap();
+	}
+	mark_lost(&lost, peer_info, tx).await;
+}
+
+/// Sends a message to a single connected peer, for `/msg <ID|ALIAS> <TEXT>`.
+async fn msg_command(
+	args: &str,
+	max_message_size: usize,
+	streams: &mut Streams,
+	peer_info: &mut PeerInfo,
+	tx: &mpsc::Sender<Event>,
+) {
+	let (prefix, text) = args.split_once(' ').unwrap_or((args, ""));
+	if prefix.is_empty() {
+		let text = "usage: /msg <ID|ALIAS> <TEXT>".to_owned();
+		tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+		return;
+	}
+	let to = match find_peer(prefix, &peer_info.peers, streams.keys()) {
+		Ok(to) => to,
+		Err(text) => {
+			tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+			return;
+		}
+	};
+	let text = text.trim();
+	if text.len() > max_message_size {
+		let text = too_long(text.len(), max_message_size);
+		tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+		return;
+	}
+	let msg = Message::direct(peer_info.id, to, text);
+	tx.send(Event::Sent(msg.clone())).await.unwrap();
+	let (stream, codec, session) = streams.get_mut(&to).unwrap();
+	match seal_for(&msg, session.as_ref()) {
+		Ok(msg) => {
+			if stream.send_req(msg, *codec).await.is_err() {
+				streams.remove(&to);
+				mark_lost(&[to], peer_info, tx).await;
+			}
+		}
+		Err(e) => {
+			let text = format!("failed to encrypt message for {to}: {e}");
+			tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+		}
+	}
+}
+
+/// Shows the peers with a live stream, for `/peers`.
+async fn peers_command(streams: &Streams, peer_info: &PeerInfo, tx: &mpsc::Sender<Event>) {
+	let mut names: Vec<_> = streams
+		.keys()
+		.map(|id| match peer_info.peers.get(id) {
+			Some(peer) => format!("{} ({})", peer.name(), &id.to_string()[..8]),
+			None => display_name(*id, None),
+		})
+		.collect();
+	names.sort();
+	let text = if names.is_empty() {
+		"no connected peers".to_owned()
+	} else {
+		format!("connected to {}", names.join(", "))
+	};
+	tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+}
+
+/// Sets our nickname, or clears it if none is given, for `/nick [NAME]`.
+async fn nick_command(name: &str, peer_info: &mut PeerInfo, conf: &Conf, tx: &mpsc::Sender<Event>) {
+	let id = UuidV4::new().into();
+	let nickname = match name {
+		"" => None,
+		name => match sanitize_nickname(name).filter(|nickname| nickname == name) {
+			Some(nickname) => Some(nickname),
+			None => {
+				let text = format!(
+					"nickname must be 1 to {MAX_NICKNAME_LEN} characters without control characters"
+				);
+				tx.send(Event::System { id, text }).await.unwrap();
+				return;
+			}
+		},
+	};
+	let saved = peer_info.load_and_modify(|peer_info| peer_info.nickname.clone_from(&nickname));
+	let text = match saved.await {
+		Err(e) => format!("failed to save peer info: {e}"),
+		Ok(()) if conf.peer.nickname.is_some() => {
+			"nickname from config takes precedence over the one set here".to_owned()
+		}
+		Ok(()) => {
+			let name = display_name(peer_info.id, nickname.as_deref());
+			tx.send(Event::Named { id: peer_info.id, name }).await.unwrap();
+			match nickname {
+				Some(nickname) => format!("nickname set to {nickname}"),
+				None => "nickname cleared".to_owned(),
+			}
+		}
+	};
+	tx.send(Event::System { id, text }).await.unwrap();
+}
+
+/// Returns the error shown for a message longer than the maximum size.
+fn too_long(len: usize, max_message_size: usize) -> String {
+	format!("message not sent, it is {len} bytes but at most {max_message_size} are allowed")
+}
+
+/// Connection attempts to peers running in the background, so input is handled while they are
+/// pending.
+#[derive(Default)]
+struct Connects {
+	tasks: JoinSet<(bool, Option<ChatStream>)>,
+	/// Peer each task is connecting to.
+	peers: HashMap<task::Id, Uuid>,
+}
+
+impl Connects {
+	fn is_pending(&self, id: Uuid) -> bool {
+		self.peers.values().any(|peer_id| *peer_id == id)
+	}
+
+	/// Waits for the next attempt to finish, returning the peer, whether connecting succeeded and
+	/// the stream if the peer was introduced. Never finishes while no attempt is pending.
+	async fn next(&mut self) -> (Uuid, bool, Option<ChatStream>) {
+		match self.tasks.join_next_with_id().await {
+			Some(Ok((task, (connected, stream)))) => {
+				(self.peers.remove(&task).unwrap(), connected, stream)
+			}
+			Some(Err(e)) => (self.peers.remove(&e.id()).unwrap(), false, None),
+			None => future::pending().await,
+		}
+	}
+}
+
+/// Starts connecting to known peers without a live stream or a pending attempt, including peers
+/// saved by other processes since the chat started.
+///
+/// Peers that failed more than `max_failed_attempts` times in a row are only retried once their
+/// backoff has passed.
+async fn connect_peers(
+	connects: &mut Connects,
+	streams: &mut Streams,
+	peer_info: &mut PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+	tx: &mpsc::Sender<Event>,
+) {
+	let max_failed_attempts = conf.peers.max_failed_attempts;
+	if let Err(e) = peer_info.reload().await {
+		let text = format!("failed to reload peer info: {e}");
+		tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+	}
+	streams.retain(|id, _| {
+		peer_info.peers.get(id).is_none_or(|peer| !peer_info.is_blocked(*id, peer.chat_addr.ip()))
+	});
+	let peers: Vec<_> = peer_info
+		.peers
+		.values()
+		.filter(|peer| {
+			!streams.contains_key(&peer.id)
+				&& !connects.is_pending(peer.id)
+				&& !peer_info.is_blocked(peer.id, peer.chat_addr.ip())
+				&& peer.should_attempt(max_failed_attempts)
+		})
+		.cloned()
+		.collect();
+	if peers.is_empty() {
+		return;
+	}
+	let (peer_info, conf) = (Arc::new(peer_info.clone()), Arc::new(conf.clone()));
+	for peer in peers {
+		let id = peer.id;
+		let (peer_info, conf, key_pair) =
+			(Arc::clone(&peer_info), Arc::clone(&conf), key_pair.clone());
+		let (tls, tx) = (tls.cloned(), tx.clone());
+		let handle = connects.tasks.spawn(async move {
+			connect_peer(&peer, &peer_info, &conf, &key_pair, tls.as_ref(), &tx).await
+		});
+		connects.peers.insert(handle.id(), id);
+	}
+}
+
+/// Connects to the peer's chat and introduces us, returning whether connecting succeeded and the
+/// stream if the peer was introduced.
+async fn connect_peer(
+	peer: &Peer,
+	peer_info: &PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+	tx: &mpsc::Sender<Event>,
+) -> (bool, Option<ChatStream>) {
+	let id = peer.id;
+	let connected = timeout(conf.net.connect_timeout, tls::connect(peer.chat_addr, tls)).await;
+	let Ok(Ok(mut stream)) = connected else { return (false, None) };
+	if let Some(cert_key) = stream.peer_public_key() {
+		if !peer.public_key.as_ref().is_some_and(|key| same_public_key(key, &cert_key)) {
+			let text = format!("TLS certificate of {id} doesn't match its pinned key");
+			tx.send(Event::System { id, text }).await.unwrap();
+			return (true, None);
+		}
+	}
+	if let Err(e) = introduce(&mut stream, peer, peer_info, conf, key_pair).await {
+		tx.send(Event::System { id, text: e.to_string() }).await.unwrap();
+		return (true, None);
+	}
+	let (reader, writer) = split(stream);
+	let (reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+	task::spawn(handle_acks(reader, conf.net.max_request_size, tx.clone()));
+	let codec = conf.net.codec.negotiate(peer.codec);
+	let session = match start_session(&mut writer, codec, id, peer_info).await {
+		Ok(session) => Some(session),
+		// The peer has no key a session key can be wrapped with, such as an Ed25519 key.
+		Err(e) if e.kind == session::ErrorKind::InvalidKey => {
+			let text = format!("messages to {id} won't be encrypted: {e}");
+			tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+			None
+		}
+		Err(e) => {
+			let text = format!("failed to start session with {}: {e}", peer.name());
+			tx.send(Event::System { id, text }).await.unwrap();
+			return (true, None);
+		}
+	};
+	tx.send(Event::Named { id, name: peer.name() }).await.unwrap();
+	if peer.status != Status::Online {
+		let text = format!("connected to {}", peer.name());
+		tx.send(Event::System { id, text }).await.unwrap();
+	}
+	(true, Some((writer, codec, session)))
+}
+
+/// Records the outcomes of connection attempts in peer info, marking peers that couldn't be
+/// reached unreachable unless they said they were going away.
+async fn record_attempts(
+	attempts: &[(Uuid, bool)],
+	peer_info: &mut PeerInfo,
+	tx: &mpsc::Sender<Event>,
+) {
+	if attempts.is_empty() {
+		return;
+	}
+	let saved = peer_info.load_and_modify(|peer_info| {
+		for (id, connected) in attempts {
+			let Some(peer) = peer_info.peers.get_mut(id) else { continue };
+			peer.record_attempt(*connected);
+			if !connected {
+				peer.mark_unreachable();
+			}
+		}
+	});
+	if let Err(e) = saved.await {
+		let text = format!("failed to save peer info: {e}");
+		tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+	}
+}
+
+/// Tells the user that streams to the peers broke and marks the peers unreachable, unless they
+/// said they were going away, so they are reconnected to on the next attempt.
+async fn mark_lost(ids: &[Uuid], peer_info: &mut PeerInfo, tx: &mpsc::Sender<Event>) {
+	for id in ids {
+		let name = peer_info.peers.get(id).map_or_else(|| display_name(*id, None), Peer::name);
+		let text = format!("lost connection to {name}, reconnecting");
+		tx.send(Event::System { id: *id, text }).await.unwrap();
+	}
+	if ids.is_empty() {
+		return;
+	}
+	let saved = peer_info.load_and_modify(|peer_info| {
+		for id in ids {
+			if let Some(peer) = peer_info.peers.get_mut(id) {
+				peer.mark_unreachable();
+			}
+		}
+	});
+	if let Err(e) = saved.await {
+		let text = format!("failed to save peer info: {e}");
+		tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+	}
+}
+
+/// Writes the request to every stream and flushes them, removing streams that fail and
+/// returning their peer IDs.
+async fn broadcast<R>(streams: &mut Streams, req: R) -> Vec<Uuid>
+where
+	R: Into<Request> + Clone,
+{
+	let mut lost = queue(streams, req).await;
+	lost.extend(flush_all(streams).await);
+	lost
 }
 
-async fn handle_input(tx: mpsc::Sender<Message>, peer_info: &PeerInfo) {
-	let mut streams = HashMap::new();
-	for (id, peer) in &peer_info.peers {
-		let Ok(stream) = TcpStream::connect(peer.chat_addr).await else { continue };
-		streams.insert(id, stream);
+/// Writes the request to every stream without flushing, so a batch of requests is sent in few
+/// writes once [`flush_all`] is called. Streams that fail are removed and their peer IDs returned.
+async fn queue<R>(streams: &mut Streams, req: R) -> Vec<Uuid>
+where
+	R: Into<Request> + Clone,
+{
+	let mut lost = Vec::new();
+	for (id, (stream, codec, _)) in streams.iter_mut() {
+		if stream.write_req(req.clone(), *codec).await.is_err() {
+			lost.push(*id);
+		}
+	}
+	for id in &lost {
+		streams.remove(id);
 	}
-	let mut stdin = BufReader::new(stdin());
-	let mut input = String::new();
+	lost
+}
+
+/// Sends what was written to every stream, removing streams that fail and returning their peer
+/// IDs.
+async fn flush_all(streams: &mut Streams) -> Vec<Uuid> {
+	let mut lost = Vec::new();
+	for (id, (stream, _, _)) in streams.iter_mut() {
+		if stream.flush().await.is_err() {
+			lost.push(*id);
+		}
+	}
+	for id in &lost {
+		streams.remove(id);
+	}
+	lost
+}
 
+/// Proves who we are to the peer whose chat we connected to, answering the signed ping its chat
+/// opens the connection with by a pong signed over the ping's nonce.
+///
+/// # Errors
+///
+/// If the ping doesn't arrive in time, isn't signed by the peer or comes with a key other than
+/// the pinned one, or the pong can't be signed or written, error kind is
+/// [`ErrorKind::Unverified`].
+async fn introduce(
+	stream: &mut MaybeTlsStream,
+	peer: &Peer,
+	peer_info: &PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+) -> Result<(), Error> {
+	let unverified = |message: String| Error::new(ErrorKind::Unverified, message);
+	let name = peer.name();
+	let ping =
+		match stream.read_req_timeout(conf.net.max_request_size, conf.net.connect_timeout).await {
+			Ok(Request::Ping(ping)) => ping,
+			Ok(_) => return Err(unverified(format!("{name} didn't open chat with a ping"))),
+			Err(e) => return Err(unverified(format!("failed to receive ping from {name}: {e}"))),
+		};
+	if ping.peer_id != peer.id {
+		let message =
+			format!("chat at {} belongs to {} rather than {name}", peer.chat_addr, ping.peer_id);
+		return Err(unverified(message));
+	}
+	if let Err(e) = ping.verify() {
+		return Err(unverified(format!("ping from {name} failed verification: {e}")));
+	}
+	if peer.public_key.is_some()
+		&& peer.public_key.as_deref() != ping.public_key.as_ref().map(String::as_bytes)
+	{
+		return Err(unverified(format!("key changed for {}, possible MITM", peer.id)));
+	}
+	let public_key = key_pair
+		.public_pem()
+		.map_err(|e| unverified(format!("failed to encode our public key: {e}")))?;
+	let pong = Pong::new(
+		peer_info.id,
+		peer_info.chat_addr,
+		conf.net.codec,
+		Some(public_key),
+		ping.nonce,
+		peer_info.nickname.clone(),
+	);
+	let pong = pong.sign(key_pair).map_err(|e| unverified(format!("failed to sign pong: {e}")))?;
+	let codec = conf.net.codec.negotiate(peer.codec);
+	if let Err(e) = stream.send_req(pong, codec).await {
+		return Err(unverified(format!("failed to send pong to {name}: {e}")));
+	}
+	Ok(())
+}
+
+/// Asks the peer that connected to our chat to prove who it is, opening the connection with a
+/// signed ping it must answer with a pong signed over the ping's nonce, and returns its ID.
+///
+/// # Errors
+///
+/// If the pong doesn't arrive in time, doesn't answer the ping, isn't signed by the peer or comes
+/// with a key other than the pinned one, or the ping can't be signed or written, error kind is
+/// [`ErrorKind::Unverified`].
+async fn challenge(
+	stream: &mut BufStream<MaybeTlsStream>,
+	peer_info: &PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+) -> Result<Uuid, Error> {
+	let unverified = |message: String| Error::new(ErrorKind::Unverified, message);
+	let public_key = key_pair
+		.public_pem()
+		.map_err(|e| unverified(format!("failed to encode our public key: {e}")))?;
+	let ping = Ping::new(
+		peer_info.id,
+		peer_info.addr,
+		peer_info.chat_addr,
+		conf.net.codec,
+		Some(public_key),
+		peer_info.nickname.clone(),
+	);
+	let ping = ping.sign(key_pair).map_err(|e| unverified(format!("failed to sign ping: {e}")))?;
+	let nonce = ping.nonce;
+	// The peer's codec isn't known until it answers.
+	if let Err(e) = stream.send_req(ping, Codec::Json).await {
+		return Err(unverified(format!("failed to send ping: {e}")));
+	}
+	let pong =
+		match stream.read_req_timeout(conf.net.max_request_size, conf.net.connect_timeout).await {
+			Ok(Request::Pong(pong)) if pong.nonce == nonce => pong,
+			Ok(_) => return Err(unverified("peer didn't answer our ping".to_owned())),
+			Err(e) => return Err(unverified(format!("failed to receive pong: {e}"))),
+		};
+	if let Err(e) = pong.verify() {
+		return Err(unverified(format!("pong from {} failed verification: {e}", pong.peer_id)));
+	}
+	let pinned_key = peer_info.peers.get(&pong.peer_id).and_then(|p| p.public_key.as_deref());
+	if pinned_key.is_some() && pinned_key != pong.public_key.as_ref().map(String::as_bytes) {
+		return Err(unverified(format!("key changed for {}, possible MITM", pong.peer_id)));
+	}
+	Ok(pong.peer_id)
+}
+
+/// Creates a session for the connection to the peer and writes the session key wrapped with the
+/// peer's public key.
+///
+/// # Errors
+///
+/// If the peer has no pinned key or its key can't wrap a session key, error kind is
+/// [`session::ErrorKind::InvalidKey`].
+/// If the session key can't be generated, wrapped or written, error kind is
+/// [`session::ErrorKind::EncryptionError`].
+async fn start_session<W>(
+	writer: &mut W,
+	codec: Codec,
+	peer_id: Uuid,
+	peer_info: &PeerInfo,
+) -> Result<Session, session::Error>
+where
+	W: WriteRequest,
+{
+	let Some(public_key) = peer_info.peers.get(&peer_id).and_then(|p| p.public_key.as_ref()) else {
+		return Err(session::Error::new(session::ErrorKind::InvalidKey, "no public key"));
+	};
+	let session = Session::new()?;
+	let key = SessionKey::new(peer_info.id, session.wrap(public_key)?);
+	writer
+		.write_req(key, codec)
+		.await
+		.map_err(|e| session::Error::new(session::ErrorKind::EncryptionError, e))?;
+	Ok(session)
+}
+
+/// Encrypts the message with the session, or leaves it as plaintext if there is none.
+fn seal_for(msg: &Message, session: Option<&Session>) -> Result<Message, session::Error> {
+	match session {
+		Some(session) => msg.seal(session),
+		None => Ok(msg.clone()),
+	}
+}
+
+/// Connects to the peer's chat address and sends it a direct message without starting the chat,
+/// returning the message as sent before encryption.
+///
+/// The message is encrypted if the peer's public key is pinned, and sent as plaintext otherwise.
+/// With `wait`, the peer must acknowledge the message within [`ACK_TIMEOUT`].
+///
+/// # Errors
+///
+/// If the peer isn't known or is blocked, error kind is [`ErrorKind::UnknownPeer`].
+/// If the text is longer than the configured maximum, error kind is [`ErrorKind::TooLong`].
+/// If the peer can't be reached in time or its certificate doesn't match its pinned key, error
+/// kind is [`ErrorKind::Unreachable`].
+/// If the peer's chat can't be told who we are, see [`introduce`].
+/// If the message can't be encrypted or written, error kind is [`ErrorKind::SendError`].
+/// If the peer doesn't acknowledge the message in time, error kind is [`ErrorKind::NoAck`].
+pub async fn send_once(
+	to: Uuid,
+	text: &str,
+	wait: bool,
+	peer_info: &PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+) -> Result<Message, Error> {
+	let peer = match peer_info.peers.get(&to) {
+		Some(peer) if !peer_info.is_blocked(to, peer.chat_addr.ip()) => peer,
+		Some(_) => return Err(Error::new(ErrorKind::UnknownPeer, format!("peer {to} is blocked"))),
+		None => return Err(Error::new(ErrorKind::UnknownPeer, format!("peer {to} not found"))),
+	};
+	if text.len() > conf.chat.max_message_size {
+		let message = too_long(text.len(), conf.chat.max_message_size);
+		return Err(Error::new(ErrorKind::TooLong, message));
+	}
+	let mut stream =
+		match timeout(conf.net.connect_timeout, tls::connect(peer.chat_addr, tls)).await {
+			Ok(Ok(stream)) => stream,
+			Ok(Err(e)) => {
+				let message =
+					format!("failed to connect to {} at {}: {e}", peer.name(), peer.chat_addr);
+				return Err(Error::new(ErrorKind::Unreachable, message));
+			}
+			Err(_) => {
+				let message = format!(
+					"{} at {} didn't answer within {}s",
+					peer.name(),
+					peer.chat_addr,
+					conf.net.connect_timeout.as_secs()
+				);
+				return Err(Error::new(ErrorKind::Unreachable, message));
+			}
+		};
+	if let Some(cert_key) = stream.peer_public_key() {
+		if !peer.public_key.as_ref().is_some_and(|key| same_public_key(key, &cert_key)) {
+			let message = format!("TLS certificate of {to} doesn't match its pinned key");
+			return Err(Error::new(ErrorKind::Unreachable, message));
+		}
+	}
+	introduce(&mut stream, peer, peer_info, conf, key_pair).await?;
+	let (mut reader, mut writer) = split(stream);
+	let codec = conf.net.codec.negotiate(peer.codec);
+	let session = match start_session(&mut writer, codec, to, peer_info).await {
+		Ok(session) => Some(session),
+		Err(e) if e.kind == session::ErrorKind::InvalidKey => {
+			warn!("message to {to} won't be encrypted: {e}");
+			None
+		}
+		Err(e) => {
+			let message = format!("failed to start session with {to}: {e}");
+			return Err(Error::new(ErrorKind::SendError, message));
+		}
+	};
+	let msg = Message::direct(peer_info.id, to, text);
+	let sealed = seal_for(&msg, session.as_ref()).map_err(|e| {
+		Error::new(ErrorKind::SendError, format!("failed to encrypt message for {to}: {e}"))
+	})?;
+	writer
+		.write_req(sealed, codec)
+		.await
+		.map_err(|e| Error::new(ErrorKind::SendError, format!("failed to send message: {e}")))?;
+	if wait {
+		let acked = timeout(ACK_TIMEOUT, async {
+			loop {
+				match reader.read_req(conf.net.max_request_size).await {
+					Ok(Request::Ack(ack)) if ack.message_id == msg.message_id => return true,
+					Ok(_) => continue,
+					Err(_) => return false,
+				}
+			}
+		});
+		if !matches!(acked.await, Ok(true)) {
+			let message = format!("{} didn't acknowledge the message", peer.name());
+			return Err(Error::new(ErrorKind::NoAck, message));
+		}
+	}
+	let _ = writer.write_req(Disconnect::new(peer_info.id), codec).await;
+	Ok(msg)
+}
+
+/// Streams a file to every connected peer in chunks of the given size, leaving the last writes
+/// to be flushed by the caller.
+///
+/// Peers whose streams break are dropped from the transfer and added to `lost`.
+async fn send_file(
+	transfer_id: Uuid,
+	path: &str,
+	chunk_size: usize,
+	streams: &mut Streams,
+	lost: &mut Vec<Uuid>,
+	tx: &mpsc::Sender<Event>,
+) -> io::Result<()> {
+	let name = Path::new(path)
+		.file_name()
+		.and_then(|n| n.to_str())
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))?;
+	let sha256 = transfer::sha256(path).await?;
+	let file = File::open(path).await?;
+	let size = file.metadata().await?.len();
+	let mut file = file.take(size);
+
+	lost.extend(queue(streams, FileOffer::new(transfer_id, name, size, &sha256)).await);
+
+	let mut buf = vec![0; chunk_size];
+	let mut sent = 0;
+	let mut seq = 0;
+	let mut progress = None;
 	loop {
-		stdin.read_line(&mut input).await.unwrap();
-		let msg = Message::new(peer_info.id, input.trim());
-		tx.send(msg.clone()).await.unwrap();
+		let n = file.read(&mut buf).await?;
+		if n == 0 {
+			break;
+		}
+		sent += n as u64;
+
+		let chunk = FileChunk::new(transfer_id, seq, buf[..n].to_vec());
+		lost.extend(queue(streams, chunk).await);
+		seq += 1;
+
+		let percent = (sent * 100).checked_div(size).unwrap_or(100);
+		if progress != Some(percent) {
+			progress = Some(percent);
+			let text = format!("sending {name}: {percent}%");
+			tx.send(Event::System { id: transfer_id, text }).await.unwrap();
+		}
+	}
+	if sent != size {
+		return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file was truncated"));
+	}
+
+	lost.extend(queue(streams, FileDone::new(transfer_id)).await);
+
+	let text = format!("sent {name}");
+	tx.send(Event::System { id: transfer_id, text }).await.unwrap();
+	Ok(())
+}
 
-		for stream in streams.values_mut() {
-			let _ = stream.write_req(msg.clone()).await;
+async fn handle_acks(
+	mut reader: BufReader<ReadHalf<MaybeTlsStream>>,
+	max_len: usize,
+	tx: mpsc::Sender<Event>,
+) {
+	loop {
+		match reader.read_req(max_len).await {
+			Ok(Request::Ack(ack)) => tx.send(Event::Ack(ack.message_id)).await.unwrap(),
+			Ok(Request::Error(err)) => {
+				let text = format!("peer responded with error: {err}");
+				tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+			}
+			Ok(_) => continue,
+			Err(_) => break,
 		}
-		input.clear();
 	}
 }
 
-async fn handle_output(mut rx: mpsc::Receiver<Message>) {
+/// Returns whether the message is already on one of the lines.
+fn is_shown(lines: &VecDeque<Line>, msg: &Message) -> bool {
+	let key = Some((msg.peer_id, msg.message_id));
+	lines.iter().any(|line| line.message_key() == key)
+}
+
+async fn handle_output(
+	mut rx: mpsc::Receiver<Event>,
+	id: Uuid,
+	mut names: HashMap<Uuid, String>,
+	conf: chat::Conf,
+	recent: Vec<history::Entry>,
+	history_key: Option<Option<Session>>,
+	colored: bool,
+) {
 	let mut stdout = stdout();
 	let mut lines = VecDeque::new();
-	let size = terminal::size().unwrap();
-	let max_width = size.0 as usize;
-	let max_height = size.1 as usize;
+	let mut history_failed = false;
+	let mut pending = HashMap::<Uuid, Instant>::new();
+	let mut delivered = HashSet::new();
+	let mut undelivered = HashSet::new();
+	let mut prompt = Vec::new();
+	let mut cursor: usize = 0;
+	let (mut max_width, mut max_height) = terminal_size();
+	// Lines between the bottom of the view and the newest line, and messages received since
+	// scrolling up.
+	let mut scroll = 0;
+	let mut unread = 0;
+	for history::Entry { msg, unencrypted } in recent {
+		lines.push_front(Line::Message { msg, unencrypted });
+	}
+	lines.truncate(SCROLLBACK_LINES);
 
 	stdout.write_all(b"\x1b[2J\x1b[H").await.unwrap();
 
 	loop {
-		let title_line = format!("\x1b[H\x1b[48;5;255m\x1b[30m{:^max_width$}\x1b[0m", "p2p / chat");
-		stdout.write_all(format!("{title_line}\x1b[{max_height};0H> ").as_bytes()).await.unwrap();
+		let page = max_height.saturating_sub(2);
+		let title = match scroll {
+			0 => "p2p / chat".to_owned(),
+			_ => format!("p2p / chat (scrolled up, {unread} unread, PageDown to return)"),
+		};
+		let title_line = format!("\x1b[H\x1b[48;5;255m\x1b[30m{title:^max_width$}\x1b[0m");
+		// The end of a long line is scrolled into view, keeping the cursor on screen.
+		let width = max_width.saturating_sub(3).max(1);
+		let start = cursor.saturating_sub(width - 1);
+		let visible: String = prompt.iter().skip(start).take(width).collect();
+		let column = 3 + cursor - start;
+		let prompt_line =
+			format!("\x1b[{max_height};1H\x1b[2K> {visible}\x1b[{max_height};{column}H");
+		stdout.write_all(format!("{title_line}{prompt_line}").as_bytes()).await.unwrap();
 		stdout.flush().await.unwrap();
 
-		let msg = rx.recv().await.unwrap();
-		lines.push_front(format!("{}: {}", msg.peer_id, msg.text));
-		if lines.len() > max_height - 2 {
-			lines.pop_back();
+		let expiry = pending.values().min().map(|&sent| sent + ACK_TIMEOUT);
+		let len = lines.len();
+		tokio::select! {
+			event = rx.recv() => {
+				let event = event.unwrap();
+				let saved = match &event {
+					Event::Sent(msg) => Some((msg, false)),
+					Event::Received { msg, encrypted } => Some((msg, !encrypted)),
+					_ => None,
+				};
+				if let Some((msg, unencrypted)) = saved {
+					// A copy of a message already shown, such as our own relayed back, is dropped.
+					if is_shown(&lines, msg) {
+						continue;
+					}
+					let entry = history::Entry { msg: msg.clone(), unencrypted };
+					let appended = match &history_key {
+						Some(key) => history::append(&conf.history_path, &entry, key.as_ref()).await,
+						None => Ok(()),
+					};
+					if let Err(e) = appended {
+						if !history_failed {
+							history_failed = true;
+							let text = format!("failed to save chat history: {e}");
+							lines.push_front(Line::System { id: UuidV4::new().into(), text });
+						}
+					}
+				}
+				match event {
+					Event::Sent(msg) => {
+						pending.insert(msg.message_id, Instant::now());
+						lines.push_front(Line::Message { msg, unencrypted: false });
+						scroll = 0;
+						unread = 0;
+					}
+					Event::Received { msg, encrypted } => {
+						lines.push_front(Line::Message { msg, unencrypted: !encrypted });
+						if scroll > 0 {
+							unread += 1;
+						}
+					}
+					Event::Scroll(Scroll::Up) => {
+						scroll = (scroll + page).min(lines.len().saturating_sub(page));
+					}
+					Event::Scroll(Scroll::Down) => {
+						scroll = scroll.saturating_sub(page);
+						if scroll == 0 {
+							unread = 0;
+						}
+					}
+					Event::Ack(message_id) => {
+						if pending.remove(&message_id).is_some() || undelivered.remove(&message_id) {
+							delivered.insert(message_id);
+						}
+					}
+					Event::Clear => {
+						lines.clear();
+						scroll = 0;
+						unread = 0;
+					}
+					Event::Resize => {}
+					Event::Named { id, name } => {
+						names.insert(id, name);
+						continue;
+					}
+					Event::Prompt { text, cursor: position } => {
+						prompt = text;
+						cursor = position;
+						continue;
+					}
+					Event::System { id, text } => {
+						match lines.iter_mut().find(|line| line.id() == id) {
+							Some(line) => *line = Line::System { id, text },
+							None => lines.push_front(Line::System { id, text }),
+						}
+					}
+				}
+			}
+			_ = sleep_until(expiry.unwrap_or_else(Instant::now)), if expiry.is_some() => {
+				pending.retain(|&message_id, sent| {
+					let expired = sent.elapsed() >= ACK_TIMEOUT;
+					if expired {
+						undelivered.insert(message_id);
+					}
+					!expired
+				});
+			}
 		}
+		// New lines are kept out of view while scrolled up.
+		if scroll > 0 {
+			scroll += lines.len() - len;
+		}
+		if lines.len() > SCROLLBACK_LINES {
+			if let Some(line) = lines.pop_back() {
+				pending.remove(&line.id());
+				delivered.remove(&line.id());
+				undelivered.remove(&line.id());
+			}
+		}
+		scroll = scroll.min(lines.len().saturating_sub(page));
+		(max_width, max_height) = terminal_size();
 
+		// Rows between the title and the prompt are filled from the bottom, cutting off the top
+		// of the oldest line shown if it doesn't fit.
 		stdout.write_all(b"\x1b[2J\x1b[H").await.unwrap();
-		for (i, line) in lines.iter().enumerate() {
-			let height = max_height - i - 1;
-			stdout.write_all(format!("\x1b[{height};1H{line}").as_bytes()).await.unwrap();
+		let mut height = max_height.saturating_sub(1);
+		for line in lines.iter().skip(scroll) {
+			if height < 2 {
+				break;
+			}
+			let mut styles = Vec::new();
+			let line = match line {
+				Line::Message { msg, unencrypted } => {
+					let marker = if delivered.contains(&msg.message_id) {
+						" ✓"
+					} else if undelivered.contains(&msg.message_id) {
+						" ✗"
+					} else {
+						""
+					};
+					let timestamp = if conf.show_timestamps {
+						let sent_at = DateTime::<Local>::from(msg.sent_at).format("%H:%M:%S");
+						format!("[{sent_at}] ")
+					} else {
+						String::new()
+					};
+					let direct = if msg.to.is_some() { "[DM] " } else { "" };
+					let unencrypted = if *unencrypted { " (unencrypted)" } else { "" };
+					let name_of = |peer_id| {
+						names.get(&peer_id).cloned().unwrap_or_else(|| display_name(peer_id, None))
+					};
+					let style = |style| colored.then_some(style);
+					let mut line = format!("{timestamp}{direct}");
+					// Our own lines are set apart, with the recipient of a direct message.
+					if msg.peer_id == id {
+						push_styled(&mut line, &mut styles, "you", style("\x1b[1m"));
+						if let Some(to) = msg.to {
+							line.push_str(" -> ");
+							push_styled(
+								&mut line,
+								&mut styles,
+								&name_of(to),
+								style(name_color(to)),
+							);
+						}
+					} else {
+						let (name, color) = (name_of(msg.peer_id), name_color(msg.peer_id));
+						push_styled(&mut line, &mut styles, &name, style(color));
+					}
+					let text = sanitize_text(&msg.text);
+					line.push_str(&format!(": {text}{unencrypted}{marker}"));
+					line
+				}
+				Line::System { text, .. } => format!("* {}", sanitize_text(text)),
+			};
+			for row in wrap(&line, max_width).iter().rev() {
+				if height < 2 {
+					break;
+				}
+				let row = paint(&line, row, &styles);
+				stdout.write_all(format!("\x1b[{height};1H{row}").as_bytes()).await.unwrap();
+				height -= 1;
+			}
 		}
 	}
 }
 
-async fn listen(tx: mpsc::Sender<Message>, peer_info: &PeerInfo) {
-	let listener = TcpListener::bind(&peer_info.chat_addr).await.unwrap_or_else(|e| {
-		error!("failed to start chat listener on {}: {e}", peer_info.chat_addr);
-		exit(1);
-	});
-	while let Ok((mut stream, _)) = listener.accept().await {
-		loop {
-			let Ok(Request::Message(msg)) = stream.read_req(1024).await else { break };
-			tx.send(msg).await.unwrap();
+/// Returns the width and height of the terminal, or [`FALLBACK_SIZE`] if it can't be determined,
+/// such as when stdout isn't a terminal.
+fn terminal_size() -> (usize, usize) {
+	let (width, height) = terminal::size()
+		.ok()
+		.filter(|&(width, height)| width > 0 && height > 0)
+		.unwrap_or(FALLBACK_SIZE);
+	(width as usize, height as usize)
+}
+
+/// Splits the line into rows at most `width` columns wide, returning the byte range of each
+/// row and breaking after the last space in a row if there is one. Double-width characters such as
+/// CJK and emoji take two columns.
+///
+/// The line must be plain text, with control characters stripped and styles applied to the rows
+/// afterwards by [`paint`], as escape sequences would be counted as printable characters.
+fn wrap(line: &str, width: usize) -> Vec<Range<usize>> {
+	let width = width.max(2);
+	let mut rows = Vec::new();
+	let mut start = 0;
+	let mut row_width = 0;
+	// Where the row can be broken, as the byte offset after its last space and the columns
+	// before it.
+	let mut last_space = None;
+	for (offset, grapheme) in line.grapheme_indices(true) {
+		let is_space = grapheme.chars().all(char::is_whitespace);
+		let grapheme_width = grapheme.width();
+		if row_width + grapheme_width > width {
+			match last_space.take() {
+				Some((end, end_width)) if end < offset && !is_space => {
+					rows.push(start..end);
+					start = end;
+					row_width -= end_width;
+				}
+				_ => {
+					rows.push(start..offset);
+					start = offset;
+					row_width = 0;
+				}
+			}
+			// A double-width character may still not fit after the last word.
+			if row_width + grapheme_width > width {
+				rows.push(start..offset);
+				start = offset;
+				row_width = 0;
+			}
+			// A space that wrapped is dropped instead of starting the next row.
+			if is_space {
+				start = offset + grapheme.len();
+				continue;
+			}
+		}
+		row_width += grapheme_width;
+		if is_space {
+			last_space = Some((offset + grapheme.len(), row_width));
+		}
+	}
+	rows.push(start..line.len());
+	rows
+}
+
+/// Appends the text to the line, recording the byte range it takes up if it has a style.
+fn push_styled(
+	line: &mut String,
+	styles: &mut Vec<(Range<usize>, &'static str)>,
+	text: &str,
+	style: Option<&'static str>,
+) {
+	if let Some(style) = style {
+		styles.push((line.len()..line.len() + text.len(), style));
+	}
+	line.push_str(text);
+}
+
+/// Returns the row of the line with the escape sequence of each style around the part of the row
+/// it covers. Styles must be in order and not overlap.
+fn paint(line: &str, row: &Range<usize>, styles: &[(Range<usize>, &str)]) -> String {
+	let mut painted = String::new();
+	let mut at = row.start;
+	for (range, style) in styles {
+		let start = range.start.clamp(row.start, row.end);
+		let end = range.end.clamp(row.start, row.end);
+		if start < end {
+			painted.push_str(&line[at..start]);
+			painted.push_str(&format!("{style}{}\x1b[0m", &line[start..end]));
+			at = end;
 		}
 	}
+	painted.push_str(&line[at..row.end]);
+	painted
+}
+
+/// Where the chat listener looks up peers for each connection.
+#[derive(Clone)]
+enum Peers {
+	/// Peer info read again from disk, as nothing else keeps it current.
+	Saved(Box<PeerInfo>),
+	/// The server's state, kept current by its single writer.
+	Shared(Arc<Mutex<State>>),
+}
+
+impl Peers {
+	/// Returns current peer info, reporting a failure to read it.
+	async fn current(&mut self, tx: &mpsc::Sender<Event>) -> PeerInfo {
+		match self {
+			Self::Saved(peer_info) => {
+				// Picks up peers blocked since the chat started.
+				if let Err(e) = peer_info.reload().await {
+					let text = format!("failed to reload peer info: {e}");
+					tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+				}
+				PeerInfo::clone(peer_info)
+			}
+			Self::Shared(state) => state.lock().await.peer_info().clone(),
+		}
+	}
+}
+
+/// Receives messages and files from peers until a shutdown is signaled.
+///
+/// Each connection is handled in its own task, so several peers can chat at once.
+async fn listen(
+	tx: mpsc::Sender<Event>,
+	listener: TcpListener,
+	peers: Peers,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	tls: Option<Tls>,
+	mut shutdown: watch::Receiver<bool>,
+) {
+	let text = match listener.local_addr() {
+		Ok(bound) => format!("listening for chat on {bound}"),
+		Err(e) => format!("failed to get bound chat address: {e}"),
+	};
+	tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+	let mut tasks = JoinSet::new();
+	loop {
+		let (stream, addr) = tokio::select! {
+			accepted = listener.accept() => match accepted {
+				Ok(accepted) => accepted,
+				Err(_) => break,
+			},
+			_ = shutdown.changed() => break,
+		};
+		while tasks.try_join_next().is_some() {}
+		let tx = tx.clone();
+		let mut peers = peers.clone();
+		let conf = conf.clone();
+		let key_pair = key_pair.clone();
+		let tls = tls.clone();
+		let mut shutdown = shutdown.clone();
+		tasks.spawn(async move {
+			let peer_info = peers.current(&tx).await;
+			if peer_info.blocked_addrs.contains(&addr.ip()) {
+				return;
+			}
+			let accepted = tokio::select! {
+				accepted = tls::accept(stream, tls.as_ref()) => accepted,
+				_ = shutdown.changed() => return,
+			};
+			match accepted {
+				Ok(stream) => {
+					let mut stream = BufStream::new(stream);
+					handle(&mut stream, &tx, &peer_info, &conf, &key_pair, shutdown).await
+				}
+				Err(e) => {
+					let text = format!("failed to accept connection from {addr}: {e}");
+					tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+				}
+			}
+		});
+	}
+	while tasks.join_next().await.is_some() {}
+}
+
+/// Receives messages and files from a peer until the connection is closed or a shutdown is
+/// signaled, discarding incomplete file transfers.
+async fn handle(
+	stream: &mut BufStream<MaybeTlsStream>,
+	tx: &mpsc::Sender<Event>,
+	peer_info: &PeerInfo,
+	conf: &Conf,
+	key_pair: &KeyPair,
+	mut shutdown: watch::Receiver<bool>,
+) {
+	let codec = conf.net.codec;
+	// File chunks are encoded as JSON arrays of up to four characters per byte.
+	let max_len = conf.net.max_request_size + 4 * conf.transfer.chunk_size;
+	let peer_id = tokio::select! {
+		verified = challenge(stream, peer_info, conf, key_pair) => match verified {
+			Ok(peer_id) if peer_info.blocked.contains(&peer_id) => return,
+			Ok(peer_id) => peer_id,
+			Err(e) => {
+				let text = format!("refused chat connection: {e}");
+				tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+				return;
+			}
+		},
+		_ = shutdown.changed() => return,
+	};
+	let mut transfers = HashMap::new();
+	let mut session = None;
+	loop {
+		let req = tokio::select! {
+			req = stream.read_req_timeout(max_len, conf.chat.read_timeout) => req,
+			_ = shutdown.changed() => break,
+		};
+		match req {
+			// Only the peer that proved who it is may send on this connection.
+			Ok(Request::SessionKey(SessionKey { peer_id: sender, .. }))
+			| Ok(Request::Message(Message { peer_id: sender, .. }))
+				if sender != peer_id =>
+			{
+				let text = format!("dropped chat connection from {peer_id} sending as {sender}");
+				tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+				break;
+			}
+			Ok(Request::SessionKey(key)) => match Session::unwrap(key_pair, &key.key) {
+				Ok(key) => session = Some(key),
+				Err(e) => {
+					let text = format!("failed to decrypt session key from {}: {e}", key.peer_id);
+					tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+				}
+			},
+			Ok(Request::Message(mut msg)) => {
+				let encrypted = msg.sealed.is_some();
+				if let Err(e) = msg.open(session.as_ref()) {
+					let text = format!("failed to decrypt message from {}: {e}", msg.peer_id);
+					tx.send(Event::System { id: msg.message_id, text }).await.unwrap();
+					continue;
+				}
+				if msg.text.len() > conf.chat.max_message_size {
+					let text = format!(
+						"dropped message from {} longer than {} bytes",
+						msg.peer_id, conf.chat.max_message_size
+					);
+					tx.send(Event::System { id: msg.message_id, text }).await.unwrap();
+					continue;
+				}
+				let now = SystemTime::now();
+				let skew = match msg.sent_at.duration_since(now) {
+					Ok(ahead) => ahead,
+					Err(e) => e.duration(),
+				};
+				if skew > MAX_CLOCK_SKEW {
+					msg.sent_at = now;
+				}
+				let message_id = msg.message_id;
+				let ack_codec = peer_info
+					.peers
+					.get(&peer_id)
+					.map_or(Codec::Json, |peer| codec.negotiate(peer.codec));
+				tx.send(Event::Received { msg, encrypted }).await.unwrap();
+				let _ = stream.send_req(Ack::new(message_id), ack_codec).await;
+			}
+			Ok(Request::FileOffer(offer)) => {
+				let text = match Transfer::start(&offer, &conf.transfer.downloads).await {
+					Ok(transfer) => {
+						let text = format!("receiving {}: 0%", transfer.name);
+						transfers.insert(offer.transfer_id, transfer);
+						text
+					}
+					Err(e) => format!("failed to receive {}: {e}", offer.name),
+				};
+				tx.send(Event::System { id: offer.transfer_id, text }).await.unwrap();
+			}
+			Ok(Request::FileChunk(chunk)) => receive_chunk(&chunk, &mut transfers, tx).await,
+			Ok(Request::FileDone(done)) => {
+				let Some(transfer) = transfers.remove(&done.transfer_id) else { continue };
+				let name = transfer.name.clone();
+				let text = match transfer.finish().await {
+					Ok(path) => format!("received {name} into {}", path.display()),
+					Err(e) => format!("failed to receive {name}: {e}"),
+				};
+				tx.send(Event::System { id: done.transfer_id, text }).await.unwrap();
+			}
+			Ok(Request::Error(err)) => {
+				let text = format!("peer sent error: {err}");
+				tx.send(Event::System { id: UuidV4::new().into(), text }).await.unwrap();
+			}
+			_ => break,
+		}
+	}
+	for (id, transfer) in transfers.drain() {
+		let text = format!("failed to receive {}: connection closed", transfer.name);
+		transfer.abort().await;
+		tx.send(Event::System { id, text }).await.unwrap();
+	}
+}
+
+async fn receive_chunk(
+	chunk: &FileChunk,
+	transfers: &mut HashMap<Uuid, Transfer>,
+	tx: &mpsc::Sender<Event>,
+) {
+	let Some(transfer) = transfers.get_mut(&chunk.transfer_id) else { return };
+	let progress = transfer.progress();
+	let text = match transfer.write_chunk(chunk).await {
+		Ok(()) if transfer.progress() == progress => return,
+		Ok(()) => format!("receiving {}: {}%", transfer.name, transfer.progress()),
+		Err(e) => {
+			let transfer = transfers.remove(&chunk.transfer_id).unwrap();
+			let text = format!("failed to receive {}: {e}", transfer.name);
+			transfer.abort().await;
+			text
+		}
+	};
+	tx.send(Event::System { id: chunk.transfer_id, text }).await.unwrap();
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	BindError,
+	AddrInUse,
+	TerminalError,
+	UnknownPeer,
+	TooLong,
+	Unreachable,
+	SendError,
+	NoAck,
+	Unverified,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::crypto::keys::Algorithm;
+	use std::net::SocketAddr;
+	use tempfile::TempDir;
+	use tokio::net::TcpStream;
+
+	fn conf() -> (Conf, TempDir) {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("config.toml");
+		std::fs::write(&path, Conf::default_toml()).unwrap();
+		(Conf::load(&path).unwrap(), dir)
+	}
+
+	async fn peer_info(port: u16) -> PeerInfo {
+		let addr = SocketAddr::from(([127, 0, 0, 1], port));
+		let chat_addr = SocketAddr::from(([127, 0, 0, 1], port + 1));
+		PeerInfo::new(UuidV4::new(), addr, chat_addr, "peer_info.json").await
+	}
+
+	/// Records `other` in `peer_info` with `key` pinned.
+	fn pin(peer_info: &mut PeerInfo, other: &PeerInfo, key: &KeyPair) {
+		let peer = peer_info.peer_or_insert(other.id, other.addr, other.chat_addr);
+		peer.public_key = Some(key.public_pem().unwrap().into_bytes());
+	}
+
+	async fn connected() -> (MaybeTlsStream, BufStream<MaybeTlsStream>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+		let (server, _) = listener.accept().await.unwrap();
+		(MaybeTlsStream::Plain(client), BufStream::new(MaybeTlsStream::Plain(server)))
+	}
+
+	#[tokio::test]
+	async fn introduced_peer_is_verified() {
+		let (conf, _dir) = conf();
+		let (ours, theirs) = (peer_info(7040).await, peer_info(7050).await);
+		let our_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let their_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let (mut ours_pinned, mut theirs_pinned) = (ours.clone(), theirs.clone());
+		pin(&mut ours_pinned, &theirs, &their_key);
+		pin(&mut theirs_pinned, &ours, &our_key);
+		let us = theirs_pinned.peers[&ours.id].clone();
+
+		let (mut client, mut server) = connected().await;
+		let (verified, introduced) = tokio::join!(
+			challenge(&mut server, &ours_pinned, &conf, &our_key),
+			introduce(&mut client, &us, &theirs_pinned, &conf, &their_key),
+		);
+		introduced.unwrap();
+		assert_eq!(verified.unwrap(), theirs.id);
+	}
+
+	#[tokio::test]
+	async fn impostor_is_refused() {
+		let (conf, _dir) = conf();
+		let (ours, theirs) = (peer_info(7040).await, peer_info(7050).await);
+		let our_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let their_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let impostor_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let mut ours_pinned = ours.clone();
+		pin(&mut ours_pinned, &theirs, &their_key);
+		let mut impostor = theirs.clone();
+		pin(&mut impostor, &ours, &our_key);
+		let us = impostor.peers[&ours.id].clone();
+
+		let (mut client, mut server) = connected().await;
+		let (verified, _) = tokio::join!(
+			challenge(&mut server, &ours_pinned, &conf, &our_key),
+			introduce(&mut client, &us, &impostor, &conf, &impostor_key),
+		);
+		assert_eq!(verified.unwrap_err().kind, ErrorKind::Unverified);
+	}
+
+	#[tokio::test]
+	async fn wrong_chat_is_refused() {
+		let (conf, _dir) = conf();
+		let (ours, theirs, other) =
+			(peer_info(7040).await, peer_info(7050).await, peer_info(7060).await);
+		let our_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let their_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let mut theirs_pinned = theirs.clone();
+		pin(&mut theirs_pinned, &other, &our_key);
+		let expected = theirs_pinned.peers[&other.id].clone();
+
+		// We answer, but the connecting peer expected someone else at this address.
+		let (mut client, mut server) = connected().await;
+		let (_, introduced) = tokio::join!(challenge(&mut server, &ours, &conf, &our_key), async {
+			let introduced =
+				introduce(&mut client, &expected, &theirs_pinned, &conf, &their_key).await;
+			// Hangs up rather than leaving our side waiting for a pong.
+			drop(client);
+			introduced
+		},);
+		assert_eq!(introduced.unwrap_err().kind, ErrorKind::Unverified);
+	}
+
+	#[tokio::test]
+	async fn keys_are_handled_while_connecting() {
+		let (conf, dir) = conf();
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		// Accepts the connection but never opens the chat with a ping.
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let (addr, chat_addr) = (([127, 0, 0, 1], 7040), ([127, 0, 0, 1], 7041));
+		let path = dir.path().join("peer_info.json");
+		let mut peer_info = PeerInfo::new(UuidV4::new(), addr, chat_addr, path).await;
+		let silent = listener.local_addr().unwrap();
+		peer_info.peer_or_insert(UuidV4::new(), silent, silent);
+		peer_info.save().await.unwrap();
+
+		let (tx, mut rx) = mpsc::channel(32);
+		let (key_tx, key_rx) = mpsc::channel(32);
+		let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+		let input = task::spawn(async move {
+			handle_input(tx, peer_info, &conf, &key_pair, None, key_rx, shutdown_rx).await
+		});
+		let _stalled = listener.accept().await.unwrap();
+
+		key_tx.send(KeyEvent::from(KeyCode::Char('a'))).await.unwrap();
+		let prompt = timeout(Duration::from_secs(1), async {
+			loop {
+				if let Some(Event::Prompt { text, .. }) = rx.recv().await {
+					return text;
+				}
+			}
+		});
+		assert_eq!(prompt.await.unwrap(), vec!['a']);
+		drop(key_tx);
+		timeout(Duration::from_secs(1), input).await.unwrap().unwrap();
+	}
+
+	/// Connects to our chat as `theirs`, sends the messages and hangs up, returning the events our
+	/// side of the connection produced.
+	async fn receive(
+		ours: &PeerInfo,
+		our_key: &KeyPair,
+		theirs: &PeerInfo,
+		their_key: &KeyPair,
+		msgs: Vec<Message>,
+	) -> Vec<Event> {
+		let (conf, _dir) = conf();
+		let mut theirs_pinned = theirs.clone();
+		pin(&mut theirs_pinned, ours, our_key);
+		let us = theirs_pinned.peers[&ours.id].clone();
+		let (tx, mut rx) = mpsc::channel(32);
+		let (_shutdown_tx, shutdown) = watch::channel(false);
+		let (mut client, mut server) = connected().await;
+		let conf = &conf;
+		let sent = async move {
+			introduce(&mut client, &us, &theirs_pinned, conf, their_key).await.unwrap();
+			for msg in msgs {
+				client.send_req(msg, Codec::Json).await.unwrap();
+			}
+		};
+		tokio::join!(handle(&mut server, &tx, ours, conf, our_key, shutdown), sent);
+		drop(tx);
+		let mut events = Vec::new();
+		while let Some(event) = rx.recv().await {
+			events.push(event);
+		}
+		events
+	}
+
+	#[tokio::test]
+	async fn same_message_id_from_another_peer_is_kept_apart() {
+		let (ours, alice, bob) =
+			(peer_info(7040).await, peer_info(7050).await, peer_info(7060).await);
+		let our_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let alice_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let bob_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let mut ours_pinned = ours.clone();
+		pin(&mut ours_pinned, &alice, &alice_key);
+		pin(&mut ours_pinned, &bob, &bob_key);
+		let from_alice = Message::new(alice.id, "hello");
+		let from_bob = Message { peer_id: bob.id, ..from_alice.clone() };
+
+		let mut events = receive(
+			&ours_pinned,
+			&our_key,
+			&alice,
+			&alice_key,
+			vec![from_alice.clone(), from_alice.clone()],
+		)
+		.await;
+		events.extend(receive(&ours_pinned, &our_key, &bob, &bob_key, vec![from_bob]).await);
+		// Bob can't pass a message off as Alice's.
+		let forged = receive(&ours_pinned, &our_key, &bob, &bob_key, vec![from_alice.clone()]);
+		let forged = forged.await;
+		assert!(!forged.iter().any(|event| matches!(event, Event::Received { .. })));
+
+		let received = events.iter().filter(|event| matches!(event, Event::Received { .. }));
+		assert_eq!(received.count(), 3);
+		let mut lines = VecDeque::new();
+		for event in events {
+			let Event::Received { msg, encrypted } = event else { continue };
+			if !is_shown(&lines, &msg) {
+				lines.push_front(Line::Message { msg, unencrypted: !encrypted });
+			}
+		}
+		let shown: Vec<_> = lines.iter().filter_map(Line::message_key).collect();
+		let id = from_alice.message_id;
+		assert_eq!(shown, vec![(bob.id, id), (alice.id, id)]);
+	}
+
+	#[test]
+	fn wrap_breaks_after_last_space() {
+		let line = "hello wide 世界 world";
+		let rows: Vec<_> = wrap(line, 8).into_iter().map(|row| &line[row]).collect();
+		assert_eq!(rows, ["hello ", "wide ", "世界 ", "world"]);
+	}
+
+	#[test]
+	fn wrap_counts_escape_sequences_as_printable() {
+		let line = "\x1b[31mab";
+		assert_eq!(wrap(line, 4).len(), 2);
+	}
+
+	#[test]
+	fn paint_styles_names_split_across_rows() {
+		let mut line = String::new();
+		let mut styles = Vec::new();
+		push_styled(&mut line, &mut styles, "alice", Some("\x1b[31m"));
+		line.push_str(": hi");
+		let rows: Vec<_> = wrap(&line, 3).iter().map(|row| paint(&line, row, &styles)).collect();
+		assert_eq!(rows, ["\x1b[31mali\x1b[0m", "\x1b[31mce\x1b[0m:", "hi"]);
+	}
 }
@@ -1,57 +1,693 @@
+use crate::conf::net;
+use crate::crypto::keys::same_public_key;
+use crate::crypto::{KeyPair, Uuid};
 use crate::peer::info::PeerInfo;
-use crate::peer::{Peer, Status};
-use crate::rpc::request::{Ping, ReadRequest, Request, WriteRequest};
-use log::{error, info};
-use std::io;
+use crate::peer::{sanitize_nickname, Peer, Status};
+use crate::rpc::request::{Codec, Disconnect, Ping, Pong, ReadRequest, Request, WriteRequest};
+use crate::rpc::tls;
+use crate::rpc::tls::{MaybeTlsStream, Tls};
+use futures::stream::{self, StreamExt};
+use log::{error, info, warn};
+use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
-use std::process::exit;
-use std::time::SystemTime;
-use tokio::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, io};
+use tokio::net::lookup_host;
+use tokio::{signal, time};
 
-pub async fn connect<A>(addr: A, peer_info: &mut PeerInfo)
-where
-	A: Into<SocketAddr>,
-{
-	let addr = addr.into();
-	let Ok(mut stream) = TcpStream::connect(addr).await else {
-		error!("peer at {addr} is unreachable");
-		exit(1);
+/// Longest wait between [`connect`] retries, however many there were.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How [`connect`] treats the peer.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Options {
+	/// Whether to replace the pinned public key if the peer's key has changed.
+	pub trust_new_key: bool,
+	/// Interval to keep pinging the peer at after connecting, if the connection is kept open.
+	pub heartbeat: Option<Duration>,
+	/// Whether to record the peer as unreachable and carry on instead of exiting if it can't be
+	/// reached, retrying at the heartbeat interval if there is one.
+	pub keep_going: bool,
+	/// Whether IPv6 addresses of a host name are tried before IPv4 ones.
+	pub prefer_ipv6: bool,
+	/// Number of times to retry connecting and pinging the peer before giving up.
+	pub retries: u32,
+	/// Wait before the first retry, doubled after each one up to [`MAX_RETRY_DELAY`].
+	pub retry_delay: Duration,
+}
+
+/// Connects to the peer at `host:port`, exchanging pings and known peers.
+///
+/// A host name is resolved and each of its addresses is tried in turn, see [`resolve`]. The name
+/// is kept in the peer's record so its address can be looked up again later.
+///
+/// Failing to connect or to get a pong is retried as many times as [`Options::retries`] allows,
+/// waiting longer after each attempt. A peer that rejects the ping isn't retried.
+///
+/// With a heartbeat, the connection is kept open and the peer is pinged at that interval until
+/// Ctrl-C is pressed, marking it unreachable whenever a ping fails and reconnecting on the next
+/// one.
+///
+/// # Errors
+///
+/// If the peer can't be reached without [`Options::keep_going`], error kind is
+/// [`ErrorKind::Unreachable`].
+/// If the peer's key changed without [`Options::trust_new_key`], error kind is
+/// [`ErrorKind::KeyChanged`].
+/// If peer info can't be saved, error kind is [`ErrorKind::WriteError`].
+/// Otherwise see [`resolve`] and [`ping`].
+pub async fn connect(
+	host: &str,
+	peer_info: &mut PeerInfo,
+	conf: &net::Conf,
+	public_key: String,
+	key_pair: &KeyPair,
+	options: Options,
+	tls: Option<&Tls>,
+) -> Result<(), Error> {
+	let addrs = resolve(host, options.prefer_ipv6).await?;
+	check_not_self(host, &addrs, peer_info)?;
+	let name = host.parse::<SocketAddr>().is_err().then(|| host.to_owned());
+	let codec = conf.codec;
+	let mut retried = 0;
+	let (addr, mut stream, pong, latency) = loop {
+		let e = match open_any(&addrs, conf, tls).await {
+			Ok((addr, mut stream)) => {
+				match ping(&mut stream, host, peer_info, conf, &public_key, key_pair, Codec::Json)
+					.await
+				{
+					Ok((pong, latency)) => break (addr, stream, pong, latency),
+					// A peer that refused us or turned out to be us will do so again.
+					Err(e) if matches!(e.kind, ErrorKind::Rejected | ErrorKind::ConnectToSelf) => {
+						return Err(e)
+					}
+					Err(e) if retried < options.retries => e,
+					Err(e) => return Err(e),
+				}
+			}
+			Err(e) => {
+				record_failure(&addrs, peer_info).await;
+				connect_error(host, &e)
+			}
+		};
+		if retried < options.retries {
+			let delay =
+				options.retry_delay.saturating_mul(1 << retried.min(16)).min(MAX_RETRY_DELAY);
+			retried += 1;
+			warn!("{e}, retrying in {}ms ({retried} of {})", delay.as_millis(), options.retries);
+			tokio::select! {
+				_ = time::sleep(delay) => continue,
+				_ = signal::ctrl_c() => return Err(e),
+			}
+		}
+		if !options.keep_going {
+			return Err(e);
+		}
+		let Some(heartbeat) = options.heartbeat else {
+			warn!("{e}");
+			return Ok(());
+		};
+		warn!("{e}, retrying in {}s", heartbeat.as_secs());
+		tokio::select! {
+			_ = time::sleep(heartbeat) => {}
+			_ = signal::ctrl_c() => return Ok(()),
+		}
 	};
 
-	let ping = Ping::new(peer_info.id, peer_info.addr, peer_info.chat_addr);
-	if let Err(e) = stream.write_req(ping).await {
-		error!("failed to send ping to peer at {addr}: {e}");
-		exit(1);
+	let pinned_key = peer_info.peers.get(&pong.peer_id).and_then(|p| p.public_key.as_deref());
+	if pinned_key.is_some() && pinned_key != pong.public_key.as_ref().map(String::as_bytes) {
+		if !options.trust_new_key {
+			return Err(Error::new(
+				ErrorKind::KeyChanged,
+				format!("key changed for {}, possible MITM", pong.peer_id),
+			));
+		}
+		warn!("key changed for {}, trusting new key", pong.peer_id);
+	}
+
+	if pong.public_key.is_none() {
+		warn!("peer at {host} didn't share a public key, messages to it won't be encrypted")
+	}
+	let peer_id = pong.peer_id;
+	let peer_key = pong.public_key.clone();
+	let peer_codec = codec.negotiate(pong.codec);
+
+	if let Err(e) = stream.write_req(Request::GetPeers, peer_codec).await {
+		warn!("failed to request known peers from peer at {host}: {e}");
+	}
+	let known_peers = match stream.read_req(conf.max_request_size).await {
+		Ok(Request::Peers(peers)) => peers.peers,
+		Ok(Request::Error(err)) => {
+			warn!("peer at {host} responded with error: {err}");
+			Vec::new()
+		}
+		Ok(_) => {
+			warn!("unexpected response from peer at {host} (not a peer list)");
+			Vec::new()
+		}
+		Err(e) => {
+			warn!("failed to receive known peers from peer at {host}: {e}");
+			Vec::new()
+		}
+	};
+
+	let saved = peer_info.load_and_modify(|peer_info| {
+		let peer = peer_info.peers.entry(pong.peer_id).or_insert(Peer::new(
+			pong.peer_id,
+			addr,
+			pong.peer_chat_addr,
+		));
+		peer.status = Status::Online;
+		peer.last_seen = Some(SystemTime::now());
+		peer.record_attempt(true);
+		peer.latency = Some(latency);
+		peer.codec = pong.codec;
+		peer.nickname = pong.nickname.as_deref().and_then(sanitize_nickname);
+		peer.public_key = pong.public_key.map(String::into_bytes);
+		if name.is_some() {
+			peer.host.clone_from(&name);
+		}
+		for known_peer in known_peers {
+			if known_peer.id != peer_info.id {
+				peer_info.peer_or_insert(known_peer.id, known_peer.addr, known_peer.chat_addr);
+			}
+		}
+	});
+	saved
+		.await
+		.map_err(|e| Error::new(ErrorKind::WriteError, format!("failed to save peer info: {e}")))?;
+
+	info!("connected to peer at {host} ({}ms round trip)", latency.as_millis());
+
+	let mut stream = Some(stream);
+	if let Some(heartbeat) = options.heartbeat {
+		info!("pinging peer at {host} every {}s, press Ctrl-C to stop", heartbeat.as_secs());
+		let mut interval = time::interval(heartbeat);
+		interval.tick().await;
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				_ = signal::ctrl_c() => break,
+			}
+			let mut attempted = None;
+			if stream.is_none() {
+				stream = open(addr, conf, tls).await.ok();
+				attempted = Some(stream.is_some());
+			}
+			let alive = match &mut stream {
+				Some(stream) => {
+					let pong =
+						ping(stream, host, peer_info, conf, &public_key, key_pair, peer_codec)
+							.await;
+					pong.and_then(|(pong, latency)| {
+						if pong.peer_id == peer_id && pong.public_key == peer_key {
+							Ok(latency)
+						} else {
+							Err(Error::new(
+								ErrorKind::PeerChanged,
+								format!("peer at {host} is no longer {peer_id}"),
+							))
+						}
+					})
+				}
+				None => Err(Error::new(
+					ErrorKind::Unreachable,
+					format!("peer at {host} is unreachable"),
+				)),
+			};
+			if let Err(e) = &alive {
+				warn!("heartbeat failed: {e}");
+				stream = None;
+			}
+			let saved = peer_info.load_and_modify(|peer_info| {
+				let Some(peer) = peer_info.peers.get_mut(&peer_id) else { return };
+				if let Some(connected) = attempted {
+					peer.record_attempt(connected);
+				}
+				match alive {
+					Ok(latency) => {
+						peer.status = Status::Online;
+						peer.last_seen = Some(SystemTime::now());
+						peer.latency = Some(latency);
+					}
+					Err(_) => peer.status = Status::Unreachable,
+				}
+			});
+			if let Err(e) = saved.await {
+				error!("failed to save peer info: {e}");
+			}
+		}
+	}
+
+	if let Some(mut stream) = stream {
+		let _ = stream.write_req(Disconnect::new(peer_info.id), peer_codec).await;
+	}
+	Ok(())
+}
+
+/// Opens a connection to the peer at the address, giving up after the connect timeout.
+///
+/// # Errors
+///
+/// If the peer doesn't accept the connection in time, error kind is [`io::ErrorKind::TimedOut`].
+/// Otherwise see [`tls::connect`].
+async fn open(addr: SocketAddr, conf: &net::Conf, tls: Option<&Tls>) -> io::Result<MaybeTlsStream> {
+	time::timeout(conf.connect_timeout, tls::connect(addr, tls)).await.unwrap_or_else(|_| {
+		Err(io::Error::new(io::ErrorKind::TimedOut, format!("timed out connecting to {addr}")))
+	})
+}
+
+/// Opens a connection to the first of the addresses that accepts one, see [`open`], returning it
+/// with the address.
+///
+/// # Errors
+///
+/// If none accepts a connection, returns the error for the last one tried.
+async fn open_any(
+	addrs: &[SocketAddr],
+	conf: &net::Conf,
+	tls: Option<&Tls>,
+) -> io::Result<(SocketAddr, MaybeTlsStream)> {
+	let mut failed = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+	for &addr in addrs {
+		match open(addr, conf, tls).await {
+			Ok(stream) => return Ok((addr, stream)),
+			Err(e) => failed = e,
+		}
+	}
+	Err(failed)
+}
+
+/// Looks up the addresses of `host:port`, where the host may be a name or an IP address, with
+/// IPv4 ones first unless `prefer_ipv6` is set.
+///
+/// # Errors
+///
+/// If the host name can't be resolved, error kind is [`ErrorKind::ResolveError`].
+pub async fn resolve(host: &str, prefer_ipv6: bool) -> Result<Vec<SocketAddr>, Error> {
+	let mut addrs: Vec<_> = lookup_host(host)
+		.await
+		.map_err(|e| Error::new(ErrorKind::ResolveError, format!("failed to resolve {host}: {e}")))?
+		.collect();
+	if addrs.is_empty() {
+		let message = format!("failed to resolve {host}: no addresses found");
+		return Err(Error::new(ErrorKind::ResolveError, message));
+	}
+	addrs.sort_by_key(|addr| addr.is_ipv6() != prefer_ipv6);
+	Ok(addrs)
+}
+
+/// Checks that none of the addresses `host:port` resolved to is one we listen on ourselves.
+///
+/// # Errors
+///
+/// If one of them is ours, error kind is [`ErrorKind::ConnectToSelf`].
+fn check_not_self(host: &str, addrs: &[SocketAddr], peer_info: &PeerInfo) -> Result<(), Error> {
+	let ours = |addr: &SocketAddr| {
+		[peer_info.addr, peer_info.chat_addr].iter().any(|own| {
+			// Listening on all interfaces includes loopback.
+			let same_ip = own.ip() == addr.ip()
+				|| own.ip().is_unspecified()
+					&& (addr.ip().is_loopback() || addr.ip().is_unspecified());
+			own.port() == addr.port() && same_ip
+		})
+	};
+	if addrs.iter().any(ours) {
+		let message = format!("refusing to connect to self at {host}");
+		return Err(Error::new(ErrorKind::ConnectToSelf, message));
+	}
+	Ok(())
+}
+
+/// Describes a failure to [`open`] a connection to the peer at `host:port`, as given by the user.
+fn connect_error(host: &str, e: &io::Error) -> Error {
+	let message = match e.kind() {
+		io::ErrorKind::ConnectionAborted => format!("failed to connect to peer at {host}: {e}"),
+		io::ErrorKind::TimedOut => format!("timed out connecting to {host}"),
+		_ => format!("peer at {host} is unreachable"),
+	};
+	Error::new(ErrorKind::Unreachable, message)
+}
+
+/// Counts a failed connection attempt against known peers at any of the addresses.
+async fn record_failure(addrs: &[SocketAddr], peer_info: &mut PeerInfo) {
+	if !peer_info.peers.values().any(|peer| addrs.contains(&peer.addr)) {
+		return;
+	}
+	let saved = peer_info.load_and_modify(|peer_info| {
+		for peer in peer_info.peers.values_mut().filter(|peer| addrs.contains(&peer.addr)) {
+			peer.record_attempt(false);
+			peer.status = Status::Unreachable;
+		}
+	});
+	if let Err(e) = saved.await {
+		warn!("failed to save peer info: {e}");
 	}
+}
 
-	let pong = match stream.read_req(1024).await {
-		Ok(Request::Pong(pong)) => pong,
+/// Sends a signed ping over the stream and waits for the matching pong, returning it with the
+/// time it took to arrive.
+///
+/// # Errors
+///
+/// If the address is our own, error kind is [`ErrorKind::ConnectToSelf`].
+/// If the peer rejects the ping, error kind is [`ErrorKind::Rejected`].
+/// If the peer doesn't answer within the connect timeout, error kind is [`ErrorKind::TimedOut`].
+/// If the ping can't be sent or the answer isn't the matching pong signed with the key it came
+/// with, error kind is [`ErrorKind::PingError`].
+async fn ping(
+	stream: &mut MaybeTlsStream,
+	host: &str,
+	peer_info: &PeerInfo,
+	conf: &net::Conf,
+	public_key: &str,
+	key_pair: &KeyPair,
+	write_codec: Codec,
+) -> Result<(Pong, Duration), Error> {
+	let ping = Ping::new(
+		peer_info.id,
+		peer_info.addr,
+		peer_info.chat_addr,
+		conf.codec,
+		Some(public_key.to_owned()),
+		peer_info.nickname.clone(),
+	);
+	let ping = ping
+		.sign(key_pair)
+		.map_err(|e| Error::new(ErrorKind::PingError, format!("failed to sign ping: {e}")))?;
+	let nonce = ping.nonce;
+	let sent = Instant::now();
+	let ping_id = stream.write_req(ping, write_codec).await.map_err(|e| {
+		Error::new(ErrorKind::PingError, format!("failed to send ping to peer at {host}: {e}"))
+	})?;
+
+	let pong = match stream
+		.read_req_with_id_timeout(conf.max_request_size, conf.connect_timeout)
+		.await
+	{
+		Ok((Some(pong_id), Request::Pong(_))) if pong_id != ping_id => {
+			return Err(Error::new(
+				ErrorKind::PingError,
+				format!("unexpected response from peer at {host} (pong doesn't match ping)"),
+			));
+		}
+		Ok((_, Request::Pong(pong))) if pong.nonce != nonce => {
+			return Err(Error::new(
+				ErrorKind::PingError,
+				format!("unexpected response from peer at {host} (pong nonce doesn't match ping)"),
+			));
+		}
+		Ok((_, Request::Pong(pong))) if pong.peer_id == peer_info.id => {
+			let message = format!("refusing to connect to self at {host}");
+			return Err(Error::new(ErrorKind::ConnectToSelf, message));
+		}
+		Ok((_, Request::Pong(pong))) => pong,
+		Ok((_, Request::Error(err))) => {
+			let message = format!("peer at {host} responded with error: {err}");
+			return Err(Error::new(ErrorKind::Rejected, message));
+		}
 		Ok(_) => {
-			error!("unexpected response from peer at {addr} (not a pong)");
-			exit(1);
+			return Err(Error::new(
+				ErrorKind::PingError,
+				format!("unexpected response from peer at {host} (not a pong)"),
+			))
 		}
 		Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
-			error!("peer at {addr} aborted connection");
-			exit(1);
+			return Err(Error::new(
+				ErrorKind::PingError,
+				format!("peer at {host} aborted connection"),
+			));
+		}
+		Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+			return Err(Error::new(
+				ErrorKind::PingError,
+				format!("peer at {host} speaks unsupported protocol: {e}"),
+			));
+		}
+		Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+			let secs = conf.connect_timeout.as_secs();
+			let message = format!("peer at {host} didn't answer ping within {secs}s");
+			return Err(Error::new(ErrorKind::TimedOut, message));
 		}
 		Err(e) => {
-			error!("failed to receive pong from peer at {addr}: {e}");
-			exit(1);
+			return Err(Error::new(
+				ErrorKind::PingError,
+				format!("failed to receive pong from peer at {host}: {e}"),
+			))
 		}
 	};
+	let latency = sent.elapsed();
 
-	let peer = peer_info.peers.entry(pong.peer_id).or_insert(Peer::new(
-		pong.peer_id,
-		addr,
-		pong.peer_chat_addr,
-	));
-	peer.status = Status::Online;
-	peer.last_seen = Some(SystemTime::now());
+	if let Err(e) = pong.verify() {
+		let message = format!("pong from peer at {host} failed verification: {e}");
+		return Err(Error::new(ErrorKind::PingError, message));
+	}
+	if let Some(cert_key) = stream.peer_public_key() {
+		if !pong.public_key.as_ref().is_some_and(|key| same_public_key(key, &cert_key)) {
+			return Err(Error::new(
+				ErrorKind::PingError,
+				format!("TLS certificate of peer at {host} doesn't match its public key"),
+			));
+		}
+	}
+	Ok((pong, latency))
+}
 
-	if let Err(e) = peer_info.save().await {
-		error!("failed to save peer info: {e}");
-		exit(1);
+/// Pings the peer at `host:port` without recording anything in peer info, returning the address
+/// that answered with its pong and the round-trip time.
+///
+/// A host name is resolved and each of its addresses is tried in turn, see [`resolve`].
+///
+/// # Errors
+///
+/// If the peer can't be reached, error kind is [`ErrorKind::Unreachable`].
+/// Otherwise see [`resolve`] and [`ping`].
+pub async fn probe(
+	host: &str,
+	prefer_ipv6: bool,
+	peer_info: &PeerInfo,
+	conf: &net::Conf,
+	public_key: &str,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+) -> Result<(SocketAddr, Pong, Duration), Error> {
+	let addrs = resolve(host, prefer_ipv6).await?;
+	check_not_self(host, &addrs, peer_info)?;
+	let (addr, mut stream) =
+		open_any(&addrs, conf, tls).await.map_err(|e| connect_error(host, &e))?;
+	let (pong, latency) =
+		ping(&mut stream, host, peer_info, conf, public_key, key_pair, Codec::Json).await?;
+	let _ = stream.write_req(Disconnect::new(peer_info.id), conf.codec.negotiate(pong.codec)).await;
+	Ok((addr, pong, latency))
+}
+
+/// Pings every known peer that isn't blocked, at most `jobs` at a time, returning the addresses
+/// and round-trip time each one answered with, without recording anything.
+///
+/// Peers known by a host name are looked up again, so they are found after their address
+/// changes. A peer that answers with another ID or a key other than the pinned one counts as
+/// unreachable, and every failure is logged.
+pub async fn probe_all(
+	peer_info: &PeerInfo,
+	conf: &net::Conf,
+	public_key: &str,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+	jobs: usize,
+) -> Vec<(Uuid, Result<(SocketAddr, SocketAddr, Duration), Error>)> {
+	let peers: Vec<_> = peer_info
+		.peers
+		.values()
+		.filter(|peer| !peer_info.is_blocked(peer.id, peer.addr.ip()))
+		.map(|peer| {
+			let host = peer.host.clone().unwrap_or_else(|| peer.addr.to_string());
+			(peer.id, host, peer.public_key.clone())
+		})
+		.collect();
+	stream::iter(peers)
+		.map(|(id, host, pinned_key)| async move {
+			let probed = probe(&host, false, peer_info, conf, public_key, key_pair, tls).await;
+			let result = probed.and_then(|(addr, pong, latency)| {
+				check_pong(id, &host, pinned_key.as_deref(), &pong)?;
+				Ok((addr, pong.peer_chat_addr, latency))
+			});
+			if let Err(e) = &result {
+				warn!("{e}");
+			}
+			(id, result)
+		})
+		.buffer_unordered(jobs)
+		.collect()
+		.await
+}
+
+/// Pings every known peer that isn't blocked, at most `jobs` at a time, and records which ones
+/// answered, saving peer info once at the end, see [`probe_all`].
+///
+/// Returns the number of peers found online and unreachable.
+///
+/// # Errors
+///
+/// If peer info can't be saved, error kind is [`ErrorKind::WriteError`].
+pub async fn refresh(
+	peer_info: &mut PeerInfo,
+	conf: &net::Conf,
+	public_key: &str,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+	jobs: usize,
+) -> Result<(usize, usize), Error> {
+	let results = probe_all(peer_info, conf, public_key, key_pair, tls, jobs).await;
+	let online = results.iter().filter(|(_, result)| result.is_ok()).count();
+	let saved = peer_info.load_and_modify(|peer_info| {
+		for (id, result) in &results {
+			if let Some(peer) = peer_info.peers.get_mut(id) {
+				record_answer(peer, result.as_ref().ok().copied());
+			}
+		}
+	});
+	saved
+		.await
+		.map_err(|e| Error::new(ErrorKind::WriteError, format!("failed to save peer info: {e}")))?;
+	Ok((online, results.len() - online))
+}
+
+/// Records whether the known peer answered pings sent to `host:port`, with the address that
+/// answered, its pong and the round-trip time if it did. Nothing is recorded for unknown peers.
+///
+/// # Errors
+///
+/// If the pong came from another peer, error kind is [`ErrorKind::PeerChanged`], and if it came
+/// with a key other than the pinned one, error kind is [`ErrorKind::KeyChanged`]. Either way the
+/// peer is recorded as unreachable.
+/// If peer info can't be saved, error kind is [`ErrorKind::WriteError`].
+pub async fn record_ping(
+	id: Uuid,
+	host: &str,
+	answer: Option<(SocketAddr, &Pong, Duration)>,
+	peer_info: &mut PeerInfo,
+) -> Result<(), Error> {
+	let Some(peer) = peer_info.peers.get(&id) else { return Ok(()) };
+	let checked = match answer {
+		Some((addr, pong, latency)) => check_pong(id, host, peer.public_key.as_deref(), pong)
+			.map(|()| Some((addr, pong.peer_chat_addr, latency))),
+		None => Ok(None),
+	};
+	let answer = checked.as_ref().ok().copied().flatten();
+	let saved = peer_info.load_and_modify(|peer_info| {
+		if let Some(peer) = peer_info.peers.get_mut(&id) {
+			record_answer(peer, answer);
+		}
+	});
+	saved
+		.await
+		.map_err(|e| Error::new(ErrorKind::WriteError, format!("failed to save peer info: {e}")))?;
+	checked.map(|_| ())
+}
+
+/// Checks that the pong came from the peer with the ID, with its pinned key if it has one.
+///
+/// # Errors
+///
+/// If the pong came from another peer, error kind is [`ErrorKind::PeerChanged`].
+/// If the pong came with a key other than the pinned one, error kind is
+/// [`ErrorKind::KeyChanged`].
+fn check_pong(id: Uuid, host: &str, pinned_key: Option<&[u8]>, pong: &Pong) -> Result<(), Error> {
+	if pong.peer_id != id {
+		let message = format!("peer at {host} is no longer {id}");
+		return Err(Error::new(ErrorKind::PeerChanged, message));
+	}
+	if pinned_key.is_some() && pinned_key != pong.public_key.as_ref().map(String::as_bytes) {
+		let message = format!("key changed for {id}, possible MITM");
+		return Err(Error::new(ErrorKind::KeyChanged, message));
 	}
+	Ok(())
+}
+
+/// Records a ping attempt in the peer, with the addresses and round-trip time it answered with
+/// if it did, or marking it unreachable unless it said it was going away.
+pub fn record_answer(peer: &mut Peer, answer: Option<(SocketAddr, SocketAddr, Duration)>) {
+	peer.record_attempt(answer.is_some());
+	match answer {
+		Some((addr, chat_addr, latency)) => {
+			peer.addr = addr;
+			peer.chat_addr = chat_addr;
+			peer.status = Status::Online;
+			peer.last_seen = Some(SystemTime::now());
+			peer.latency = Some(latency);
+		}
+		None => peer.mark_unreachable(),
+	}
+}
+
+/// Notifies the peer at the address that we are going away.
+///
+/// The peer is pinged first, as it only accepts a disconnect from a peer that proved who it is
+/// on the same connection. Failures are logged.
+pub async fn disconnect<A>(
+	addr: A,
+	peer_info: &PeerInfo,
+	conf: &net::Conf,
+	public_key: &str,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+) where
+	A: Into<SocketAddr>,
+{
+	let addr = addr.into();
+	let Ok(mut stream) = open(addr, conf, tls).await else {
+		warn!("peer at {addr} is unreachable");
+		return;
+	};
+	let host = addr.to_string();
+	let pong = ping(&mut stream, &host, peer_info, conf, public_key, key_pair, Codec::Json).await;
+	let codec = match pong {
+		Ok((pong, _)) => conf.codec.negotiate(pong.codec),
+		Err(e) => {
+			warn!("failed to disconnect from peer at {addr}: {e}");
+			return;
+		}
+	};
+	if let Err(e) = stream.write_req(Disconnect::new(peer_info.id), codec).await {
+		warn!("failed to send disconnect to peer at {addr}: {e}");
+		return;
+	}
+	info!("disconnected from peer at {addr}");
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
 
-	info!("connected to peer at {addr}");
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	ResolveError,
+	ConnectToSelf,
+	Unreachable,
+	TimedOut,
+	PingError,
+	Rejected,
+	KeyChanged,
+	PeerChanged,
+	WriteError,
 }
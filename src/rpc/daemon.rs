@@ -0,0 +1,192 @@
+use crate::conf::Conf;
+use crate::crypto::KeyPair;
+use crate::peer::info::PeerInfo;
+use crate::peer::Status;
+use crate::rpc::server::{State, Update};
+use crate::rpc::tls::Tls;
+use crate::rpc::{chat, client, server};
+use futures::future::join_all;
+use log::info;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::{fmt, io};
+use tokio::signal;
+use tokio::sync::{watch, Mutex};
+use tokio::time::MissedTickBehavior;
+use tokio::{task, time};
+
+/// Number of peers pinged at once by the keepalive.
+const KEEPALIVE_JOBS: usize = 16;
+
+/// Runs the server and chat listeners, pings known peers every `ping_interval` and marks idle
+/// ones offline, until Ctrl-C is pressed or SIGTERM is received.
+///
+/// Everything works on one copy of peer info, whose changes are saved by a single writer. On
+/// shutdown, online peers are told we are going away, peers that were still connected are saved
+/// as offline and every pending change is saved.
+///
+/// # Errors
+///
+/// If either listener's address is already taken, error kind is [`ErrorKind::AddrInUse`].
+/// If either listener can't be bound otherwise, error kind is [`ErrorKind::BindError`].
+/// If signals can't be listened for, error kind is [`ErrorKind::SignalError`].
+pub async fn run(
+	peer_info: &PeerInfo,
+	conf: &Conf,
+	public_key: String,
+	key_pair: &KeyPair,
+	tls: Option<Tls>,
+) -> Result<(), Error> {
+	let mut terminated = terminated().map_err(|e| {
+		Error::new(ErrorKind::SignalError, format!("failed to listen for signals: {e}"))
+	})?;
+	let mut peer_info = peer_info.clone();
+	let listener = server::bind(&mut peer_info).await.map_err(|e| match e.kind {
+		server::ErrorKind::AddrInUse => Error::new(ErrorKind::AddrInUse, e.err),
+		server::ErrorKind::BindError => Error::new(ErrorKind::BindError, e.err),
+	})?;
+	let chat_listener = chat::bind(&mut peer_info).await.map_err(|e| match e.kind {
+		chat::ErrorKind::AddrInUse => Error::new(ErrorKind::AddrInUse, e.err),
+		_ => Error::new(ErrorKind::BindError, e.err),
+	})?;
+	let state = Arc::new(Mutex::new(State::new(
+		peer_info.clone(),
+		public_key.clone(),
+		key_pair.clone(),
+		conf.net,
+	)));
+	let sweeper =
+		task::spawn(server::mark_idle_offline(Arc::clone(&state), conf.net.offline_after));
+	let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+	let stop = async {
+		terminated.recv().await;
+		info!("shutting down");
+	};
+	let serve = async {
+		let connected = server::serve(listener, &state, conf.net, tls.clone(), stop).await;
+		let _ = shutdown_tx.send(true);
+		connected
+	};
+	let (connected, (), (), ()) = tokio::join!(
+		serve,
+		chat::serve(chat_listener, &state, conf, key_pair, tls.clone(), shutdown_rx.clone()),
+		keepalive(&state, conf, &public_key, key_pair, tls.as_ref(), shutdown_rx.clone()),
+		server::save_pending(Arc::clone(&state), conf.net.save_interval, shutdown_rx),
+	);
+	sweeper.abort();
+
+	let snapshot = state.lock().await.peer_info().clone();
+	let online = snapshot.peers.values().filter(|peer| {
+		peer.status == Status::Online && !snapshot.is_blocked(peer.id, peer.addr.ip())
+	});
+	join_all(online.map(|peer| {
+		time::timeout(
+			conf.net.connect_timeout,
+			client::disconnect(
+				peer.addr,
+				&snapshot,
+				&conf.net,
+				&public_key,
+				key_pair,
+				tls.as_ref(),
+			),
+		)
+	}))
+	.await;
+	server::save_final(&state, connected).await;
+	Ok(())
+}
+
+/// Pings known peers that aren't blocked every `ping_interval`, queuing whether they answered,
+/// until a shutdown is signaled.
+///
+/// Peers that failed more than `max_failed_attempts` times in a row are skipped until their
+/// backoff has passed.
+async fn keepalive(
+	state: &Arc<Mutex<State>>,
+	conf: &Conf,
+	public_key: &str,
+	key_pair: &KeyPair,
+	tls: Option<&Tls>,
+	mut shutdown: watch::Receiver<bool>,
+) {
+	let mut interval = time::interval(conf.net.ping_interval);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	loop {
+		tokio::select! {
+			_ = interval.tick() => {}
+			_ = shutdown.changed() => return,
+		}
+		let mut peer_info = state.lock().await.peer_info().clone();
+		peer_info.peers.retain(|_, peer| peer.should_attempt(conf.peers.max_failed_attempts));
+		let probed =
+			client::probe_all(&peer_info, &conf.net, public_key, key_pair, tls, KEEPALIVE_JOBS);
+		let results = tokio::select! {
+			results = probed => results,
+			_ = shutdown.changed() => return,
+		};
+		let mut state = state.lock().await;
+		for (peer_id, result) in results {
+			state.update(peer_id, Update::Probed(result.ok()));
+		}
+	}
+}
+
+/// Stream of termination requests, which are Ctrl-C and, on Unix, SIGTERM.
+struct Terminated {
+	#[cfg(unix)]
+	sigterm: signal::unix::Signal,
+}
+
+/// Starts listening for termination requests.
+fn terminated() -> io::Result<Terminated> {
+	Ok(Terminated {
+		#[cfg(unix)]
+		sigterm: signal::unix::signal(signal::unix::SignalKind::terminate())?,
+	})
+}
+
+impl Terminated {
+	/// Waits for the next termination request.
+	async fn recv(&mut self) {
+		#[cfg(unix)]
+		tokio::select! {
+			_ = signal::ctrl_c() => {}
+			_ = self.sigterm.recv() => {}
+		}
+		#[cfg(not(unix))]
+		let _ = signal::ctrl_c().await;
+	}
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	BindError,
+	AddrInUse,
+	SignalError,
+}
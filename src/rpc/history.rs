@@ -0,0 +1,147 @@
+use crate::crypto::{KeyPair, Sealed, Session};
+use crate::rpc::request::Message;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+/// Size the history file may grow to before it is moved to a sibling `.old` file, replacing the
+/// previous one.
+pub const MAX_HISTORY_SIZE: u64 = 1024 * 1024;
+
+/// Context the history key is derived under, so it differs from the peer info key.
+const HISTORY_CONTEXT: &[u8] = b"p2p chat history";
+
+/// Message in the history file.
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+	#[serde(flatten)]
+	pub msg: Message,
+	/// Whether the message was received without end-to-end encryption. Lines written before
+	/// this was recorded count as encrypted.
+	#[serde(default)]
+	pub unencrypted: bool,
+}
+
+/// Line of the history file holding an entry encrypted with the history key.
+#[derive(Serialize, Deserialize)]
+struct SealedEntry {
+	entry: Sealed,
+}
+
+/// Derives the key chat history is encrypted with at rest from our key pair.
+///
+/// # Errors
+///
+/// This function returns [`io::Error`] if the private key can't be encoded.
+pub fn storage_key(key_pair: &KeyPair) -> io::Result<Session> {
+	Session::derive(key_pair, HISTORY_CONTEXT).map_err(io::Error::other)
+}
+
+/// Reads up to `count` of the most recent entries from the history file, oldest first,
+/// decrypting entries with the key.
+///
+/// Lines that can't be parsed or decrypted are skipped. A missing file has no entries.
+///
+/// # Errors
+///
+/// This function returns [`io::Error`] if the file exists but can't be read.
+pub async fn read_last<P>(path: P, count: usize, key: Option<&Session>) -> io::Result<Vec<Entry>>
+where
+	P: AsRef<Path>,
+{
+	let data = match fs::read_to_string(path).await {
+		Ok(data) => data,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+		Err(e) => return Err(e),
+	};
+	let mut entries: Vec<_> =
+		data.lines().rev().filter_map(|line| parse(line, key)).take(count).collect();
+	entries.reverse();
+	Ok(entries)
+}
+
+/// Appends the entry to the history file as a JSON line, encrypted if there is a key, rotating
+/// the file once it exceeds [`MAX_HISTORY_SIZE`].
+///
+/// # Errors
+///
+/// This function returns [`io::Error`] if the entry can't be encrypted, or the file can't be
+/// rotated, created or written to.
+pub async fn append<P>(path: P, entry: &Entry, key: Option<&Session>) -> io::Result<()>
+where
+	P: AsRef<Path>,
+{
+	let path = path.as_ref();
+	if fs::metadata(path).await.is_ok_and(|metadata| metadata.len() >= MAX_HISTORY_SIZE) {
+		fs::rename(path, old_path(path)).await?;
+	}
+	if let Some(dir) = path.parent() {
+		fs::create_dir_all(dir).await?;
+	}
+	let mut line = serde_json::to_vec(entry).map_err(io::Error::other)?;
+	if let Some(key) = key {
+		let entry = key.encrypt(&line).map_err(io::Error::other)?;
+		line = serde_json::to_vec(&SealedEntry { entry }).map_err(io::Error::other)?;
+	}
+	line.push(b'\n');
+	let mut file = OpenOptions::new().create(true).append(true).open(path).await?;
+	file.write_all(&line).await
+}
+
+/// Parses a line of the history file, which is encrypted if it was written with a key.
+fn parse(line: &str, key: Option<&Session>) -> Option<Entry> {
+	match serde_json::from_str::<SealedEntry>(line) {
+		Ok(sealed) => serde_json::from_slice(&key?.decrypt(&sealed.entry).ok()?).ok(),
+		Err(_) => serde_json::from_str(line).ok(),
+	}
+}
+
+fn old_path(path: &Path) -> PathBuf {
+	let mut path = path.as_os_str().to_owned();
+	path.push(".old");
+	PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::crypto::keys::Algorithm;
+	use crate::crypto::UuidV4;
+	use tempfile::TempDir;
+
+	fn entry(text: &str, unencrypted: bool) -> Entry {
+		Entry { msg: Message::new(UuidV4::new(), text), unencrypted }
+	}
+
+	#[tokio::test]
+	async fn encrypted_history_round_trips() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("history.jsonl");
+		let key = storage_key(&KeyPair::generate(Algorithm::Ed25519, 0).unwrap()).unwrap();
+		let entries = [entry("secret", false), entry("in the clear", true)];
+		for entry in &entries {
+			append(&path, entry, Some(&key)).await.unwrap();
+		}
+
+		let data = std::fs::read_to_string(&path).unwrap();
+		assert!(!data.contains("secret"));
+		assert_eq!(read_last(&path, 10, Some(&key)).await.unwrap(), entries);
+		assert_eq!(read_last(&path, 10, None).await.unwrap(), []);
+	}
+
+	#[tokio::test]
+	async fn plaintext_lines_keep_unencrypted_flag() {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("history.jsonl");
+		let old = Message::new(UuidV4::new(), "before flags were saved");
+		std::fs::write(&path, format!("{}\n", serde_json::to_string(&old).unwrap())).unwrap();
+		append(&path, &entry("unencrypted", true), None).await.unwrap();
+
+		let entries = read_last(&path, 10, None).await.unwrap();
+		assert_eq!(entries[0], Entry { msg: old, unencrypted: false });
+		assert!(entries[1].unencrypted);
+	}
+}
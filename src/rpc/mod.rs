@@ -1,4 +1,8 @@
 pub mod chat;
 pub mod client;
+pub mod daemon;
+pub mod history;
 pub mod request;
 pub mod server;
+pub mod tls;
+pub mod transfer;
@@ -1,33 +1,77 @@
-use crate::crypto::Uuid;
+use crate::crypto::{session, signature, KeyPair, Sealed, Session, Uuid, UuidV4};
+use crate::peer::Peer;
+use rand::random;
 use serde::{Deserialize, Serialize};
-use std::io;
-use std::io::ErrorKind::{ConnectionAborted, InvalidData};
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind::{ConnectionAborted, InvalidData, TimedOut, UnexpectedEof, Unsupported};
 use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fmt, io};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time;
+
+/// Version of the wire protocol spoken by this build.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Oldest protocol version this build can decode. Version 2 added required fields, such as the
+/// nonce and timestamp of pings and the ID of messages, which older peers don't send.
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// Largest request payload length that may be configured, in bytes.
+pub const MAX_REQUEST_LEN: usize = 16 * 1024 * 1024;
+
+/// Random value identifying a single ping.
+pub type Nonce = [u8; 16];
+
+/// First two bytes of a TLS handshake record, seen in place of the length header when a peer
+/// with TLS enabled talks to one without it.
+const TLS_HANDSHAKE_PREFIX: u32 = 0x1603;
+
+/// First byte of a bincode payload. JSON payloads always start with `{`.
+const BINCODE_MARKER: u8 = 0;
+
+/// First byte of a MessagePack payload.
+const MESSAGE_PACK_MARKER: u8 = 1;
 
 pub trait ReadRequest: AsyncReadExt + Unpin {
-	async fn read_req(&mut self, cap: usize) -> io::Result<Request>;
+	async fn read_req(&mut self, max_len: usize) -> io::Result<Request>;
+
+	async fn read_req_with_id(&mut self, max_len: usize) -> io::Result<(Option<Uuid>, Request)>;
+
+	async fn read_req_timeout(&mut self, max_len: usize, timeout: Duration) -> io::Result<Request>;
+
+	async fn read_req_with_id_timeout(
+		&mut self,
+		max_len: usize,
+		timeout: Duration,
+	) -> io::Result<(Option<Uuid>, Request)>;
 }
 
 impl<W> ReadRequest for W
 where
 	W: AsyncReadExt + Unpin,
 {
-	/// Reads a request into a buffer with the specified capacity.
+	/// Reads a length-prefixed request.
 	///
-	/// If the request exceeds the buffer size it will be truncated, causing it to be malformed.
+	/// The request is expected to start with a big-endian `u32` header holding the payload length,
+	/// followed by the payload itself. The payload is read in full even if it arrives in multiple
+	/// segments. Its [`Codec`] is detected automatically.
 	///
 	/// # Errors
 	///
 	/// This function returns [`io::Error`] if underlying implementation of [`Self::read`] fails.
-	/// If this function reads 0 bytes, error kind is [`ConnectionAborted`].
+	/// If the stream ends before the header is read, error kind is [`ConnectionAborted`].
+	/// If the payload length exceeds `max_len`, the payload is malformed or the peer
+	/// starts a TLS handshake, error kind is [`InvalidData`].
+	/// If the request was sent with a protocol version newer than [`PROTOCOL_VERSION`] or older
+	/// than [`MIN_PROTOCOL_VERSION`], error kind is [`Unsupported`].
 	///
 	/// # Examples
 	///
 	/// ```rust
 	/// let stream = TcpStream::connect("192.168.0.1:7040");
 	///
-	/// let ping = match stream.read_req().await {
+	/// let ping = match stream.read_req(64 * 1024).await {
 	///     Ok(Request::Ping(ping)) => ping,
 	///     Ok(req) => panic!("unexpected request: {req:?}"),
 	///     Err(e) if e.kind() == ConnectionAborted => panic!("connection aborted"),
@@ -36,17 +80,80 @@ where
 	///
 	/// println!("received ping: {ping:?}");
 	/// ```
-	async fn read_req(&mut self, cap: usize) -> io::Result<Request> {
-		let mut buf = vec![0; cap];
-		match self.read(&mut buf).await? {
-			0 => Err(io::Error::new(ConnectionAborted, "connection aborted")),
-			n => serde_json::from_slice(&buf[..n]).map_err(|e| io::Error::new(InvalidData, e)),
+	async fn read_req(&mut self, max_len: usize) -> io::Result<Request> {
+		self.read_req_with_id(max_len).await.map(|(_, req)| req)
+	}
+
+	/// Reads a length-prefixed request along with its ID.
+	///
+	/// The ID is [`None`] if the request was sent by a peer that doesn't tag requests with IDs.
+	///
+	/// # Errors
+	///
+	/// See [`Self::read_req`].
+	async fn read_req_with_id(&mut self, max_len: usize) -> io::Result<(Option<Uuid>, Request)> {
+		let len = match self.read_u32().await {
+			Ok(len) => len as usize,
+			Err(e) if e.kind() == UnexpectedEof => {
+				return Err(io::Error::new(ConnectionAborted, "connection aborted"))
+			}
+			Err(e) => return Err(e),
+		};
+		if len as u32 >> 16 == TLS_HANDSHAKE_PREFIX {
+			return Err(io::Error::new(
+				InvalidData,
+				"peer started a TLS handshake, it may have TLS enabled",
+			));
 		}
+		if len > max_len {
+			return Err(io::Error::new(
+				InvalidData,
+				format!("request length {len} exceeds maximum of {max_len}"),
+			));
+		}
+		let mut buf = vec![0; len];
+		self.read_exact(&mut buf).await?;
+		decode(&buf)
+	}
+
+	/// Reads a length-prefixed request, giving up if it doesn't arrive in full within the timeout.
+	///
+	/// # Errors
+	///
+	/// If the timeout elapses, error kind is [`TimedOut`].
+	/// Otherwise see [`Self::read_req`].
+	async fn read_req_timeout(&mut self, max_len: usize, timeout: Duration) -> io::Result<Request> {
+		self.read_req_with_id_timeout(max_len, timeout).await.map(|(_, req)| req)
+	}
+
+	/// Reads a length-prefixed request along with its ID, giving up if it doesn't arrive in full
+	/// within the timeout.
+	///
+	/// # Errors
+	///
+	/// If the timeout elapses, error kind is [`TimedOut`].
+	/// Otherwise see [`Self::read_req`].
+	async fn read_req_with_id_timeout(
+		&mut self,
+		max_len: usize,
+		timeout: Duration,
+	) -> io::Result<(Option<Uuid>, Request)> {
+		time::timeout(timeout, self.read_req_with_id(max_len))
+			.await
+			.map_err(|_| io::Error::new(TimedOut, format!("no request within {timeout:?}")))?
 	}
 }
 
 pub trait WriteRequest: AsyncWriteExt + Unpin {
-	async fn write_req<R>(&mut self, req: R) -> io::Result<()>
+	async fn write_req<R>(&mut self, req: R, codec: Codec) -> io::Result<Uuid>
+	where
+		R: Into<Request>;
+
+	async fn write_req_with_id<R>(&mut self, req: R, codec: Codec, id: Uuid) -> io::Result<()>
+	where
+		R: Into<Request>;
+
+	async fn send_req<R>(&mut self, req: R, codec: Codec) -> io::Result<Uuid>
 	where
 		R: Into<Request>;
 }
@@ -55,11 +162,179 @@ impl<W> WriteRequest for W
 where
 	W: AsyncWriteExt + Unpin,
 {
-	async fn write_req<R>(&mut self, req: R) -> io::Result<()>
+	/// Writes a request prefixed with its length as a big-endian `u32`.
+	///
+	/// The request is encoded with the specified codec and tagged with [`PROTOCOL_VERSION`] and a
+	/// new ID, which is returned.
+	///
+	/// # Errors
+	///
+	/// This function returns [`io::Error`] if underlying implementation of [`Self::write`] fails.
+	/// If the request can't be serialized or its length doesn't fit into `u32`, error kind is
+	/// [`InvalidData`].
+	async fn write_req<R>(&mut self, req: R, codec: Codec) -> io::Result<Uuid>
+	where
+		R: Into<Request>,
+	{
+		let id = UuidV4::new().into();
+		self.write_req_with_id(req, codec, id).await?;
+		Ok(id)
+	}
+
+	/// Writes a request tagged with the specified ID, e.g. to answer a request with the same ID.
+	///
+	/// # Errors
+	///
+	/// See [`Self::write_req`].
+	async fn write_req_with_id<R>(&mut self, req: R, codec: Codec, id: Uuid) -> io::Result<()>
+	where
+		R: Into<Request>,
+	{
+		let payload = encode(id, req.into(), codec)?;
+		let len = u32::try_from(payload.len()).map_err(|e| io::Error::new(InvalidData, e))?;
+		let mut frame = Vec::with_capacity(4 + payload.len());
+		frame.extend_from_slice(&len.to_be_bytes());
+		frame.extend_from_slice(&payload);
+		self.write_all(&frame).await
+	}
+
+	/// Writes a request and flushes the stream, so a buffered stream sends it right away along
+	/// with any requests written before it.
+	///
+	/// Requests written to a [`BufWriter`] or [`BufStream`] with [`Self::write_req`] are only sent
+	/// once the buffer fills up or the stream is flushed, so a batch of requests is written that
+	/// way and ended with this or [`AsyncWriteExt::flush`].
+	///
+	/// # Errors
+	///
+	/// This function returns [`io::Error`] if underlying implementation of [`Self::flush`] fails.
+	/// Otherwise see [`Self::write_req`].
+	///
+	/// # Examples
+	///
+	/// ```rust
+	/// let mut stream = BufWriter::new(TcpStream::connect("192.168.0.1:7041").await?);
+	///
+	/// for chunk in chunks {
+	///     stream.write_req(chunk, Codec::Json).await?;
+	/// }
+	/// stream.send_req(FileDone::new(transfer_id), Codec::Json).await?;
+	/// ```
+	///
+	/// [`BufWriter`]: tokio::io::BufWriter
+	/// [`BufStream`]: tokio::io::BufStream
+	async fn send_req<R>(&mut self, req: R, codec: Codec) -> io::Result<Uuid>
 	where
 		R: Into<Request>,
 	{
-		self.write_all(&serde_json::to_vec(&req.into())?).await
+		let id = self.write_req(req, codec).await?;
+		self.flush().await?;
+		Ok(id)
+	}
+}
+
+fn encode(id: Uuid, req: Request, codec: Codec) -> io::Result<Vec<u8>> {
+	match codec {
+		Codec::Json => {
+			Ok(serde_json::to_vec(&Envelope { version: PROTOCOL_VERSION, id, req: &req })?)
+		}
+		Codec::Bincode => {
+			let mut payload = vec![BINCODE_MARKER, PROTOCOL_VERSION];
+			bincode::serialize_into(&mut payload, &BinaryEnvelope { id, req })
+				.map_err(|e| io::Error::new(InvalidData, e))?;
+			Ok(payload)
+		}
+		Codec::MessagePack => {
+			let mut payload = vec![MESSAGE_PACK_MARKER, PROTOCOL_VERSION];
+			rmp_serde::encode::write(&mut payload, &BinaryEnvelope { id, req })
+				.map_err(|e| io::Error::new(InvalidData, e))?;
+			Ok(payload)
+		}
+	}
+}
+
+fn decode(buf: &[u8]) -> io::Result<(Option<Uuid>, Request)> {
+	let (codec, version, id) = match buf {
+		[BINCODE_MARKER, version, ..] => (Codec::Bincode, *version, None),
+		[MESSAGE_PACK_MARKER, version, ..] => (Codec::MessagePack, *version, None),
+		_ => {
+			let header: Header =
+				serde_json::from_slice(buf).map_err(|e| io::Error::new(InvalidData, e))?;
+			(Codec::Json, header.version, header.id)
+		}
+	};
+	if version > PROTOCOL_VERSION {
+		return Err(io::Error::new(
+			Unsupported,
+			format!("peer speaks newer protocol version {version}"),
+		));
+	}
+	if version < MIN_PROTOCOL_VERSION {
+		return Err(io::Error::new(
+			Unsupported,
+			format!("peer speaks older protocol version {version}"),
+		));
+	}
+	match codec {
+		Codec::Json => serde_json::from_slice(buf)
+			.map(|req| (id, req))
+			.map_err(|e| io::Error::new(InvalidData, e)),
+		Codec::Bincode => bincode::deserialize::<BinaryEnvelope>(&buf[2..])
+			.map(|envelope| (Some(envelope.id), envelope.req))
+			.map_err(|e| io::Error::new(InvalidData, e)),
+		Codec::MessagePack => rmp_serde::from_slice::<BinaryEnvelope>(&buf[2..])
+			.map(|envelope| (Some(envelope.id), envelope.req))
+			.map_err(|e| io::Error::new(InvalidData, e)),
+	}
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+	version: u8,
+	id: Uuid,
+	#[serde(flatten)]
+	req: &'a Request,
+}
+
+#[derive(Deserialize)]
+struct Header {
+	#[serde(default)]
+	version: u8,
+	#[serde(default)]
+	id: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BinaryEnvelope {
+	id: Uuid,
+	#[serde(with = "BinaryRequest")]
+	req: Request,
+}
+
+/// Serialization format of a request payload.
+#[derive(
+	Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize,
+)]
+pub enum Codec {
+	#[default]
+	#[serde(rename = "json")]
+	Json,
+	#[serde(rename = "bincode")]
+	Bincode,
+	#[serde(rename = "msgpack")]
+	MessagePack,
+}
+
+impl Codec {
+	/// Picks the codec to use with a peer that prefers `other`.
+	///
+	/// Falls back to [`Codec::Json`] if the preferences differ.
+	pub fn negotiate(self, other: Self) -> Self {
+		if self == other {
+			self
+		} else {
+			Self::Json
+		}
 	}
 }
 
@@ -72,17 +347,77 @@ pub enum Request {
 	Pong(Pong),
 	#[serde(rename = "message")]
 	Message(Message),
+	#[serde(rename = "disconnect")]
+	Disconnect(Disconnect),
+	#[serde(rename = "get_peers")]
+	GetPeers,
+	#[serde(rename = "peers")]
+	Peers(Peers),
+	#[serde(rename = "ack")]
+	Ack(Ack),
+	#[serde(rename = "file_offer")]
+	FileOffer(FileOffer),
+	#[serde(rename = "file_chunk")]
+	FileChunk(FileChunk),
+	#[serde(rename = "file_done")]
+	FileDone(FileDone),
+	#[serde(rename = "session_key")]
+	SessionKey(SessionKey),
+	#[serde(rename = "error")]
+	Error(ErrorReply),
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+/// Externally tagged mirror of [`Request`] for binary codecs, since bincode can't decode internally
+/// tagged enums.
+///
+/// Bincode encodes variants by index, so new variants go last.
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Request")]
+enum BinaryRequest {
+	Ping(Ping),
+	Pong(Pong),
+	Message(Message),
+	Disconnect(Disconnect),
+	GetPeers,
+	Peers(Peers),
+	Ack(Ack),
+	FileOffer(FileOffer),
+	FileChunk(FileChunk),
+	Error(ErrorReply),
+	FileDone(FileDone),
+	SessionKey(SessionKey),
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct Ping {
 	pub peer_id: Uuid,
 	pub peer_addr: SocketAddr,
 	pub peer_chat_addr: SocketAddr,
+	#[serde(default)]
+	pub codec: Codec,
+	/// PEM-encoded RSA public key of the sender, if it has one.
+	#[serde(default)]
+	pub public_key: Option<String>,
+	/// Random value that lets the receiver drop replayed pings.
+	pub nonce: Nonce,
+	pub timestamp: SystemTime,
+	/// Signature over the sender's identity, nonce and timestamp made with the private key
+	/// matching `public_key`.
+	#[serde(default)]
+	pub signature: Option<Vec<u8>>,
+	#[serde(default)]
+	pub nickname: Option<String>,
 }
 
 impl Ping {
-	pub fn new<I, A>(peer_id: I, peer_addr: A, peer_chat_addr: A) -> Self
+	pub fn new<I, A>(
+		peer_id: I,
+		peer_addr: A,
+		peer_chat_addr: A,
+		codec: Codec,
+		public_key: Option<String>,
+		nickname: Option<String>,
+	) -> Self
 	where
 		I: Into<Uuid>,
 		A: Into<SocketAddr>,
@@ -91,8 +426,52 @@ impl Ping {
 			peer_id: peer_id.into(),
 			peer_addr: peer_addr.into(),
 			peer_chat_addr: peer_chat_addr.into(),
+			codec,
+			public_key,
+			nonce: random(),
+			timestamp: SystemTime::now(),
+			signature: None,
+			nickname,
 		}
 	}
+
+	/// Signs the ping with the key pair matching its public key.
+	///
+	/// # Errors
+	///
+	/// See [`signature::sign`].
+	pub fn sign(self, key_pair: &KeyPair) -> Result<Self, signature::Error> {
+		let signature = key_pair.sign(&self.signed_data())?;
+		Ok(Self { signature: Some(signature), ..self })
+	}
+
+	/// Verifies that the ping was signed by the owner of its public key.
+	///
+	/// # Errors
+	///
+	/// If the ping has no public key or signature, error kind is
+	/// [`signature::ErrorKind::MissingSignature`].
+	/// Otherwise see [`signature::verify`].
+	pub fn verify(&self) -> Result<(), signature::Error> {
+		let (Some(public_key), Some(sig)) = (&self.public_key, &self.signature) else {
+			return Err(signature::Error::new(
+				signature::ErrorKind::MissingSignature,
+				"ping is not signed",
+			));
+		};
+		signature::verify(public_key, &self.signed_data(), sig)
+	}
+
+	/// Returns the data covered by the signature.
+	fn signed_data(&self) -> Vec<u8> {
+		let timestamp = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+		let mut data = self.peer_id.as_bytes().to_vec();
+		data.extend(format!("|{}|{}|", self.peer_addr, self.peer_chat_addr).into_bytes());
+		data.extend(self.nonce);
+		data.extend(timestamp.to_be_bytes());
+		data.extend(self.nickname.as_deref().unwrap_or_default().as_bytes());
+		data
+	}
 }
 
 impl From<Ping> for Request {
@@ -101,19 +480,83 @@ impl From<Ping> for Request {
 	}
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct Pong {
 	pub peer_id: Uuid,
 	pub peer_chat_addr: SocketAddr,
+	#[serde(default)]
+	pub codec: Codec,
+	/// PEM-encoded RSA public key of the sender, if it has one.
+	#[serde(default)]
+	pub public_key: Option<String>,
+	/// Nonce of the ping this pong answers.
+	pub nonce: Nonce,
+	/// Signature over the sender's identity and the nonce made with the private key matching
+	/// `public_key`.
+	#[serde(default)]
+	pub signature: Option<Vec<u8>>,
+	#[serde(default)]
+	pub nickname: Option<String>,
 }
 
 impl Pong {
-	pub fn new<I, A>(peer_id: I, peer_chat_addr: A) -> Self
+	pub fn new<I, A>(
+		peer_id: I,
+		peer_chat_addr: A,
+		codec: Codec,
+		public_key: Option<String>,
+		nonce: Nonce,
+		nickname: Option<String>,
+	) -> Self
 	where
 		I: Into<Uuid>,
 		A: Into<SocketAddr>,
 	{
-		Self { peer_id: peer_id.into(), peer_chat_addr: peer_chat_addr.into() }
+		Self {
+			peer_id: peer_id.into(),
+			peer_chat_addr: peer_chat_addr.into(),
+			codec,
+			public_key,
+			nonce,
+			signature: None,
+			nickname,
+		}
+	}
+
+	/// Signs the pong with the key pair matching its public key.
+	///
+	/// # Errors
+	///
+	/// See [`signature::sign`].
+	pub fn sign(self, key_pair: &KeyPair) -> Result<Self, signature::Error> {
+		let signature = key_pair.sign(&self.signed_data())?;
+		Ok(Self { signature: Some(signature), ..self })
+	}
+
+	/// Verifies that the pong was signed by the owner of its public key.
+	///
+	/// # Errors
+	///
+	/// If the pong has no public key or signature, error kind is
+	/// [`signature::ErrorKind::MissingSignature`].
+	/// Otherwise see [`signature::verify`].
+	pub fn verify(&self) -> Result<(), signature::Error> {
+		let (Some(public_key), Some(sig)) = (&self.public_key, &self.signature) else {
+			return Err(signature::Error::new(
+				signature::ErrorKind::MissingSignature,
+				"pong is not signed",
+			));
+		};
+		signature::verify(public_key, &self.signed_data(), sig)
+	}
+
+	/// Returns the data covered by the signature.
+	fn signed_data(&self) -> Vec<u8> {
+		let mut data = self.peer_id.as_bytes().to_vec();
+		data.extend(format!("|{}|", self.peer_chat_addr).into_bytes());
+		data.extend(self.nonce);
+		data.extend(self.nickname.as_deref().unwrap_or_default().as_bytes());
+		data
 	}
 }
 
@@ -125,8 +568,16 @@ impl From<Pong> for Request {
 
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
 pub struct Message {
+	pub message_id: Uuid,
 	pub peer_id: Uuid,
 	pub text: String,
+	#[serde(default = "SystemTime::now")]
+	pub sent_at: SystemTime,
+	#[serde(default)]
+	pub to: Option<Uuid>,
+	/// Text encrypted with the connection's session key; `text` is empty when this is set.
+	#[serde(default)]
+	pub sealed: Option<Sealed>,
 }
 
 impl Message {
@@ -135,7 +586,54 @@ impl Message {
 		I: Into<Uuid>,
 		T: AsRef<str>,
 	{
-		Self { peer_id: peer_id.into(), text: text.as_ref().to_string() }
+		Self {
+			message_id: Uuid::now_v7(),
+			peer_id: peer_id.into(),
+			text: text.as_ref().to_string(),
+			sent_at: SystemTime::now(),
+			to: None,
+			sealed: None,
+		}
+	}
+
+	/// Creates a direct message addressed to a single peer.
+	pub fn direct<I, T>(peer_id: I, to: I, text: T) -> Self
+	where
+		I: Into<Uuid>,
+		T: AsRef<str>,
+	{
+		Self { to: Some(to.into()), ..Self::new(peer_id, text) }
+	}
+
+	/// Returns a copy of the message with the text encrypted with the session key.
+	///
+	/// # Errors
+	///
+	/// See [`Session::encrypt`].
+	pub fn seal(&self, session: &Session) -> Result<Self, session::Error> {
+		let sealed = session.encrypt(self.text.as_bytes())?;
+		Ok(Self { text: String::new(), sealed: Some(sealed), ..self.clone() })
+	}
+
+	/// Decrypts the text in place if the message is sealed.
+	///
+	/// # Errors
+	///
+	/// If the message is sealed but there is no session, or the decrypted text isn't valid UTF-8,
+	/// error kind is [`session::ErrorKind::DecryptionError`].
+	/// Otherwise see [`Session::decrypt`].
+	pub fn open(&mut self, session: Option<&Session>) -> Result<(), session::Error> {
+		let Some(sealed) = self.sealed.take() else { return Ok(()) };
+		let Some(session) = session else {
+			return Err(session::Error::new(
+				session::ErrorKind::DecryptionError,
+				"no session key was sent",
+			));
+		};
+		let text = session.decrypt(&sealed)?;
+		self.text = String::from_utf8(text)
+			.map_err(|e| session::Error::new(session::ErrorKind::DecryptionError, e))?;
+		Ok(())
 	}
 }
 
@@ -144,3 +642,419 @@ impl From<Message> for Request {
 		Self::Message(msg)
 	}
 }
+
+/// Session key for encrypting messages on this connection, wrapped with the recipient's public key.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct SessionKey {
+	pub peer_id: Uuid,
+	pub key: Vec<u8>,
+}
+
+impl SessionKey {
+	pub fn new<I>(peer_id: I, key: Vec<u8>) -> Self
+	where
+		I: Into<Uuid>,
+	{
+		Self { peer_id: peer_id.into(), key }
+	}
+}
+
+impl From<SessionKey> for Request {
+	fn from(key: SessionKey) -> Self {
+		Self::SessionKey(key)
+	}
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Disconnect {
+	pub peer_id: Uuid,
+}
+
+impl Disconnect {
+	pub fn new<I>(peer_id: I) -> Self
+	where
+		I: Into<Uuid>,
+	{
+		Self { peer_id: peer_id.into() }
+	}
+}
+
+impl From<Disconnect> for Request {
+	fn from(disconnect: Disconnect) -> Self {
+		Self::Disconnect(disconnect)
+	}
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Peers {
+	pub peers: Vec<Peer>,
+}
+
+impl Peers {
+	pub fn new<P>(peers: P) -> Self
+	where
+		P: IntoIterator<Item = Peer>,
+	{
+		Self { peers: peers.into_iter().collect() }
+	}
+}
+
+impl From<Peers> for Request {
+	fn from(peers: Peers) -> Self {
+		Self::Peers(peers)
+	}
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Ack {
+	pub message_id: Uuid,
+}
+
+impl Ack {
+	pub fn new<I>(message_id: I) -> Self
+	where
+		I: Into<Uuid>,
+	{
+		Self { message_id: message_id.into() }
+	}
+}
+
+impl From<Ack> for Request {
+	fn from(ack: Ack) -> Self {
+		Self::Ack(ack)
+	}
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct FileOffer {
+	pub transfer_id: Uuid,
+	pub name: String,
+	pub size: u64,
+	/// Hex-encoded SHA-256 checksum of the file.
+	pub sha256: String,
+}
+
+impl FileOffer {
+	pub fn new<I, N, S>(transfer_id: I, name: N, size: u64, sha256: S) -> Self
+	where
+		I: Into<Uuid>,
+		N: AsRef<str>,
+		S: AsRef<str>,
+	{
+		Self {
+			transfer_id: transfer_id.into(),
+			name: name.as_ref().to_string(),
+			size,
+			sha256: sha256.as_ref().to_string(),
+		}
+	}
+}
+
+impl From<FileOffer> for Request {
+	fn from(offer: FileOffer) -> Self {
+		Self::FileOffer(offer)
+	}
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct FileChunk {
+	pub transfer_id: Uuid,
+	pub seq: u64,
+	pub data: Vec<u8>,
+}
+
+impl FileChunk {
+	pub fn new<I>(transfer_id: I, seq: u64, data: Vec<u8>) -> Self
+	where
+		I: Into<Uuid>,
+	{
+		Self { transfer_id: transfer_id.into(), seq, data }
+	}
+}
+
+impl From<FileChunk> for Request {
+	fn from(chunk: FileChunk) -> Self {
+		Self::FileChunk(chunk)
+	}
+}
+
+/// Marks the end of a file transfer after its last chunk.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct FileDone {
+	pub transfer_id: Uuid,
+}
+
+impl FileDone {
+	pub fn new<I>(transfer_id: I) -> Self
+	where
+		I: Into<Uuid>,
+	{
+		Self { transfer_id: transfer_id.into() }
+	}
+}
+
+impl From<FileDone> for Request {
+	fn from(done: FileDone) -> Self {
+		Self::FileDone(done)
+	}
+}
+
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct ErrorReply {
+	pub code: ErrorCode,
+	pub message: String,
+}
+
+impl ErrorReply {
+	pub fn new<M>(code: ErrorCode, message: M) -> Self
+	where
+		M: AsRef<str>,
+	{
+		Self { code, message: message.as_ref().to_string() }
+	}
+}
+
+impl From<ErrorReply> for Request {
+	fn from(err: ErrorReply) -> Self {
+		Self::Error(err)
+	}
+}
+
+impl Display for ErrorReply {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{} ({})", self.message, self.code)
+	}
+}
+
+#[derive(
+	Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default, Serialize, Deserialize,
+)]
+pub enum ErrorCode {
+	#[default]
+	#[serde(rename = "internal")]
+	Internal,
+	#[serde(rename = "invalid_request")]
+	InvalidRequest,
+	#[serde(rename = "unsupported_version")]
+	UnsupportedVersion,
+	#[serde(rename = "rejected")]
+	Rejected,
+}
+
+impl Display for ErrorCode {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Internal => write!(f, "internal error"),
+			Self::InvalidRequest => write!(f, "invalid request"),
+			Self::UnsupportedVersion => write!(f, "unsupported version"),
+			Self::Rejected => write!(f, "rejected"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::crypto::keys::Algorithm;
+
+	#[test]
+	fn older_versions_are_unsupported() {
+		let unversioned = br#"{"method":"get_peers"}"#;
+		assert_eq!(decode(unversioned).unwrap_err().kind(), Unsupported);
+		let old = br#"{"version":1,"method":"get_peers"}"#;
+		assert_eq!(decode(old).unwrap_err().kind(), Unsupported);
+		let old = [BINCODE_MARKER, 1, 0, 0];
+		assert_eq!(decode(&old).unwrap_err().kind(), Unsupported);
+		let current = format!(r#"{{"version":{PROTOCOL_VERSION},"method":"get_peers"}}"#);
+		assert_eq!(decode(current.as_bytes()).unwrap().1, Request::GetPeers);
+	}
+
+	fn signed_pong(key_pair: &KeyPair) -> Pong {
+		let public_key = key_pair.public_pem().unwrap();
+		let chat_addr: SocketAddr = "127.0.0.1:7041".parse().unwrap();
+		let pong =
+			Pong::new(Uuid::new_v4(), chat_addr, Codec::Json, Some(public_key), [7; 16], None);
+		pong.sign(key_pair).unwrap()
+	}
+
+	#[test]
+	fn signed_pong_verifies() {
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		signed_pong(&key_pair).verify().unwrap();
+	}
+
+	#[test]
+	fn tampered_pong_fails_verification() {
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let pong = Pong { nonce: [8; 16], ..signed_pong(&key_pair) };
+		assert!(pong.verify().is_err());
+		let pong = Pong { signature: None, ..signed_pong(&key_pair) };
+		let e = pong.verify().unwrap_err();
+		assert_eq!(e.kind, signature::ErrorKind::MissingSignature);
+	}
+
+	#[test]
+	fn pong_signed_with_another_key_fails_verification() {
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let other = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let pong = Pong { public_key: Some(other.public_pem().unwrap()), ..signed_pong(&key_pair) };
+		assert!(pong.verify().is_err());
+	}
+
+	fn signed_ping(key_pair: &KeyPair) -> Ping {
+		let public_key = key_pair.public_pem().unwrap();
+		let (addr, chat_addr) = (([127, 0, 0, 1], 7040), ([127, 0, 0, 1], 7041));
+		let ping = Ping::new(Uuid::new_v4(), addr, chat_addr, Codec::Json, Some(public_key), None);
+		ping.sign(key_pair).unwrap()
+	}
+
+	#[test]
+	fn forged_ping_fails_verification() {
+		let victim = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let forger = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		signed_ping(&victim).verify().unwrap();
+
+		// Claiming another ID breaks the signature.
+		let ping = Ping { peer_id: Uuid::new_v4(), ..signed_ping(&forger) };
+		assert!(ping.verify().is_err());
+		// So does carrying the victim's key with a signature made by another one.
+		let ping = Ping { public_key: Some(victim.public_pem().unwrap()), ..signed_ping(&forger) };
+		assert!(ping.verify().is_err());
+		let ping = Ping { signature: None, ..signed_ping(&victim) };
+		assert_eq!(ping.verify().unwrap_err().kind, signature::ErrorKind::MissingSignature);
+	}
+
+	/// Returns one request of every kind, with optional fields set.
+	fn every_request() -> Vec<Request> {
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let (id, addr) = (Uuid::new_v4(), SocketAddr::from(([127, 0, 0, 1], 7040)));
+		let mut peer = Peer::new(id, addr, addr);
+		peer.last_seen = Some(SystemTime::now());
+		peer.nickname = Some("alice".to_owned());
+		peer.public_key = Some(key_pair.public_pem().unwrap().into_bytes());
+		peer.latency = Some(Duration::from_millis(12));
+		let msg = Message::direct(id, Uuid::now_v7(), "hello");
+		vec![
+			Request::Ping(signed_ping(&key_pair)),
+			Request::Pong(signed_pong(&key_pair)),
+			Request::Message(msg.clone()),
+			Request::Message(msg.seal(&Session::new().unwrap()).unwrap()),
+			Request::Disconnect(Disconnect::new(id)),
+			Request::GetPeers,
+			Request::Peers(Peers::new([peer])),
+			Request::Ack(Ack::new(msg.message_id)),
+			Request::FileOffer(FileOffer::new(id, "notes.txt", 3, "ab12")),
+			Request::FileChunk(FileChunk::new(id, 0, vec![1, 2, 3])),
+			Request::FileDone(FileDone::new(id)),
+			Request::SessionKey(SessionKey::new(id, vec![4, 5, 6])),
+			Request::Error(ErrorReply::new(ErrorCode::Rejected, "blocked")),
+		]
+	}
+
+	#[tokio::test]
+	async fn every_request_round_trips_in_every_codec() {
+		for codec in [Codec::Json, Codec::Bincode, Codec::MessagePack] {
+			for sent in every_request() {
+				let mut frame = Vec::new();
+				let id = frame.write_req(sent.clone(), codec).await.unwrap();
+				let read = frame.as_slice().read_req_with_id(MAX_REQUEST_LEN).await.unwrap();
+				assert_eq!(read, (Some(id), sent), "{codec:?}");
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn silent_peer_times_out() {
+		let (mut client, mut server) = tokio::io::duplex(64);
+		let timeout = Duration::from_millis(50);
+		let e = server.read_req_timeout(MAX_REQUEST_LEN, timeout).await.unwrap_err();
+		assert_eq!(e.kind(), TimedOut);
+
+		// The whole request must arrive in time, not just its header.
+		client.write_u32(100).await.unwrap();
+		let e = server.read_req_timeout(MAX_REQUEST_LEN, timeout).await.unwrap_err();
+		assert_eq!(e.kind(), TimedOut);
+	}
+
+	#[tokio::test]
+	async fn request_arriving_before_timeout_is_read() {
+		let (mut client, mut server) = tokio::io::duplex(64);
+		let writer = async {
+			time::sleep(Duration::from_millis(100)).await;
+			client.write_req(Request::GetPeers, Codec::Json).await.unwrap();
+		};
+		let timeout = Duration::from_millis(300);
+		let (_, read) = tokio::join!(writer, server.read_req_timeout(MAX_REQUEST_LEN, timeout));
+		assert_eq!(read.unwrap(), Request::GetPeers);
+	}
+
+	#[tokio::test]
+	async fn frame_split_across_segments_is_reassembled() {
+		let sent = Request::Message(Message::new(Uuid::new_v4(), "x".repeat(1000)));
+		let mut frame = Vec::new();
+		frame.write_req(sent.clone(), Codec::Json).await.unwrap();
+		frame.write_req(Request::GetPeers, Codec::Json).await.unwrap();
+
+		let (mut client, mut server) = tokio::io::duplex(4096);
+		let writer = async {
+			// Splits inside the header, inside the payload and right before the next frame.
+			for segment in [&frame[..2], &frame[2..10], &frame[10..500], &frame[500..]] {
+				client.write_all(segment).await.unwrap();
+				tokio::task::yield_now().await;
+			}
+		};
+		let reader = async {
+			let first = server.read_req(MAX_REQUEST_LEN).await.unwrap();
+			(first, server.read_req(MAX_REQUEST_LEN).await.unwrap())
+		};
+		let (_, read) = tokio::join!(writer, reader);
+		assert_eq!(read, (sent, Request::GetPeers));
+	}
+
+	#[tokio::test]
+	async fn frame_longer_than_maximum_is_rejected() {
+		let mut frame = Vec::new();
+		frame.write_req(Request::GetPeers, Codec::Json).await.unwrap();
+		let e = frame.as_slice().read_req(frame.len() - 5).await.unwrap_err();
+		assert_eq!(e.kind(), InvalidData);
+		frame.as_slice().read_req(frame.len() - 4).await.unwrap();
+	}
+
+	#[tokio::test]
+	async fn request_arriving_byte_by_byte_is_read_in_full() {
+		// A buffer of one byte makes every read return a single byte.
+		let (mut client, mut server) = tokio::io::duplex(1);
+		let ping = Ping::new(
+			Uuid::new_v4(),
+			([127, 0, 0, 1], 7040),
+			([127, 0, 0, 1], 7041),
+			Codec::Json,
+			None,
+			None,
+		);
+		let sent = Request::Ping(ping);
+		let (written, read) = tokio::join!(
+			client.write_req(sent.clone(), Codec::Json),
+			server.read_req(MAX_REQUEST_LEN)
+		);
+		written.unwrap();
+		assert_eq!(read.unwrap(), sent);
+	}
+
+	#[tokio::test]
+	async fn stream_ending_mid_request_fails() {
+		let mut frame = Vec::new();
+		frame.write_req(Request::GetPeers, Codec::Json).await.unwrap();
+		frame.truncate(frame.len() - 1);
+		let e = frame.as_slice().read_req(MAX_REQUEST_LEN).await.unwrap_err();
+		assert_eq!(e.kind(), UnexpectedEof);
+		let e = [0u8; 2].as_slice().read_req(MAX_REQUEST_LEN).await.unwrap_err();
+		assert_eq!(e.kind(), ConnectionAborted);
+	}
+
+	#[test]
+	fn newer_versions_are_unsupported() {
+		let new = format!(r#"{{"version":{},"method":"get_peers"}}"#, PROTOCOL_VERSION + 1);
+		assert_eq!(decode(new.as_bytes()).unwrap_err().kind(), Unsupported);
+	}
+}
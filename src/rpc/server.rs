@@ -1,45 +1,837 @@
+use crate::conf::net;
+use crate::crypto::keys::same_public_key;
+use crate::crypto::{signature, KeyPair, Uuid, UuidV4};
 use crate::peer::info::PeerInfo;
-use crate::peer::Status;
-use crate::rpc::request::{Ping, Pong, ReadRequest, Request, WriteRequest};
-use log::{error, warn};
-use std::process::exit;
+use crate::peer::{sanitize_nickname, Peer, Status};
+use crate::rpc::request::{
+	Codec, ErrorCode, ErrorReply, Nonce, Peers, Ping, Pong, ReadRequest, Request, WriteRequest,
+};
+use crate::rpc::tls::{MaybeTlsStream, Tls};
+use crate::rpc::{client, tls};
+use log::{error, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-use tokio::task;
-
-pub async fn listen(peer_info: &PeerInfo) {
-	let listener = TcpListener::bind(peer_info.addr).await.unwrap_or_else(|e| {
-		error!("failed to start server listener on {}: {e}", peer_info.addr);
-		exit(1);
+use std::time::{Duration, SystemTime};
+use std::{fmt, io, mem};
+use tokio::io::{AsyncWriteExt, BufStream};
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tokio::{task, time};
+
+/// Number of recent ping nonces remembered per peer.
+const NONCE_CACHE_SIZE: usize = 64;
+
+/// State shared between connection and background tasks.
+pub struct State {
+	/// Peer info as saved, with pending updates applied.
+	peer_info: PeerInfo,
+	public_key: String,
+	/// Key pair pongs are signed with, matching `public_key`.
+	key_pair: KeyPair,
+	/// Recently seen ping nonces per peer, oldest first.
+	nonces: HashMap<Uuid, VecDeque<Nonce>>,
+	/// Updates to peers not saved yet, oldest first.
+	pending: Vec<(Uuid, Update)>,
+	/// Whether known peers keep their stored addresses when they ping from new ones.
+	pin_addresses: bool,
+}
+
+/// Change to a peer that is recorded in memory right away and saved later.
+pub enum Update {
+	/// The peer pinged us at the time.
+	Ping(Box<Ping>, SystemTime),
+	/// The peer's status changed, along with when it was last seen if that is known.
+	Status(Status, Option<SystemTime>),
+	/// We pinged the peer, which answered with its addresses and the round-trip time if it did,
+	/// see [`client::record_answer`].
+	Probed(Option<(SocketAddr, SocketAddr, Duration)>),
+}
+
+impl State {
+	pub fn new(
+		peer_info: PeerInfo,
+		public_key: String,
+		key_pair: KeyPair,
+		conf: net::Conf,
+	) -> Self {
+		Self {
+			peer_info,
+			public_key,
+			key_pair,
+			nonces: HashMap::new(),
+			pending: Vec::new(),
+			pin_addresses: conf.pin_addresses,
+		}
+	}
+
+	/// Returns peer info as saved, with pending updates applied.
+	pub fn peer_info(&self) -> &PeerInfo {
+		&self.peer_info
+	}
+
+	/// Applies the update to peer info in memory and queues it to be saved.
+	pub fn update(&mut self, peer_id: Uuid, update: Update) -> bool {
+		let changed = apply_update(&mut self.peer_info, peer_id, &update, self.pin_addresses);
+		self.pending.push((peer_id, update));
+		changed
+	}
+
+	/// Remembers the nonce, returning `false` if it was already seen for the peer.
+	fn remember_nonce(&mut self, peer_id: Uuid, nonce: Nonce) -> bool {
+		let nonces = self.nonces.entry(peer_id).or_default();
+		if nonces.contains(&nonce) {
+			return false;
+		}
+		if nonces.len() == NONCE_CACHE_SIZE {
+			nonces.pop_front();
+		}
+		nonces.push_back(nonce);
+		true
+	}
+}
+
+/// Accepts connections until Ctrl-C is pressed.
+///
+/// On Ctrl-C, no new connections are accepted, connections in progress are closed after their
+/// current request, and peers that were still connected are saved as offline.
+///
+/// # Errors
+///
+/// See [`bind`].
+pub async fn listen(
+	peer_info: &PeerInfo,
+	conf: net::Conf,
+	public_key: String,
+	key_pair: &KeyPair,
+	tls: Option<Tls>,
+) -> Result<(), Error> {
+	let mut peer_info = peer_info.clone();
+	let listener = bind(&mut peer_info).await?;
+	let state = Arc::new(Mutex::new(State::new(peer_info, public_key, key_pair.clone(), conf)));
+	let sweeper = task::spawn(mark_idle_offline(Arc::clone(&state), conf.offline_after));
+	let (shutdown_tx, shutdown_rx) = watch::channel(false);
+	let saver = task::spawn(save_pending(Arc::clone(&state), conf.save_interval, shutdown_rx));
+	let connected = serve(listener, &state, conf, tls, signal::ctrl_c()).await;
+	sweeper.abort();
+	let _ = shutdown_tx.send(true);
+	let _ = saver.await;
+	save_final(&state, connected).await;
+	Ok(())
+}
+
+/// Binds the server listener to our address, saving the address it was bound to if the port
+/// was picked by the system.
+///
+/// # Errors
+///
+/// If our address is taken, error kind is [`ErrorKind::AddrInUse`].
+/// If the listener can't be bound to our address otherwise, error kind is
+/// [`ErrorKind::BindError`].
+pub async fn bind(peer_info: &mut PeerInfo) -> Result<TcpListener, Error> {
+	let listener = TcpListener::bind(peer_info.addr).await.map_err(|e| {
+		let kind = match e.kind() {
+			io::ErrorKind::AddrInUse => ErrorKind::AddrInUse,
+			_ => ErrorKind::BindError,
+		};
+		let message = format!("failed to start server listener on {}: {e}", peer_info.addr);
+		Error::new(kind, message)
+	})?;
+	match listener.local_addr() {
+		Ok(bound) => {
+			if bound != peer_info.addr {
+				let saved = peer_info.load_and_modify(|peer_info| peer_info.addr = bound);
+				if let Err(e) = saved.await {
+					error!("failed to save bound address {bound}: {e}");
+				}
+			}
+			info!("listening on {bound}");
+		}
+		Err(e) => warn!("failed to get bound address: {e}"),
+	}
+	Ok(listener)
+}
+
+/// Accepts connections on the listener until `stop` completes, then closes connections in
+/// progress after their current request.
+///
+/// Returns the IDs of peers that were still connected.
+pub async fn serve<F>(
+	listener: TcpListener,
+	state: &Arc<Mutex<State>>,
+	conf: net::Conf,
+	tls: Option<Tls>,
+	stop: F,
+) -> Vec<Uuid>
+where
+	F: Future,
+{
+	let (shutdown_tx, shutdown_rx) = watch::channel(false);
+	let mut tasks = JoinSet::new();
+	tokio::pin!(stop);
+	loop {
+		let (stream, addr) = tokio::select! {
+			accepted = listener.accept() => match accepted {
+				Ok(accepted) => accepted,
+				Err(_) => break,
+			},
+			_ = &mut stop => break,
+		};
+		while tasks.try_join_next().is_some() {}
+		let state_clone = Arc::clone(state);
+		let tls_clone = tls.clone();
+		let mut shutdown = shutdown_rx.clone();
+		tasks.spawn(async move {
+			if state_clone.lock().await.peer_info.blocked_addrs.contains(&addr.ip()) {
+				warn!("refused connection from blocked address {addr}");
+				return None;
+			}
+			let accepted = tokio::select! {
+				accepted = tls::accept(stream, tls_clone.as_ref()) => accepted,
+				_ = shutdown.changed() => return None,
+			};
+			match accepted {
+				Ok(stream) => {
+					let mut stream = BufStream::new(stream);
+					let connected = handle(&mut stream, addr, &state_clone, conf, shutdown).await;
+					// The last reply, such as an error that closed the connection, may be buffered.
+					let _ = stream.flush().await;
+					connected
+				}
+				Err(e) => {
+					warn!("failed to accept connection from peer at {addr}: {e}");
+					None
+				}
+			}
+		});
+	}
+
+	let _ = shutdown_tx.send(true);
+	let mut connected = Vec::new();
+	while let Some(result) = tasks.join_next().await {
+		if let Ok(Some(peer_id)) = result {
+			connected.push(peer_id);
+		}
+	}
+	connected
+}
+
+/// Marks the peers that were still connected as offline and saves every pending update, once
+/// [`save_pending`] has stopped.
+pub async fn save_final(state: &Arc<Mutex<State>>, connected: Vec<Uuid>) {
+	let mut state = state.lock().await;
+	for peer_id in connected {
+		state.update(peer_id, Update::Status(Status::Offline, Some(SystemTime::now())));
+	}
+	let (pending, pin_addresses) = (mem::take(&mut state.pending), state.pin_addresses);
+	let saved = state.peer_info.load_and_modify(|peer_info| {
+		for (peer_id, update) in &pending {
+			apply_update(peer_info, *peer_id, update, pin_addresses);
+		}
 	});
-	let peer_info = Arc::new(Mutex::new(peer_info.clone()));
-	while let Ok((mut stream, _)) = listener.accept().await {
-		let peer_info_clone = Arc::clone(&peer_info);
-		task::spawn(async move { handle(&mut stream, &peer_info_clone).await });
+	if let Err(e) = saved.await {
+		error!("failed to save peer info: {e}");
 	}
 }
 
-async fn handle(stream: &mut TcpStream, peer_info: &Arc<Mutex<PeerInfo>>) {
+/// Saves pending updates to peer info at most once per interval, until a shutdown is signaled.
+///
+/// Each save, or a reload when nothing is pending, replaces peer info in the state with what is
+/// on disk, so changes made by other commands, such as peers blocked since we started, are picked
+/// up. Updates queued meanwhile are applied on top. Updates that fail to save are kept and
+/// retried with the next ones. The disk is accessed without the state locked, so connections
+/// aren't held up by it.
+pub async fn save_pending(
+	state: Arc<Mutex<State>>,
+	interval: Duration,
+	mut shutdown: watch::Receiver<bool>,
+) {
+	let mut interval = time::interval(interval);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 	loop {
-		let Ok(Request::Ping(req)) = stream.read_req(1024).await else { continue };
-		handle_ping(stream, &req, peer_info).await;
+		tokio::select! {
+			_ = interval.tick() => {}
+			_ = shutdown.changed() => return,
+		}
+		let (pending, mut peer_info, pin_addresses) = {
+			let mut state = state.lock().await;
+			(mem::take(&mut state.pending), state.peer_info.clone(), state.pin_addresses)
+		};
+		let refreshed = if pending.is_empty() {
+			peer_info.reload().await
+		} else {
+			peer_info
+				.load_and_modify(|peer_info| {
+					for (peer_id, update) in &pending {
+						apply_update(peer_info, *peer_id, update, pin_addresses);
+					}
+				})
+				.await
+		};
+		let mut state = state.lock().await;
+		match refreshed {
+			Ok(()) => {
+				for (peer_id, update) in &state.pending {
+					apply_update(&mut peer_info, *peer_id, update, pin_addresses);
+				}
+				state.peer_info = peer_info;
+			}
+			Err(e) if pending.is_empty() => warn!("failed to reload peer info: {e}"),
+			Err(e) => {
+				error!("failed to save peer info, retrying later: {e}");
+				state.pending.splice(0..0, pending);
+			}
+		}
+	}
+}
+
+/// Applies the update to the peer, returning `true` if a ping moved it to new addresses.
+///
+/// Status updates to peers that aren't known are ignored.
+fn apply_update(
+	peer_info: &mut PeerInfo,
+	peer_id: Uuid,
+	update: &Update,
+	pin_addresses: bool,
+) -> bool {
+	match update {
+		Update::Ping(req, now) => record_ping(peer_info, req, *now, pin_addresses),
+		Update::Status(status, last_seen) => {
+			if let Some(peer) = peer_info.peers.get_mut(&peer_id) {
+				match status {
+					Status::Unreachable => peer.mark_unreachable(),
+					_ => peer.status = *status,
+				}
+				if last_seen.is_some() {
+					peer.last_seen = *last_seen;
+				}
+			}
+			false
+		}
+		Update::Probed(answer) => {
+			if let Some(peer) = peer_info.peers.get_mut(&peer_id) {
+				client::record_answer(peer, *answer);
+			}
+			false
+		}
+	}
+}
+
+/// Periodically marks online peers that haven't been seen for `offline_after` as offline, so
+/// peers that went away without disconnecting don't stay online forever.
+pub async fn mark_idle_offline(state: Arc<Mutex<State>>, offline_after: Duration) {
+	// A peer seen "in the future" because the clock moved backwards counts as just seen.
+	let is_idle = |peer: &Peer| {
+		peer.status == Status::Online
+			&& peer.last_seen.is_none_or(|seen| seen.elapsed().is_ok_and(|e| e > offline_after))
+	};
+	let mut interval = time::interval(offline_after);
+	interval.tick().await;
+	loop {
+		interval.tick().await;
+		let mut state = state.lock().await;
+		let idle: Vec<_> = state
+			.peer_info
+			.peers
+			.values()
+			.filter(|peer| is_idle(peer))
+			.map(|peer| peer.id)
+			.collect();
+		for peer_id in idle {
+			state.update(peer_id, Update::Status(Status::Offline, None));
+		}
+	}
+}
+
+/// Serves requests on the connection until it is closed.
+///
+/// Returns the ID of the connected peer if the connection was closed because of a shutdown.
+async fn handle(
+	stream: &mut BufStream<MaybeTlsStream>,
+	addr: SocketAddr,
+	state: &Arc<Mutex<State>>,
+	conf: net::Conf,
+	mut shutdown: watch::Receiver<bool>,
+) -> Option<Uuid> {
+	let mut peer_id = None;
+	let mut reply_codec = Codec::Json;
+	loop {
+		// Replies to the previous request go out before waiting for the next one, and if that
+		// fails, so does reading.
+		let _ = stream.flush().await;
+		let req = tokio::select! {
+			req = stream.read_req_with_id_timeout(conf.max_request_size, conf.read_timeout) => req,
+			_ = shutdown.changed() => return peer_id,
+		};
+		match req {
+			Ok((req_id, Request::Ping(req))) => {
+				let req_id = req_id.unwrap_or_else(|| UuidV4::new().into());
+				let pinged_as = req.peer_id;
+				reply_codec = conf.codec.negotiate(req.codec);
+				if !handle_ping(stream, addr, req_id, req, state, conf).await {
+					return None;
+				}
+				// Only set once a ping proved the connection comes from the peer.
+				peer_id = Some(pinged_as);
+			}
+			Ok((req_id, Request::GetPeers)) => {
+				let req_id = req_id.unwrap_or_else(|| UuidV4::new().into());
+				if conf.share_peers {
+					handle_get_peers(stream, req_id, peer_id, state, reply_codec).await;
+				} else {
+					let err = ErrorReply::new(ErrorCode::Rejected, "peer sharing is disabled");
+					let _ = stream.write_req_with_id(err, reply_codec, req_id).await;
+				}
+			}
+			Ok((_, Request::Disconnect(req))) if peer_id == Some(req.peer_id) => {
+				set_status(req.peer_id, Status::Offline, state).await;
+				return None;
+			}
+			Ok((_, Request::Disconnect(req))) => {
+				warn!("peer at {addr} sent disconnect for {} without pinging as it", req.peer_id);
+				return None;
+			}
+			// The peer hung up between requests, which says nothing about whether it is still up.
+			Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => return None,
+			Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+				warn!("peer at {addr} went idle: {e}");
+				if let Some(peer_id) = peer_id {
+					set_status(peer_id, Status::Offline, state).await;
+				}
+				return None;
+			}
+			Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+				warn!("peer at {addr} speaks unsupported protocol: {e}");
+				let err = ErrorReply::new(ErrorCode::UnsupportedVersion, e.to_string());
+				let _ = stream.write_req(err, Codec::Json).await;
+				return None;
+			}
+			Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+				warn!("peer at {addr} sent invalid request: {e}");
+				let err = ErrorReply::new(ErrorCode::InvalidRequest, e.to_string());
+				let _ = stream.write_req(err, Codec::Json).await;
+				return None;
+			}
+			Ok((req_id, _)) => {
+				warn!("peer at {addr} sent unexpected request");
+				let err = ErrorReply::new(ErrorCode::InvalidRequest, "unexpected request");
+				let req_id = req_id.unwrap_or_else(|| UuidV4::new().into());
+				let _ = stream.write_req_with_id(err, reply_codec, req_id).await;
+			}
+			Err(e) => {
+				warn!("connection to peer at {addr} failed: {e}");
+				if let Some(peer_id) = peer_id {
+					set_status(peer_id, Status::Unreachable, state).await;
+				}
+				return None;
+			}
+		}
+	}
+}
+
+/// Verifies the ping and answers it with a pong.
+///
+/// Returns `false` if the ping was rejected and the connection should be closed.
+async fn handle_ping(
+	stream: &mut BufStream<MaybeTlsStream>,
+	addr: SocketAddr,
+	req_id: Uuid,
+	req: Ping,
+	state: &Arc<Mutex<State>>,
+	conf: net::Conf,
+) -> bool {
+	if let Err(e) = req.verify() {
+		warn!("peer at {addr} sent ping that failed verification: {e}");
+		// The reason sent back doesn't include the details, which may come from openssl.
+		let reason = match e.kind {
+			signature::ErrorKind::MissingSignature => "ping is not signed",
+			_ => "ping signature is invalid",
+		};
+		let err = ErrorReply::new(ErrorCode::Rejected, reason);
+		let _ = stream.write_req_with_id(err, Codec::Json, req_id).await;
+		return false;
+	}
+	if let Some(cert_key) = stream.get_ref().peer_public_key() {
+		if !req.public_key.as_ref().is_some_and(|key| same_public_key(key, &cert_key)) {
+			warn!("peer at {addr} sent ping with a key that doesn't match its TLS certificate");
+			let err = ErrorReply::new(ErrorCode::Rejected, "public key doesn't match certificate");
+			let _ = stream.write_req_with_id(err, Codec::Json, req_id).await;
+			return false;
+		}
+	}
+	let now = SystemTime::now();
+	let skew = match req.timestamp.duration_since(now) {
+		Ok(ahead) => ahead,
+		Err(e) => e.duration(),
+	};
+	if skew > conf.max_clock_skew {
+		warn!("peer at {addr} sent stale ping ({}s off local time)", skew.as_secs());
+		let err = ErrorReply::new(ErrorCode::Rejected, "ping timestamp is too far off");
+		let _ = stream.write_req_with_id(err, Codec::Json, req_id).await;
+		return false;
+	}
+
+	let (req_codec, peer_addr) = (req.codec, req.peer_addr);
+	let (accepted, key_pair) = {
+		let mut state = state.lock().await;
+		(accept_ping(&mut state, addr, req, conf, now), state.key_pair.clone())
+	};
+	let pong = match accepted {
+		Ok(pong) => pong,
+		Err(reason) => {
+			let err = ErrorReply::new(ErrorCode::Rejected, reason);
+			let _ = stream.write_req_with_id(err, Codec::Json, req_id).await;
+			return false;
+		}
+	};
+	// Signed without the state locked, as signing with an RSA key takes a while.
+	let pong = match pong.sign(&key_pair) {
+		Ok(pong) => pong,
+		Err(e) => {
+			error!("failed to sign pong: {e}");
+			let err = ErrorReply::new(ErrorCode::Internal, "failed to sign pong");
+			let _ = stream.write_req_with_id(err, Codec::Json, req_id).await;
+			return false;
+		}
+	};
+
+	let codec = conf.codec.negotiate(req_codec);
+	if stream.write_req_with_id(pong, codec, req_id).await.is_err() {
+		warn!("peer that sent ping at {peer_addr} is unreachable");
 	}
+	true
 }
 
-async fn handle_ping(stream: &mut TcpStream, req: &Ping, peer_info: &Arc<Mutex<PeerInfo>>) {
-	let mut peer_info = peer_info.lock().await;
-	if stream.write_req(Pong::new(peer_info.id, peer_info.chat_addr)).await.is_err() {
-		warn!("peer that sent ping at {} is unreachable", req.peer_addr);
-		return;
+/// Checks the ping against peer info and, if it is accepted, records the peer to be saved and
+/// returns our pong.
+///
+/// Only quick checks are made, as the state is locked meanwhile. A rejected ping is logged and
+/// the reason to send back is returned.
+fn accept_ping(
+	state: &mut State,
+	addr: SocketAddr,
+	req: Ping,
+	conf: net::Conf,
+	now: SystemTime,
+) -> Result<Pong, &'static str> {
+	if req.peer_id == state.peer_info.id {
+		warn!("refused ping from ourselves at {addr}");
+		return Err("refusing to connect to self");
 	}
+	if state.peer_info.is_blocked(req.peer_id, addr.ip()) {
+		warn!("refused ping from blocked peer {} at {addr}", req.peer_id);
+		return Err("blocked");
+	}
+	let pinned_key = state.peer_info.peers.get(&req.peer_id).and_then(|p| p.public_key.as_deref());
+	if pinned_key.is_some() && pinned_key != req.public_key.as_ref().map(String::as_bytes) {
+		warn!("key changed for {}, possible MITM", req.peer_id);
+		return Err("public key doesn't match pinned key");
+	}
+	if !state.remember_nonce(req.peer_id, req.nonce) {
+		warn!("peer at {addr} replayed ping from {}", req.peer_id);
+		return Err("ping was replayed");
+	}
+
+	let pong = Pong::new(
+		state.peer_info.id,
+		state.peer_info.chat_addr,
+		conf.codec,
+		Some(state.public_key.clone()),
+		req.nonce,
+		state.peer_info.nickname.clone(),
+	);
+	let (peer_id, peer_addr, peer_chat_addr) = (req.peer_id, req.peer_addr, req.peer_chat_addr);
+	if state.update(peer_id, Update::Ping(Box::new(req), now)) {
+		info!("peer {peer_id} moved to {peer_addr} (chat {peer_chat_addr})");
+	}
+	Ok(pong)
+}
 
+/// Records the peer that sent the ping as online, returning `true` if its addresses changed.
+fn record_ping(peer_info: &mut PeerInfo, req: &Ping, now: SystemTime, pin_addresses: bool) -> bool {
 	let peer = peer_info.peer_or_insert(req.peer_id, req.peer_addr, req.peer_chat_addr);
+	let moved = !pin_addresses && peer.set_addrs(req.peer_addr, req.peer_chat_addr);
 	peer.status = Status::Online;
-	peer.last_seen = Some(SystemTime::now());
+	peer.last_seen = Some(now);
+	peer.codec = req.codec;
+	peer.nickname = req.nickname.as_deref().and_then(sanitize_nickname);
+	if let Some(key) = &req.public_key {
+		peer.public_key = Some(key.clone().into_bytes());
+	}
+	moved
+}
 
-	if let Err(e) = peer_info.save().await {
-		error!("failed to save peer info: {e}");
+async fn handle_get_peers(
+	stream: &mut BufStream<MaybeTlsStream>,
+	req_id: Uuid,
+	peer_id: Option<Uuid>,
+	state: &Arc<Mutex<State>>,
+	codec: Codec,
+) {
+	let peers = {
+		let state = state.lock().await;
+		let peers = state.peer_info.peers.values().filter(|p| Some(p.id) != peer_id);
+		Peers::new(peers.map(|p| Peer {
+			alias: None,
+			failed_attempts: 0,
+			last_attempt: None,
+			latency: None,
+			host: None,
+			..p.clone()
+		}))
+	};
+	if let Err(e) = stream.write_req_with_id(peers, codec, req_id).await {
+		warn!("failed to send known peers: {e}");
+	}
+}
+
+/// Sets the status of a known peer whose connection closed.
+async fn set_status(peer_id: Uuid, status: Status, state: &Arc<Mutex<State>>) {
+	let mut state = state.lock().await;
+	if state.peer_info.peers.contains_key(&peer_id) {
+		state.update(peer_id, Update::Status(status, Some(SystemTime::now())));
+	}
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	BindError,
+	AddrInUse,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::conf::Conf;
+	use crate::crypto::keys::Algorithm;
+	use crate::rpc::request::{ReadRequest, WriteRequest, MAX_REQUEST_LEN};
+	use tempfile::TempDir;
+	use tokio::net::TcpStream;
+
+	/// Returns a state for a new peer with an Ed25519 key.
+	async fn state() -> Arc<Mutex<State>> {
+		let peer_info = peer_info_with(UuidV4::new().into(), Status::Unknown).await;
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let public_key = key_pair.public_pem().unwrap();
+		Arc::new(Mutex::new(State::new(peer_info, public_key, key_pair, net_conf())))
+	}
+
+	/// Returns a signed ping from a new peer.
+	fn ping() -> Ping {
+		let key_pair = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let addr = SocketAddr::from(([127, 0, 0, 1], 7050));
+		let ping =
+			Ping::new(UuidV4::new(), addr, addr, Codec::Json, key_pair.public_pem().ok(), None);
+		ping.sign(&key_pair).unwrap()
+	}
+
+	/// Returns both ends of a local connection, the server end buffered as [`handle`] takes it.
+	async fn connected() -> (TcpStream, BufStream<MaybeTlsStream>, SocketAddr) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+		let (server, addr) = listener.accept().await.unwrap();
+		(client, BufStream::new(MaybeTlsStream::Plain(server)), addr)
+	}
+
+	#[tokio::test]
+	async fn handle_returns_when_peer_hangs_up() {
+		let state = state().await;
+		let (_shutdown_tx, shutdown) = watch::channel(false);
+
+		// Hanging up before sending anything.
+		let (client, mut server, addr) = connected().await;
+		drop(client);
+		let handled = handle(&mut server, addr, &state, net_conf(), shutdown.clone());
+		assert_eq!(time::timeout(Duration::from_secs(1), handled).await.unwrap(), None);
+
+		// Hanging up after a ping leaves the peer online, while breaking off partway through a
+		// request marks it unreachable.
+		assert_eq!(status_after_ping(&state, &shutdown, &[]).await, Status::Online);
+		assert_eq!(
+			status_after_ping(&state, &shutdown, &[0, 0, 0, 16, 1, 2]).await,
+			Status::Unreachable
+		);
+	}
+
+	/// Pings the server, writes the bytes and hangs up, returning the status of the peer once the
+	/// connection is handled.
+	async fn status_after_ping(
+		state: &Arc<Mutex<State>>,
+		shutdown: &watch::Receiver<bool>,
+		then: &[u8],
+	) -> Status {
+		let (mut client, mut server, addr) = connected().await;
+		let ping = ping();
+		let peer_id = ping.peer_id;
+		let client = async move {
+			client.write_req(ping, Codec::Json).await.unwrap();
+			assert!(matches!(client.read_req(MAX_REQUEST_LEN).await, Ok(Request::Pong(_))));
+			client.write_all(then).await.unwrap();
+		};
+		let handled = async {
+			let handled = handle(&mut server, addr, state, net_conf(), shutdown.clone());
+			time::timeout(Duration::from_secs(1), handled).await.unwrap()
+		};
+		assert_eq!(tokio::join!(client, handled).1, None);
+		let status = state.lock().await.peer_info().peers[&peer_id].status;
+		status
+	}
+
+	fn net_conf() -> net::Conf {
+		let dir = TempDir::new().unwrap();
+		let path = dir.path().join("config.toml");
+		std::fs::write(&path, Conf::default_toml()).unwrap();
+		Conf::load(&path).unwrap().net
+	}
+
+	async fn peer_info_with(peer_id: Uuid, status: Status) -> PeerInfo {
+		let addr = SocketAddr::from(([127, 0, 0, 1], 7040));
+		let chat_addr = SocketAddr::from(([127, 0, 0, 1], 7041));
+		let mut peer_info = PeerInfo::new(UuidV4::new(), addr, chat_addr, "peer_info.json").await;
+		peer_info.peer_or_insert(peer_id, addr, chat_addr).status = status;
+		peer_info
+	}
+
+	#[tokio::test]
+	async fn broken_connection_after_disconnect_stays_offline() {
+		let peer_id = UuidV4::new().into();
+		let mut peer_info = peer_info_with(peer_id, Status::Online).await;
+		let now = Some(SystemTime::now());
+		apply_update(&mut peer_info, peer_id, &Update::Status(Status::Offline, now), false);
+		apply_update(&mut peer_info, peer_id, &Update::Status(Status::Unreachable, now), false);
+		assert_eq!(peer_info.peers[&peer_id].status, Status::Offline);
+	}
+
+	#[tokio::test]
+	async fn broken_connection_marks_online_peer_unreachable() {
+		let peer_id = UuidV4::new().into();
+		let mut peer_info = peer_info_with(peer_id, Status::Online).await;
+		let now = Some(SystemTime::now());
+		apply_update(&mut peer_info, peer_id, &Update::Status(Status::Unreachable, now), false);
+		assert_eq!(peer_info.peers[&peer_id].status, Status::Unreachable);
+	}
+
+	#[tokio::test]
+	async fn ping_signed_with_another_key_than_pinned_is_refused() {
+		let (victim, forger): (Uuid, Uuid) = (UuidV4::new().into(), UuidV4::new().into());
+		let mut peer_info = peer_info_with(victim, Status::Online).await;
+		let victim_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let pinned = victim_key.public_pem().unwrap();
+		peer_info.peers.get_mut(&victim).unwrap().public_key = Some(pinned.into_bytes());
+		let our_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let public_key = our_key.public_pem().unwrap();
+		let mut state = State::new(peer_info, public_key, our_key, net_conf());
+
+		// The forger signs properly with its own key, but claims the victim's ID.
+		let forger_key = KeyPair::generate(Algorithm::Ed25519, 0).unwrap();
+		let addr = SocketAddr::from(([192, 0, 2, 1], 7040));
+		let ping = Ping::new(victim, addr, addr, Codec::Json, forger_key.public_pem().ok(), None);
+		let ping = ping.sign(&forger_key).unwrap();
+		ping.verify().unwrap();
+		let refused = accept_ping(&mut state, addr, ping, net_conf(), SystemTime::now());
+		assert_eq!(refused.unwrap_err(), "public key doesn't match pinned key");
+		assert_ne!(state.peer_info().peers[&victim].addr, addr);
+
+		let ping = Ping::new(forger, addr, addr, Codec::Json, forger_key.public_pem().ok(), None);
+		let ping = ping.sign(&forger_key).unwrap();
+		accept_ping(&mut state, addr, ping, net_conf(), SystemTime::now()).unwrap();
+	}
+
+	#[tokio::test]
+	async fn ping_from_new_address_updates_peer() {
+		let ping = ping();
+		let mut peer_info = peer_info_with(ping.peer_id, Status::Offline).await;
+		let now = SystemTime::now();
+		let moved = Ping {
+			peer_addr: SocketAddr::from(([192, 0, 2, 1], 7040)),
+			peer_chat_addr: SocketAddr::from(([192, 0, 2, 1], 7041)),
+			..ping
+		};
+
+		assert!(record_ping(&mut peer_info, &moved, now, false));
+		let peer = &peer_info.peers[&moved.peer_id];
+		assert_eq!((peer.addr, peer.chat_addr), (moved.peer_addr, moved.peer_chat_addr));
+		assert_eq!(peer.status, Status::Online);
+		assert!(!record_ping(&mut peer_info, &moved, now, false));
+	}
+
+	#[tokio::test]
+	async fn pinned_addresses_are_kept() {
+		let ping = ping();
+		let mut peer_info = peer_info_with(ping.peer_id, Status::Offline).await;
+		let known = peer_info.peers[&ping.peer_id].clone();
+		let moved = Ping { peer_addr: SocketAddr::from(([192, 0, 2, 1], 7040)), ..ping };
+
+		assert!(!record_ping(&mut peer_info, &moved, SystemTime::now(), true));
+		assert_eq!(peer_info.peers[&moved.peer_id].addr, known.addr);
+	}
+
+	#[tokio::test]
+	async fn concurrent_pings_are_not_held_up_by_a_stalled_peer() {
+		const PEERS: usize = 50;
+		let state = state().await;
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+		let server = task::spawn({
+			let state = Arc::clone(&state);
+			async move { serve(listener, &state, net_conf(), None, stop_rx).await }
+		});
+
+		// A peer that sends half a request header and then nothing.
+		let mut stalled = TcpStream::connect(addr).await.unwrap();
+		stalled.write_all(&[0, 0]).await.unwrap();
+		let pings = (0..PEERS).map(|_| async move {
+			let mut client = TcpStream::connect(addr).await.unwrap();
+			client.write_req(ping(), Codec::Json).await.unwrap();
+			let answered = matches!(client.read_req(MAX_REQUEST_LEN).await, Ok(Request::Pong(_)));
+			// Kept open so the peer stays online.
+			(answered, client)
+		});
+		let answered = time::timeout(Duration::from_secs(10), futures::future::join_all(pings));
+		let (answered, _clients): (Vec<_>, Vec<_>) = answered.await.unwrap().into_iter().unzip();
+		assert!(answered.into_iter().all(|answered| answered));
+		let online = state
+			.lock()
+			.await
+			.peer_info()
+			.peers
+			.values()
+			.filter(|peer| peer.status == Status::Online)
+			.count();
+		assert_eq!(online, PEERS);
+
+		drop(stalled);
+		let _ = stop_tx.send(());
+		time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
 	}
 }
@@ -0,0 +1,383 @@
+use crate::crypto::KeyPair;
+use crate::rpc::request::{Codec, ErrorCode, ErrorReply, WriteRequest};
+use openssl::ssl::{
+	ErrorCode as SslErrorCode, Ssl, SslAcceptor, SslConnector, SslMethod, SslStream, SslVerifyMode,
+};
+use openssl::x509::X509;
+use std::fmt::{Debug, Display, Formatter};
+use std::future::poll_fn;
+use std::io::ErrorKind::{ConnectionAborted, InvalidData, WouldBlock};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::{fmt, io};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// First byte of a TLS handshake record.
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+/// TLS configuration built from our key pair and self-signed certificate.
+///
+/// Certificates are self-signed, so the handshake accepts any certificate and the caller
+/// compares [`TlsStream::peer_public_key`] with the key it has pinned for the peer.
+#[derive(Clone)]
+pub struct Tls {
+	acceptor: SslAcceptor,
+	connector: SslConnector,
+}
+
+impl Tls {
+	/// Creates a TLS configuration from the key pair and its PEM-encoded certificate.
+	///
+	/// # Errors
+	///
+	/// If the certificate can't be parsed, error kind is [`ErrorKind::InvalidCertificate`].
+	/// If the certificate wasn't made for the key pair, error kind is [`ErrorKind::KeyMismatch`].
+	pub fn new(key_pair: &KeyPair, cert: &[u8]) -> Result<Self, Error> {
+		let cert =
+			X509::from_pem(cert).map_err(|e| Error::new(ErrorKind::InvalidCertificate, e))?;
+
+		let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+			.map_err(|e| Error::new(ErrorKind::InvalidCertificate, e))?;
+		acceptor
+			.set_private_key(key_pair.pkey())
+			.and_then(|_| acceptor.set_certificate(&cert))
+			.map_err(|e| Error::new(ErrorKind::InvalidCertificate, e))?;
+		acceptor.check_private_key().map_err(|e| Error::new(ErrorKind::KeyMismatch, e))?;
+		acceptor.set_verify_callback(
+			SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+			|_, _| true,
+		);
+
+		let mut connector = SslConnector::builder(SslMethod::tls())
+			.map_err(|e| Error::new(ErrorKind::InvalidCertificate, e))?;
+		connector
+			.set_private_key(key_pair.pkey())
+			.and_then(|_| connector.set_certificate(&cert))
+			.map_err(|e| Error::new(ErrorKind::InvalidCertificate, e))?;
+		connector.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+
+		Ok(Self { acceptor: acceptor.build(), connector: connector.build() })
+	}
+
+	/// Performs the server side of the TLS handshake.
+	///
+	/// # Errors
+	///
+	/// If the handshake fails, error kind is [`ErrorKind::HandshakeError`].
+	pub async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, Error>
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		let ssl = Ssl::new(self.acceptor.context())
+			.map_err(|e| Error::new(ErrorKind::HandshakeError, e))?;
+		TlsStream::handshake(ssl, stream, |s| s.accept()).await
+	}
+
+	/// Performs the client side of the TLS handshake.
+	///
+	/// # Errors
+	///
+	/// If the handshake fails, error kind is [`ErrorKind::HandshakeError`].
+	pub async fn connect<S>(&self, stream: S) -> Result<TlsStream<S>, Error>
+	where
+		S: AsyncRead + AsyncWrite + Unpin,
+	{
+		let ssl = self
+			.connector
+			.configure()
+			.map(|c| c.verify_hostname(false).use_server_name_indication(false))
+			.and_then(|c| c.into_ssl(""))
+			.map_err(|e| Error::new(ErrorKind::HandshakeError, e))?;
+		TlsStream::handshake(ssl, stream, |s| s.connect()).await
+	}
+}
+
+/// Async stream encrypted with TLS.
+pub struct TlsStream<S> {
+	inner: SslStream<Bridge<S>>,
+}
+
+impl<S> TlsStream<S>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	async fn handshake<F>(ssl: Ssl, stream: S, mut f: F) -> Result<Self, Error>
+	where
+		F: FnMut(&mut SslStream<Bridge<S>>) -> Result<(), openssl::ssl::Error>,
+	{
+		let bridge = Bridge { stream, waker: None };
+		let inner =
+			SslStream::new(ssl, bridge).map_err(|e| Error::new(ErrorKind::HandshakeError, e))?;
+		let mut tls = Self { inner };
+		poll_fn(|cx| match tls.with_context(cx, &mut f) {
+			Ok(()) => Poll::Ready(Ok(())),
+			Err(e) if would_block(&e) => Poll::Pending,
+			Err(e) => Poll::Ready(Err(Error::new(ErrorKind::HandshakeError, e.to_string()))),
+		})
+		.await?;
+		Ok(tls)
+	}
+
+	/// Returns the PEM-encoded public key of the peer's certificate.
+	pub fn peer_public_key(&self) -> Option<Vec<u8>> {
+		self.inner.ssl().peer_certificate()?.public_key().ok()?.public_key_to_pem().ok()
+	}
+
+	/// Runs the operation with the task context available to the underlying stream.
+	fn with_context<F, R>(&mut self, cx: &mut Context<'_>, f: F) -> R
+	where
+		F: FnOnce(&mut SslStream<Bridge<S>>) -> R,
+	{
+		self.inner.get_mut().waker = Some(cx.waker().clone());
+		let result = f(&mut self.inner);
+		self.inner.get_mut().waker = None;
+		result
+	}
+}
+
+impl<S> AsyncRead for TlsStream<S>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.with_context(cx, |s| s.ssl_read(buf.initialize_unfilled())) {
+			Ok(len) => {
+				buf.advance(len);
+				Poll::Ready(Ok(()))
+			}
+			Err(e) if e.code() == SslErrorCode::ZERO_RETURN => Poll::Ready(Ok(())),
+			Err(e) if would_block(&e) => Poll::Pending,
+			Err(e) => Poll::Ready(Err(into_io_error(e))),
+		}
+	}
+}
+
+impl<S> AsyncWrite for TlsStream<S>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.with_context(cx, |s| s.ssl_write(buf)) {
+			Ok(len) => Poll::Ready(Ok(len)),
+			Err(e) if would_block(&e) => Poll::Pending,
+			Err(e) => Poll::Ready(Err(into_io_error(e))),
+		}
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.with_context(cx, |s| s.get_mut().flush()) {
+			Err(e) if e.kind() == WouldBlock => Poll::Pending,
+			result => Poll::Ready(result),
+		}
+	}
+
+	fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.with_context(cx, |s| s.shutdown()) {
+			Ok(_) => {}
+			Err(e) if e.code() == SslErrorCode::ZERO_RETURN => {}
+			Err(e) if would_block(&e) => return Poll::Pending,
+			Err(e) => return Poll::Ready(Err(into_io_error(e))),
+		}
+		Pin::new(&mut self.inner.get_mut().stream).poll_shutdown(cx)
+	}
+}
+
+/// TCP stream that is encrypted with TLS if it is enabled.
+pub enum MaybeTlsStream {
+	Plain(TcpStream),
+	Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl MaybeTlsStream {
+	/// Returns the PEM-encoded public key of the peer's certificate, or `None` for plain streams.
+	pub fn peer_public_key(&self) -> Option<Vec<u8>> {
+		match self {
+			Self::Plain(_) => None,
+			Self::Tls(stream) => stream.peer_public_key(),
+		}
+	}
+}
+
+impl AsyncRead for MaybeTlsStream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+			Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for MaybeTlsStream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+			Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+			Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+			Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+		}
+	}
+}
+
+/// Connects to the address, performing the TLS handshake if TLS is enabled.
+///
+/// # Errors
+///
+/// This function returns [`io::Error`] if the connection can't be established.
+/// If the TLS handshake fails, e.g. because the peer has TLS disabled, error kind is
+/// [`ConnectionAborted`].
+pub async fn connect<A>(addr: A, tls: Option<&Tls>) -> io::Result<MaybeTlsStream>
+where
+	A: Into<SocketAddr>,
+{
+	let stream = TcpStream::connect(addr.into()).await?;
+	let Some(tls) = tls else { return Ok(MaybeTlsStream::Plain(stream)) };
+	match tls.connect(stream).await {
+		Ok(stream) => Ok(MaybeTlsStream::Tls(Box::new(stream))),
+		Err(e) => Err(io::Error::new(
+			ConnectionAborted,
+			format!("TLS handshake failed, the peer may have TLS disabled: {e}"),
+		)),
+	}
+}
+
+/// Wraps an accepted connection, performing the TLS handshake if TLS is enabled.
+///
+/// A peer that starts talking without a handshake is told that TLS is required in plain text,
+/// so it gets an error reply instead of a broken connection.
+///
+/// # Errors
+///
+/// If the peer didn't start a TLS handshake, error kind is [`InvalidData`].
+/// If the TLS handshake fails, error kind is [`ConnectionAborted`].
+pub async fn accept(mut stream: TcpStream, tls: Option<&Tls>) -> io::Result<MaybeTlsStream> {
+	let Some(tls) = tls else { return Ok(MaybeTlsStream::Plain(stream)) };
+	let mut first = [0; 1];
+	if stream.peek(&mut first).await? == 1 && first[0] != HANDSHAKE_RECORD {
+		let err = ErrorReply::new(ErrorCode::Rejected, "TLS is required");
+		let _ = stream.write_req(err, Codec::Json).await;
+		return Err(io::Error::new(InvalidData, "peer didn't start TLS, it may have TLS disabled"));
+	}
+	match tls.accept(stream).await {
+		Ok(stream) => Ok(MaybeTlsStream::Tls(Box::new(stream))),
+		Err(e) => Err(io::Error::new(ConnectionAborted, format!("TLS handshake failed: {e}"))),
+	}
+}
+
+/// Adapts an async stream to the blocking [`Read`] and [`Write`] that [`SslStream`] expects,
+/// reporting [`WouldBlock`] when the stream isn't ready.
+struct Bridge<S> {
+	stream: S,
+	/// Waker of the task currently polling the TLS stream.
+	waker: Option<Waker>,
+}
+
+impl<S> Bridge<S> {
+	fn poll<F, R>(&mut self, f: F) -> io::Result<R>
+	where
+		F: FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<R>>,
+		S: Unpin,
+	{
+		let waker = self.waker.clone().ok_or_else(|| io::Error::from(WouldBlock))?;
+		let mut cx = Context::from_waker(&waker);
+		match f(Pin::new(&mut self.stream), &mut cx) {
+			Poll::Ready(result) => result,
+			Poll::Pending => Err(io::Error::from(WouldBlock)),
+		}
+	}
+}
+
+impl<S> Read for Bridge<S>
+where
+	S: AsyncRead + Unpin,
+{
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let mut buf = ReadBuf::new(buf);
+		self.poll(|stream, cx| stream.poll_read(cx, &mut buf))?;
+		Ok(buf.filled().len())
+	}
+}
+
+impl<S> Write for Bridge<S>
+where
+	S: AsyncWrite + Unpin,
+{
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.poll(|stream, cx| stream.poll_write(cx, buf))
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.poll(|stream, cx| stream.poll_flush(cx))
+	}
+}
+
+/// Returns whether the TLS operation is waiting for the underlying stream.
+fn would_block(e: &openssl::ssl::Error) -> bool {
+	(e.code() == SslErrorCode::WANT_READ || e.code() == SslErrorCode::WANT_WRITE)
+		&& e.io_error().is_some_and(|e| e.kind() == WouldBlock)
+}
+
+fn into_io_error(e: openssl::ssl::Error) -> io::Error {
+	e.into_io_error().unwrap_or_else(io::Error::other)
+}
+
+#[derive(Debug)]
+pub struct Error {
+	pub kind: ErrorKind,
+	pub err: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl Error {
+	pub fn new<E>(kind: ErrorKind, err: E) -> Self
+	where
+		E: Into<Box<dyn std::error::Error + Send + Sync>>,
+	{
+		Self { kind, err: err.into() }
+	}
+}
+
+impl Display for Error {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.err)
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ErrorKind {
+	#[default]
+	InvalidCertificate,
+	KeyMismatch,
+	HandshakeError,
+}
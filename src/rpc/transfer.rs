@@ -0,0 +1,236 @@
+use crate::peer::sanitize_text;
+use crate::rpc::request::{FileChunk, FileOffer};
+use openssl::sha::Sha256;
+use std::io;
+use std::io::ErrorKind::InvalidData;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Default size of a file chunk in bytes.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Maximum size of a file chunk in bytes, which keeps JSON-encoded chunks well below the maximum
+/// request length.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Incoming file transfer.
+///
+/// Chunks are written to a `.part` file named after the transfer next to the destination, which
+/// is only renamed to the destination once every chunk has been received in order and the
+/// checksum matches. Existing files are never overwritten, see [`Transfer::finish`].
+pub struct Transfer {
+	pub name: String,
+	pub size: u64,
+	pub received: u64,
+	next_seq: u64,
+	sha256: String,
+	hasher: Sha256,
+	file: File,
+	dir: PathBuf,
+	part_path: PathBuf,
+}
+
+impl Transfer {
+	/// Starts receiving an offered file into the directory.
+	///
+	/// Only the file name of the offered name is used, so a peer can't write outside the
+	/// directory, and control characters are stripped from it.
+	///
+	/// # Errors
+	///
+	/// If the offered name has no file name, error kind is [`InvalidData`].
+	/// This function returns [`io::Error`] if the directory or the part file can't be created,
+	/// including when a part file for the same transfer already exists.
+	pub async fn start<P>(offer: &FileOffer, dir: P) -> io::Result<Self>
+	where
+		P: AsRef<Path>,
+	{
+		let name = Path::new(&offer.name)
+			.file_name()
+			.and_then(|n| n.to_str())
+			.map(|n| sanitize_text(n).trim().to_owned())
+			.filter(|n| !n.is_empty() && n != "." && n != "..")
+			.ok_or_else(|| io::Error::new(InvalidData, "invalid file name"))?;
+		fs::create_dir_all(&dir).await?;
+		let dir = dir.as_ref().to_path_buf();
+		let part_path = dir.join(format!("{name}.{}.part", offer.transfer_id));
+		let file = OpenOptions::new().write(true).create_new(true).open(&part_path).await?;
+		Ok(Self {
+			name,
+			size: offer.size,
+			received: 0,
+			next_seq: 0,
+			sha256: offer.sha256.to_lowercase(),
+			hasher: Sha256::new(),
+			file,
+			dir,
+			part_path,
+		})
+	}
+
+	/// Writes the chunk to the part file.
+	///
+	/// # Errors
+	///
+	/// If the chunk is out of order or the transfer exceeds the offered size, error kind is
+	/// [`InvalidData`].
+	/// This function returns [`io::Error`] if writing to the part file fails.
+	pub async fn write_chunk(&mut self, chunk: &FileChunk) -> io::Result<()> {
+		if chunk.seq != self.next_seq {
+			return Err(io::Error::new(
+				InvalidData,
+				format!("expected chunk {} but got {}", self.next_seq, chunk.seq),
+			));
+		}
+		self.received += chunk.data.len() as u64;
+		if self.received > self.size {
+			return Err(io::Error::new(InvalidData, "received more data than offered"));
+		}
+		self.file.write_all(&chunk.data).await?;
+		self.hasher.update(&chunk.data);
+		self.next_seq += 1;
+		Ok(())
+	}
+
+	/// Returns transfer progress in percent.
+	pub fn progress(&self) -> u64 {
+		(self.received * 100).checked_div(self.size).unwrap_or(100)
+	}
+
+	/// Verifies the received file and moves the part file to its destination, returning the
+	/// destination path.
+	///
+	/// The destination is the offered name in the directory, or if a file by that name exists,
+	/// the first free name numbered like `name (1).ext`.
+	///
+	/// # Errors
+	///
+	/// If not all offered data was received or the checksum doesn't match, error kind is
+	/// [`InvalidData`] and the part file is discarded.
+	/// This function returns [`io::Error`] if flushing or renaming the part file fails.
+	pub async fn finish(mut self) -> io::Result<PathBuf> {
+		if self.received != self.size {
+			self.abort().await;
+			return Err(io::Error::new(InvalidData, "transfer ended before all data was received"));
+		}
+		if to_hex(&self.hasher.clone().finish()) != self.sha256 {
+			self.abort().await;
+			return Err(io::Error::new(InvalidData, "checksum mismatch"));
+		}
+		self.file.flush().await?;
+		// Claims the name by creating the file, so a file created meanwhile isn't replaced.
+		for n in 0.. {
+			let path = self.dir.join(numbered(&self.name, n));
+			match OpenOptions::new().write(true).create_new(true).open(&path).await {
+				Ok(_) => {
+					if let Err(e) = fs::rename(&self.part_path, &path).await {
+						let _ = fs::remove_file(&path).await;
+						return Err(e);
+					}
+					return Ok(path);
+				}
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+				Err(e) => return Err(e),
+			}
+		}
+		unreachable!("ran out of numbers for {}", self.name)
+	}
+
+	/// Discards the part file.
+	pub async fn abort(self) {
+		drop(self.file);
+		let _ = fs::remove_file(&self.part_path).await;
+	}
+}
+
+/// Computes the hex-encoded SHA-256 checksum of the file.
+///
+/// # Errors
+///
+/// This function returns [`io::Error`] if reading the file fails.
+pub async fn sha256<P>(path: P) -> io::Result<String>
+where
+	P: AsRef<Path>,
+{
+	let mut file = File::open(path).await?;
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0; DEFAULT_CHUNK_SIZE];
+	loop {
+		let n = file.read(&mut buf).await?;
+		if n == 0 {
+			return Ok(to_hex(&hasher.finish()));
+		}
+		hasher.update(&buf[..n]);
+	}
+}
+
+/// Returns the name with ` (n)` inserted before its extension, or the name itself for 0.
+fn numbered(name: &str, n: u32) -> String {
+	if n == 0 {
+		return name.to_owned();
+	}
+	let path = Path::new(name);
+	match (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|e| e.to_str())) {
+		(Some(stem), Some(extension)) => format!("{stem} ({n}).{extension}"),
+		_ => format!("{name} ({n})"),
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::crypto::UuidV4;
+	use tempfile::TempDir;
+
+	async fn receive(dir: &Path, name: &str, data: &[u8]) -> io::Result<PathBuf> {
+		let mut hasher = Sha256::new();
+		hasher.update(data);
+		let offer =
+			FileOffer::new(UuidV4::new(), name, data.len() as u64, to_hex(&hasher.finish()));
+		let mut transfer = Transfer::start(&offer, dir).await?;
+		transfer.write_chunk(&FileChunk::new(offer.transfer_id, 0, data.to_vec())).await?;
+		transfer.finish().await
+	}
+
+	#[test]
+	fn numbered_keeps_extension() {
+		assert_eq!(numbered("notes.txt", 0), "notes.txt");
+		assert_eq!(numbered("notes.txt", 2), "notes (2).txt");
+		assert_eq!(numbered("archive.tar.gz", 1), "archive.tar (1).gz");
+		assert_eq!(numbered(".bashrc", 1), ".bashrc (1)");
+	}
+
+	#[tokio::test]
+	async fn finish_does_not_overwrite_existing_files() {
+		let dir = TempDir::new().unwrap();
+		std::fs::write(dir.path().join("notes.txt"), "mine").unwrap();
+		let first = receive(dir.path(), "notes.txt", b"first").await.unwrap();
+		let second = receive(dir.path(), "../notes.txt", b"second").await.unwrap();
+
+		assert_eq!(std::fs::read(dir.path().join("notes.txt")).unwrap(), b"mine");
+		assert_eq!(first, dir.path().join("notes (1).txt"));
+		assert_eq!(std::fs::read(first).unwrap(), b"first");
+		assert_eq!(second, dir.path().join("notes (2).txt"));
+		assert_eq!(std::fs::read(second).unwrap(), b"second");
+	}
+
+	#[tokio::test]
+	async fn concurrent_transfers_of_same_name_use_their_own_part_files() {
+		let dir = TempDir::new().unwrap();
+		let (first, second) = tokio::join!(
+			receive(dir.path(), "notes.txt", b"first"),
+			receive(dir.path(), "notes.txt", b"second"),
+		);
+		let mut received =
+			[std::fs::read(first.unwrap()).unwrap(), std::fs::read(second.unwrap()).unwrap()];
+		received.sort();
+		assert_eq!(received, [b"first".to_vec(), b"second".to_vec()]);
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+	}
+}